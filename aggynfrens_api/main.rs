@@ -47,6 +47,104 @@ fn main() {
                 };
                 std::sync::Arc::new(cx)
             };
+            let qtrunk_cx = {
+                use qtrunk_api::*;
+                let config = Config {
+                    pass_salt_hash: uuid::Uuid::new_v4().as_bytes().to_vec(),
+                    argon2_conf: argon2::Config::default(),
+                    auth_token_lifespan: time::Duration::new(
+                        common::utils::get_env_var("AUTH_TOKEN_LIFESPAN_SECS")
+                            .unwrap_or_log()
+                            .parse()
+                            .unwrap_or_log(),
+                        0,
+                    ),
+                    web_session_lifespan: time::Duration::new(
+                        common::utils::get_env_var("WEB_SESSION_LIFESPAN_SECS")
+                            .unwrap_or_log()
+                            .parse()
+                            .unwrap_or_log(),
+                        0,
+                    ),
+                    service_secret: common::utils::get_env_var("SERVICE_SECRET").unwrap_or_log(),
+                    redis_key_prefix: common::utils::get_env_var("QTRUNK_REDIS_KEY_PREFIX")
+                        .unwrap_or_else(|_| "qtrunk".into()),
+                    relay_name: common::utils::get_env_var("QTRUNK_RELAY_NAME")
+                        .unwrap_or_else(|_| "qtrunk".into()),
+                    relay_description: common::utils::get_env_var("QTRUNK_RELAY_DESCRIPTION")
+                        .unwrap_or_default(),
+                    relay_pubkey: common::utils::get_env_var("QTRUNK_RELAY_PUBKEY").ok(),
+                    relay_contact: common::utils::get_env_var("QTRUNK_RELAY_CONTACT").ok(),
+                    relay_url: common::utils::get_env_var("QTRUNK_RELAY_URL").unwrap_or_log(),
+                    admin_pubkeys: common::utils::get_env_var("QTRUNK_ADMIN_PUBKEYS")
+                        .map(|val| {
+                            val.split(',')
+                                .map(|key| key.trim().to_string())
+                                .filter(|key| !key.is_empty())
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                    expired_reap_interval: time::Duration::minutes(5),
+                    archive_retention: common::utils::get_env_var("QTRUNK_ARCHIVE_RETENTION_SECS")
+                        .map(|val| time::Duration::seconds(val.parse().unwrap_or_log()))
+                        .ok(),
+                    archive_max_versions: common::utils::get_env_var("QTRUNK_ARCHIVE_MAX_VERSIONS")
+                        .map(|val| val.parse().unwrap_or_log())
+                        .ok(),
+                    ping_interval: time::Duration::seconds(30),
+                    idle_timeout: time::Duration::seconds(90),
+                    shutdown_grace: time::Duration::seconds(10),
+                    limits: Limits::default(),
+                    write_policy: event::policy::Chain::default(),
+                    // a JSON array of `{ "url": ..., "filters": [...] }`
+                    upstreams: common::utils::get_env_var("QTRUNK_UPSTREAMS")
+                        .map(|val| serde_json::from_str(&val).unwrap_or_log())
+                        .unwrap_or_default(),
+                };
+                let store: Box<dyn store::EventStore> =
+                    match common::utils::get_env_var("QTRUNK_STORE").as_deref() {
+                        Ok("mem") => Box::new(store::MemStore::new()),
+                        Ok("pg") | Err(_) => {
+                            let db_url =
+                                common::utils::get_env_var("QTRUNK_DATABASE_URL").unwrap_or_log();
+                            let db_pool = sqlx::PgPool::connect(&db_url).await.unwrap_or_log();
+                            Box::new(store::PgStore::new(db_pool))
+                        }
+                        Ok(other) => panic!("unsupported QTRUNK_STORE: {other}"),
+                    };
+                // a single instance can do without redis
+                let redis_pool = match common::utils::get_env_var("REDIS_URL") {
+                    Ok(url) => {
+                        let pool = bb8_redis::bb8::Pool::builder()
+                            .build(bb8_redis::RedisConnectionManager::new(url).unwrap_or_log())
+                            .await
+                            .unwrap_or_log();
+                        Some(common::RedisPool(pool))
+                    }
+                    Err(_) => None,
+                };
+                let hose: Box<dyn hose::EventHose> = match &redis_pool {
+                    Some(redis_pool) => Box::new(hose::RedisHose::new(
+                        redis_pool.clone(),
+                        common::utils::get_env_var("QTRUNK_EVENT_HOSE_STREAM").unwrap_or_log(),
+                        common::utils::get_env_var("QTRUNK_EVENT_HOSE_MAX_LEN")
+                            .map(|val| val.parse().unwrap_or_log())
+                            .unwrap_or(10_000),
+                    )),
+                    None => Box::<hose::BroadcastHose>::default(),
+                };
+                let sw = connect::Switchboard::default();
+                let cx = Context {
+                    config,
+                    store,
+                    redis: redis_pool,
+                    hose,
+                    rate_counters: rate_limit::LocalCounters::default(),
+                    sw,
+                    manage: manage::Overrides::default(),
+                };
+                std::sync::Arc::new(cx)
+            };
             let app = axum::Router::new()
                 .route(
                     "/up",
@@ -101,52 +199,10 @@ fn main() {
                 })
                 .nest("/qtrunk", {
                     use qtrunk_api::*;
-                    let config = Config {
-                        pass_salt_hash: uuid::Uuid::new_v4().as_bytes().to_vec(),
-                        argon2_conf: argon2::Config::default(),
-                        auth_token_lifespan: time::Duration::new(
-                            common::utils::get_env_var("AUTH_TOKEN_LIFESPAN_SECS")
-                                .unwrap_or_log()
-                                .parse()
-                                .unwrap_or_log(),
-                            0,
-                        ),
-                        web_session_lifespan: time::Duration::new(
-                            common::utils::get_env_var("WEB_SESSION_LIFESPAN_SECS")
-                                .unwrap_or_log()
-                                .parse()
-                                .unwrap_or_log(),
-                            0,
-                        ),
-                        service_secret: common::utils::get_env_var("SERVICE_SECRET")
-                            .unwrap_or_log(),
-                        event_hose_redis_channel: common::utils::get_env_var(
-                            "REDIS_PUBSUB_NOSTR_HOSE",
-                        )
-                        .unwrap_or_log(),
-                    };
-                    let db_url = common::utils::get_env_var("QTRUNK_DATABASE_URL").unwrap_or_log();
-                    let db_pool = sqlx::PgPool::connect(&db_url).await.unwrap_or_log();
-                    let redis_pool = bb8_redis::bb8::Pool::builder()
-                        .build(
-                            bb8_redis::RedisConnectionManager::new(
-                                common::utils::get_env_var("REDIS_URL").unwrap_or_log(),
-                            )
-                            .unwrap_or_log(),
-                        )
-                        .await
-                        .unwrap_or_log();
-                    let redis_pool = common::RedisPool(redis_pool);
-                    let sw = connect::Switchboard::default();
-                    let cx = Context {
-                        config,
-                        db: qtrunk_api::Db::Pg { db_pool },
-                        redis: redis_pool,
-                        sw,
-                    };
-                    let cx = std::sync::Arc::new(cx);
-                    tokio::spawn(connect::start_switchboard(cx.clone()));
-                    axum::Router::new().merge(qtrunk_api::router(cx))
+                    tokio::spawn(connect::start_switchboard(qtrunk_cx.clone()));
+                    tokio::spawn(event::expire::start_reaper(qtrunk_cx.clone()));
+                    tokio::spawn(mirror::start_mirroring(qtrunk_cx.clone()));
+                    axum::Router::new().merge(qtrunk_api::router(qtrunk_cx.clone()))
                 })
                 .merge(
                     utoipa_swagger_ui::SwaggerUi::new("/swagger-ui")
@@ -157,7 +213,13 @@ fn main() {
                         .url(
                             "/epigram/openapi.json",
                             <epigram_api::ApiDoc as utoipa::OpenApi>::openapi(),
-                        ),
+                        )
+                        // qtrunk serves its own
+                        .config(utoipa_swagger_ui::Config::new([
+                            "/aggy/openapi.json",
+                            "/epigram/openapi.json",
+                            "/qtrunk/openapi.json",
+                        ])),
                 )
                 .layer(
                     tower::ServiceBuilder::new()
@@ -193,7 +255,30 @@ fn main() {
             tracing::info!(%address, "server going online");
             axum::Server::bind(&address)
                 .serve(app.into_make_service_with_connect_info::<std::net::SocketAddr>())
+                .with_graceful_shutdown(async move {
+                    shutdown_signal().await;
+                    tracing::info!("server going offline");
+                    // websockets outlive the server so they're seen off separately
+                    qtrunk_cx
+                        .sw
+                        .shutdown(qtrunk_cx.config.shutdown_grace.unsigned_abs())
+                        .await;
+                })
                 .await
         })
         .unwrap_or_log()
 }
+
+/// Resolves on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let sigterm = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .unwrap_or_log()
+            .recv()
+            .await;
+    };
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res.unwrap_or_log(),
+        _ = sigterm => {}
+    }
+}
@@ -0,0 +1,69 @@
+//! Cost of finding the subscriptions an event should be dispatched to as
+//! the number of open subscriptions grows, indexed vs. scanning them all.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use deps::*;
+
+use common::utils::CHeapStr;
+use qtrunk_api::connect::SubIndex;
+use qtrunk_api::event::{Event, Filter};
+use serde_json::json;
+use uuid::Uuid;
+
+fn hex(seed: usize) -> String {
+    format!("{seed:064x}")
+}
+
+/// A mix of the usual subscriptions: following authors, watching replies
+/// to notes, watching kinds and the odd firehose.
+fn filters(seed: usize) -> Vec<Filter> {
+    let filter = match seed % 10 {
+        0..=5 => json!({ "authors": [hex(seed), hex(seed + 1)], "kinds": [1, 6] }),
+        6 | 7 => json!({ "#e": [hex(seed)] }),
+        8 => json!({ "kinds": [30000 + seed % 1000] }),
+        _ => json!({ "ids": [hex(seed)] }),
+    };
+    serde_json::from_value(json!([filter])).unwrap()
+}
+
+fn event(seed: usize) -> Event {
+    Event {
+        id: hex(usize::MAX - seed),
+        pubkey: hex(seed),
+        created_at: time::OffsetDateTime::now_utc(),
+        kind: 1,
+        tags: vec![vec!["e".into(), hex(seed + 6)]],
+        content: "hello".into(),
+        sig: hex(0),
+    }
+}
+
+fn dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dispatch");
+    for count in [100, 1_000, 10_000, 100_000] {
+        let mut index = SubIndex::default();
+        let mut scan = vec![];
+        for seed in 0..count {
+            let client_id = Uuid::new_v4();
+            let sub_id = CHeapStr::new(format!("sub{seed}"));
+            index.insert(client_id, sub_id.clone(), filters(seed));
+            scan.push((client_id, sub_id, filters(seed)));
+        }
+        let event = event(count / 2);
+        group.bench_with_input(BenchmarkId::new("indexed", count), &event, |b, event| {
+            b.iter(|| index.matching(event))
+        });
+        group.bench_with_input(BenchmarkId::new("scan", count), &event, |b, event| {
+            b.iter(|| {
+                scan.iter()
+                    .filter(|(_, _, filters)| filters.iter().any(|filter| filter.matches(event)))
+                    .map(|(client_id, sub_id, _)| (*client_id, sub_id.clone()))
+                    .collect::<Vec<_>>()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
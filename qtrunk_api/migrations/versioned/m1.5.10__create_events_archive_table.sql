@@ -0,0 +1,27 @@
+-- Superseded versions of replaceable events, moved here instead of being
+-- deleted when a newer version arrives. Only reachable by the relay's
+-- admins. Pruned according to the retention settings.
+CREATE TABLE events_archive (
+    archived_at             TIMESTAMPTZ                 NOT NULL    DEFAULT CURRENT_TIMESTAMP
+
+,   recieved_at             TIMESTAMPTZ                 NOT NULL
+,   id                      BYTEA                       NOT NULL
+,   pubkey                  BYTEA                       NOT NULL
+,   created_at              TIMESTAMPTZ                 NOT NULL
+,   kind                    INT                         NOT NULL
+,   content                 TEXT                        NOT NULL
+,   sig                     BYTEA                       NOT NULL
+,   tags                    JSONB                       NOT NULL
+-- `d` of the address, empty for those of plain replaceable events
+,   d_tag                   TEXT                        NOT NULL
+-- id of the version that took its place
+,   superseded_by           BYTEA                       NOT NULL
+
+,   PRIMARY KEY(id)
+);
+
+CREATE INDEX ON
+    events_archive (pubkey, kind, d_tag, created_at);
+
+CREATE INDEX ON
+    events_archive (archived_at);
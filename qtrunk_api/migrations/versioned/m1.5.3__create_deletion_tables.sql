@@ -0,0 +1,26 @@
+-- NIP-09 tombstones for events deleted by id (`e` tags)
+-- The referenced event need not have arrived yet so we store the
+-- pubkey of the deletion request's author alongside the id.
+CREATE TABLE event_deletions (
+    deleted_at              TIMESTAMPTZ                 NOT NULL    DEFAULT CURRENT_TIMESTAMP
+
+,   event_id                BYTEA                       NOT NULL
+,   pubkey                  BYTEA                       NOT NULL
+,   deletion_id             BYTEA                       NOT NULL
+
+,   PRIMARY KEY(event_id, pubkey)
+);
+
+-- NIP-09 tombstones for replaceable events deleted by address (`a` tags)
+-- All versions created at or before `deleted_until` are considered deleted.
+CREATE TABLE address_deletions (
+    deleted_at              TIMESTAMPTZ                 NOT NULL    DEFAULT CURRENT_TIMESTAMP
+
+,   kind                    INT                         NOT NULL
+,   pubkey                  BYTEA                       NOT NULL
+,   d_tag                   TEXT                        NOT NULL
+,   deleted_until           TIMESTAMPTZ                 NOT NULL
+,   deletion_id             BYTEA                       NOT NULL
+
+,   PRIMARY KEY(kind, pubkey, d_tag)
+);
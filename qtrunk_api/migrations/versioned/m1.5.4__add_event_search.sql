@@ -0,0 +1,9 @@
+-- NIP-50 full-text search over event content
+-- The `simple` config is used since events aren't in any one language.
+ALTER TABLE events
+    ADD COLUMN content_tsv TSVECTOR
+    GENERATED ALWAYS AS (to_tsvector('simple', content)) STORED;
+
+CREATE INDEX ON
+    events
+    USING GIN (content_tsv);
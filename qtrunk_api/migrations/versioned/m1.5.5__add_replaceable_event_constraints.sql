@@ -0,0 +1,31 @@
+-- NIP-01 replaceable events: only the latest version under an address,
+-- (pubkey, kind) or (pubkey, kind, d) for parameterized ones, is kept.
+
+-- value of the first `d` tag, empty if missing
+ALTER TABLE events
+    ADD COLUMN d_tag TEXT
+    NOT NULL
+    GENERATED ALWAYS AS (
+        COALESCE(jsonb_path_query_first(tags, '$ ? (@[0] == "d")') ->> 1, '')
+    ) STORED;
+
+-- drop stale versions stored before addresses were keyed by author
+-- the newer created_at wins with the lower id breaking ties
+DELETE FROM events old
+    USING events new
+    WHERE old.pubkey = new.pubkey
+        AND old.kind = new.kind
+        AND (
+            old.kind IN (0, 3)
+            OR (old.kind >= 10000 AND old.kind < 20000)
+            OR (old.kind >= 30000 AND old.kind < 40000 AND old.d_tag = new.d_tag)
+        )
+        AND (old.created_at, new.id) < (new.created_at, old.id);
+
+CREATE UNIQUE INDEX events_replaceable_key ON
+    events (pubkey, kind)
+    WHERE kind IN (0, 3) OR (kind >= 10000 AND kind < 20000);
+
+CREATE UNIQUE INDEX events_parameterized_replaceable_key ON
+    events (pubkey, kind, d_tag)
+    WHERE kind >= 30000 AND kind < 40000;
@@ -0,0 +1,13 @@
+-- NIP-40 expiration timestamps, taken from the `expiration` tag on insert
+ALTER TABLE events
+    ADD COLUMN expires_at TIMESTAMPTZ;
+
+UPDATE events
+    SET expires_at = to_timestamp(
+        (jsonb_path_query_first(tags, '$ ? (@[0] == "expiration")') ->> 1)::BIGINT
+    )
+    WHERE (jsonb_path_query_first(tags, '$ ? (@[0] == "expiration")') ->> 1) ~ '^[0-9]{1,12}$';
+
+CREATE INDEX ON
+    events (expires_at)
+    WHERE expires_at IS NOT NULL;
@@ -0,0 +1,29 @@
+-- NIP-86 bans placed by the relay's admins
+CREATE TABLE banned_pubkeys (
+    banned_at               TIMESTAMPTZ                 NOT NULL    DEFAULT CURRENT_TIMESTAMP
+
+,   pubkey                  BYTEA                       NOT NULL
+,   reason                  TEXT
+
+,   PRIMARY KEY(pubkey)
+);
+
+-- The banned event need not have arrived yet.
+CREATE TABLE banned_events (
+    banned_at               TIMESTAMPTZ                 NOT NULL    DEFAULT CURRENT_TIMESTAMP
+
+,   event_id                BYTEA                       NOT NULL
+,   reason                  TEXT
+
+,   PRIMARY KEY(event_id)
+);
+
+-- Overrides of the relay's configured settings made through NIP-86
+CREATE TABLE relay_settings (
+    updated_at              TIMESTAMPTZ                 NOT NULL    DEFAULT CURRENT_TIMESTAMP
+
+,   key                     TEXT                        NOT NULL
+,   value                   JSONB                       NOT NULL
+
+,   PRIMARY KEY(key)
+);
@@ -0,0 +1,63 @@
+-- Single letter tags of events, the ones filters can match on, so that tag
+-- filters are served by btree lookups instead of jsonpath scans over `tags`.
+CREATE TABLE event_tags (
+    event_id                BYTEA                       NOT NULL
+,   name                    TEXT                        NOT NULL
+,   value                   TEXT                        NOT NULL
+-- copied from the event to serve the newest first
+,   created_at              TIMESTAMPTZ                 NOT NULL
+
+,   PRIMARY KEY(event_id, name, value)
+,   FOREIGN KEY(event_id) REFERENCES events(id) ON DELETE CASCADE
+);
+
+CREATE INDEX ON
+    event_tags (name, value, created_at);
+
+-- only `[name, value, ...]` tags are indexed
+CREATE OR REPLACE FUNCTION
+    index_event_tags()
+  RETURNS TRIGGER AS
+  $body$
+      BEGIN
+          INSERT INTO event_tags (
+              event_id
+              ,name
+              ,value
+              ,created_at
+          ) SELECT
+              NEW.id
+              ,tag ->> 0
+              ,tag ->> 1
+              ,NEW.created_at
+          FROM jsonb_array_elements(NEW.tags) AS tag
+          WHERE jsonb_typeof(tag) = 'array'
+              AND char_length(tag ->> 0) = 1
+              AND tag ->> 1 IS NOT NULL
+          ON CONFLICT DO NOTHING;
+          RETURN NULL;
+      END;
+  $body$ LANGUAGE PLpgSQL;
+
+CREATE TRIGGER index_event_tags
+    AFTER INSERT
+    ON events
+    FOR EACH ROW
+    EXECUTE PROCEDURE index_event_tags();
+
+-- backfill
+INSERT INTO event_tags (
+    event_id
+    ,name
+    ,value
+    ,created_at
+) SELECT
+    events.id
+    ,tag ->> 0
+    ,tag ->> 1
+    ,events.created_at
+FROM events, jsonb_array_elements(events.tags) AS tag
+WHERE jsonb_typeof(tag) = 'array'
+    AND char_length(tag ->> 0) = 1
+    AND tag ->> 1 IS NOT NULL
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,10 @@
+-- How far each upstream relay's been mirrored, as the `since` the next
+-- backfill from it resumes at.
+CREATE TABLE mirror_cursors (
+    updated_at              TIMESTAMPTZ                 NOT NULL    DEFAULT CURRENT_TIMESTAMP
+
+,   upstream                TEXT                        NOT NULL
+,   since                   TIMESTAMPTZ                 NOT NULL
+
+,   PRIMARY KEY(upstream)
+);
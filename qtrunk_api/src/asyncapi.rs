@@ -0,0 +1,305 @@
+//! AsyncAPI document for the messages exchanged over the relay's websocket.
+//! Each message is a JSON array led by its verb, described here as a tuple.
+
+use crate::interlude::*;
+
+use serde_json::Value;
+
+pub const ASYNCAPI_VERSION: &str = "2.6.0";
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{name}") })
+}
+
+fn string(description: &str) -> Value {
+    json!({ "type": "string", "description": description })
+}
+
+fn sub_id() -> Value {
+    string("Subscription id, unique per connection")
+}
+
+fn hex_msg() -> Value {
+    json!({
+        "type": "string",
+        "pattern": "^([0-9a-f]{2})*$",
+        "description": "Hex encoded negentropy message"
+    })
+}
+
+/// A message made up of the verb followed by `elements`, and as many
+/// `rest` as there are if given.
+fn message(verb: &str, summary: &str, elements: Vec<Value>, rest: Option<Value>) -> Value {
+    let min_items = elements.len() + 1 + usize::from(rest.is_some());
+    let mut items = vec![json!({ "type": "string", "const": verb })];
+    items.extend(elements);
+    json!({
+        "name": verb,
+        "summary": summary,
+        "payload": {
+            "type": "array",
+            "items": items,
+            "minItems": min_items,
+            "additionalItems": rest.unwrap_or(json!(false)),
+        },
+    })
+}
+
+/// The messages clients can send, keyed by their component name.
+fn client_messages() -> Vec<(&'static str, Value)> {
+    vec![
+        (
+            "ClientEvent",
+            message(
+                "EVENT",
+                "Publish an event, answered with an OK",
+                vec![schema_ref("Event")],
+                None,
+            ),
+        ),
+        (
+            "ClientReq",
+            message(
+                "REQ",
+                "Subscribe to the events matching any of the filters. \
+                The stored ones are sent first, followed by an EOSE, then new \
+                ones as they come in.",
+                vec![sub_id()],
+                Some(schema_ref("Filter")),
+            ),
+        ),
+        (
+            "ClientClose",
+            message("CLOSE", "Close a subscription", vec![sub_id()], None),
+        ),
+        (
+            "ClientCount",
+            message(
+                "COUNT",
+                "Count the stored events matching any of the filters (NIP-45)",
+                vec![sub_id()],
+                Some(schema_ref("Filter")),
+            ),
+        ),
+        (
+            "ClientAuth",
+            message(
+                "AUTH",
+                "Authenticate with a signed kind 22242 event carrying the \
+                challenge (NIP-42), answered with an OK",
+                vec![schema_ref("Event")],
+                None,
+            ),
+        ),
+        (
+            "ClientNegOpen",
+            message(
+                "NEG-OPEN",
+                "Start reconciling the events matching the filter (NIP-77)",
+                vec![sub_id(), schema_ref("Filter"), hex_msg()],
+                None,
+            ),
+        ),
+        (
+            "ClientNegMsg",
+            message(
+                "NEG-MSG",
+                "Continue a reconciliation (NIP-77)",
+                vec![sub_id(), hex_msg()],
+                None,
+            ),
+        ),
+        (
+            "ClientNegClose",
+            message(
+                "NEG-CLOSE",
+                "End a reconciliation (NIP-77)",
+                vec![sub_id()],
+                None,
+            ),
+        ),
+    ]
+}
+
+/// The messages the relay sends, keyed by their component name.
+fn relay_messages() -> Vec<(&'static str, Value)> {
+    let reason = || string("Machine readable prefix, like `invalid:`, followed by a message");
+    vec![
+        (
+            "RelayEvent",
+            message(
+                "EVENT",
+                "An event matching a subscription",
+                vec![sub_id(), schema_ref("Event")],
+                None,
+            ),
+        ),
+        (
+            "RelayOk",
+            message(
+                "OK",
+                "Whether a published event, or an AUTH, was accepted",
+                vec![
+                    string("Id of the event"),
+                    json!({ "type": "boolean" }),
+                    reason(),
+                ],
+                None,
+            ),
+        ),
+        (
+            "RelayEose",
+            message(
+                "EOSE",
+                "The end of the stored events of a subscription",
+                vec![sub_id()],
+                None,
+            ),
+        ),
+        (
+            "RelayClosed",
+            message(
+                "CLOSED",
+                "A subscription or count was refused or ended by the relay",
+                vec![sub_id(), reason()],
+                None,
+            ),
+        ),
+        (
+            "RelayNotice",
+            message(
+                "NOTICE",
+                "A human readable message",
+                vec![string("Message")],
+                None,
+            ),
+        ),
+        (
+            "RelayCount",
+            message(
+                "COUNT",
+                "The answer to a COUNT (NIP-45)",
+                vec![
+                    sub_id(),
+                    json!({
+                        "type": "object",
+                        "properties": { "count": { "type": "integer", "minimum": 0 } },
+                        "required": ["count"],
+                    }),
+                ],
+                None,
+            ),
+        ),
+        (
+            "RelayAuth",
+            message(
+                "AUTH",
+                "The challenge to authenticate against (NIP-42), sent on connect",
+                vec![string("Challenge")],
+                None,
+            ),
+        ),
+        (
+            "RelayNegMsg",
+            message(
+                "NEG-MSG",
+                "The relay's side of a reconciliation (NIP-77)",
+                vec![sub_id(), hex_msg()],
+                None,
+            ),
+        ),
+        (
+            "RelayNegErr",
+            message(
+                "NEG-ERR",
+                "A reconciliation was refused or ended by the relay (NIP-77)",
+                vec![sub_id(), reason()],
+                None,
+            ),
+        ),
+    ]
+}
+
+/// The AsyncAPI document, schemas shared with the OpenAPI one.
+pub fn document() -> Value {
+    let schemas: serde_json::Map<String, Value> = [
+        <crate::event::Event as ToSchema>::schema(),
+        <crate::event::Filter as ToSchema>::schema(),
+    ]
+    .into_iter()
+    .map(|(name, schema)| (name.to_string(), serde_json::to_value(schema).unwrap()))
+    .collect();
+    let one_of = |messages: &[(&str, Value)]| {
+        messages
+            .iter()
+            .map(|(name, _)| json!({ "$ref": format!("#/components/messages/{name}") }))
+            .collect::<Vec<_>>()
+    };
+    let (client, relay) = (client_messages(), relay_messages());
+    json!({
+        "asyncapi": ASYNCAPI_VERSION,
+        "info": {
+            "title": "qtrunk_api",
+            "version": crate::build::PKG_VERSION,
+            "description": "The nostr relay protocol as spoken by qtrunk. \
+                Messages are JSON arrays sent as websocket text frames.",
+        },
+        "defaultContentType": "application/json",
+        "channels": {
+            "/": {
+                "publish": {
+                    "operationId": "clientMessage",
+                    "summary": "Messages from the client",
+                    "message": { "oneOf": one_of(&client) },
+                },
+                "subscribe": {
+                    "operationId": "relayMessage",
+                    "summary": "Messages from the relay",
+                    "message": { "oneOf": one_of(&relay) },
+                },
+            },
+        },
+        "components": {
+            "schemas": schemas,
+            "messages": client
+                .into_iter()
+                .chain(relay)
+                .map(|(name, message)| (name.to_string(), message))
+                .collect::<serde_json::Map<_, _>>(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use serde_json::Value;
+
+    fn refs<'a>(value: &'a Value, out: &mut Vec<&'a str>) {
+        match value {
+            Value::Object(map) => {
+                for (key, val) in map {
+                    match (key.as_str(), val) {
+                        ("$ref", Value::String(path)) => out.push(path),
+                        _ => refs(val, out),
+                    }
+                }
+            }
+            Value::Array(vals) => vals.iter().for_each(|val| refs(val, out)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn refs_resolve() {
+        let doc = super::document();
+        let mut found = vec![];
+        refs(&doc, &mut found);
+        assert!(!found.is_empty());
+        for path in found {
+            let pointer = path.strip_prefix('#').unwrap();
+            assert!(doc.pointer(pointer).is_some(), "dangling ref {path}");
+        }
+    }
+}
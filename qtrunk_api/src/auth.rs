@@ -0,0 +1,278 @@
+//! NIP-42 client authentication.
+
+use crate::interlude::*;
+
+use crate::event::Event;
+
+pub const AUTH_EVENT_KIND: u16 = 22242;
+
+/// How far off from now an auth event's `created_at` is allowed to be.
+pub const AUTH_WINDOW: time::Duration = time::Duration::minutes(10);
+
+#[derive(Debug, Clone)]
+pub struct Authenticate;
+
+#[derive(Debug)]
+pub struct Request {
+    pub event: Event,
+    /// The challenge sent to the connection the event arrived on.
+    pub challenge: CHeapStr,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    id: String,
+    pub pubkey: String,
+}
+
+impl Response {
+    pub fn to_nostr_ok(&self) -> serde_json::Value {
+        json!(["OK", self.id, true, ""])
+    }
+}
+
+#[derive(Debug, Serialize, thiserror::Error)]
+#[serde(crate = "serde", rename_all = "camelCase")]
+#[error("error authenticating with event {event_id}: {kind}")]
+pub struct Error {
+    event_id: String,
+    #[source]
+    kind: ErrorKind,
+}
+
+#[derive(Debug, Serialize, thiserror::Error)]
+#[serde(crate = "serde", rename_all = "camelCase", tag = "error")]
+pub enum ErrorKind {
+    #[error("invalid: auth events must be of kind {AUTH_EVENT_KIND}")]
+    WrongKind,
+    #[error("invalid: relay tag doesn't match this relay")]
+    WrongRelay,
+    #[error("invalid: challenge tag doesn't match the one issued")]
+    WrongChallenge,
+    #[error("invalid: created_at is too far off from the current time")]
+    Stale,
+    #[error("invalid:{issues}")]
+    InvalidInput {
+        #[from]
+        issues: ValidationErrors,
+    },
+}
+
+impl Error {
+    pub fn to_nostr_ok(&self) -> serde_json::Value {
+        json!(["OK", self.event_id, false, format!("{}", self.kind)])
+    }
+}
+
+pub(crate) fn tag_value<'a>(event: &'a Event, name: &str) -> Option<&'a str> {
+    event
+        .tags
+        .iter()
+        .find(|tag| matches!(tag.get(0), Some(key) if key == name))
+        .and_then(|tag| tag.get(1))
+        .map(|val| &val[..])
+}
+
+pub(crate) fn normalize_url(url: &str) -> String {
+    url.trim().trim_end_matches('/').to_lowercase()
+}
+
+fn validate_request(request: &Request, relay_url: &str) -> Result<(), ErrorKind> {
+    let Request { event, challenge } = request;
+    if event.kind != AUTH_EVENT_KIND {
+        return Err(ErrorKind::WrongKind);
+    }
+    match tag_value(event, "relay") {
+        Some(relay) if normalize_url(relay) == normalize_url(relay_url) => {}
+        _ => return Err(ErrorKind::WrongRelay),
+    }
+    match tag_value(event, "challenge") {
+        Some(val) if val == &challenge[..] => {}
+        _ => return Err(ErrorKind::WrongChallenge),
+    }
+    if (OffsetDateTime::now_utc() - event.created_at).abs() > AUTH_WINDOW {
+        return Err(ErrorKind::Stale);
+    }
+    crate::event::create::validate_request(event).map_err(ValidationErrors::from)?;
+    Ok(())
+}
+
+#[async_trait::async_trait]
+impl Endpoint for Authenticate {
+    type Request = Request;
+    type Response = Response;
+    type Error = Error;
+    type Cx = Context;
+
+    #[tracing::instrument(skip(cx), err)]
+    async fn handle(
+        &self,
+        cx: &Self::Cx,
+        request: Self::Request,
+    ) -> Result<Self::Response, Self::Error> {
+        validate_request(&request, &cx.config.relay_url[..]).map_err(|kind| Error {
+            event_id: request.event.id.clone(),
+            kind,
+        })?;
+        Ok(Response {
+            id: request.event.id,
+            pubkey: request.event.pubkey,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use crate::interlude::*;
+
+    use crate::event::Event;
+
+    /// A signed kind 22242 event answering the given challenge.
+    pub fn auth_event(
+        privkey: &str,
+        relay_url: &str,
+        challenge: &str,
+        created_at: OffsetDateTime,
+    ) -> Event {
+        let privkey = data_encoding::HEXLOWER.decode(privkey.as_bytes()).unwrap();
+        let privkey = k256::schnorr::SigningKey::from_bytes(&privkey[..]).unwrap();
+        let pubkey = data_encoding::HEXLOWER.encode(&privkey.verifying_key().to_bytes()[..]);
+        let tags = vec![
+            vec!["relay".to_string(), relay_url.to_string()],
+            vec!["challenge".to_string(), challenge.to_string()],
+        ];
+        let (id, sig) = crate::event::hex_id_and_sig_for_event(
+            &privkey,
+            &pubkey[..],
+            created_at,
+            super::AUTH_EVENT_KIND,
+            &tags,
+            "",
+        );
+        Event {
+            id,
+            pubkey,
+            created_at,
+            kind: super::AUTH_EVENT_KIND,
+            tags,
+            content: "".into(),
+            sig,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use super::testing::*;
+    use super::Request;
+    use crate::event::testing::*;
+
+    const RELAY_URL: &str = "wss://qtrunk.test";
+    const CHALLENGE: &str = "dead beef";
+
+    fn fixture_request() -> Request {
+        Request {
+            event: auth_event(
+                EVENT_01_PRIVKEY,
+                RELAY_URL,
+                CHALLENGE,
+                OffsetDateTime::now_utc(),
+            ),
+            challenge: CHeapStr::new(CHALLENGE.to_string()),
+        }
+    }
+
+    common::table_tests! {
+        validate,
+        (request, expected_err),
+        {
+            common::utils::testing::setup_tracing_once();
+            let res = crate::auth::validate_request(&request, RELAY_URL);
+            match (res, expected_err) {
+                (Ok(()), None) => {}
+                (Err(err), Some(expected)) => {
+                    assert!(format!("{err:?}").starts_with(expected), "unexpected err: {err:?}");
+                }
+                (res, _) => panic!("unexpected result: {res:?}"),
+            }
+        }
+    }
+
+    validate! {
+        accepts_valid: (
+            fixture_request(),
+            Option::<&str>::None,
+        ),
+        ignores_trailing_slash: (
+            Request {
+                event: auth_event(
+                    EVENT_01_PRIVKEY,
+                    "wss://qtrunk.test/",
+                    CHALLENGE,
+                    OffsetDateTime::now_utc(),
+                ),
+                ..fixture_request()
+            },
+            Option::<&str>::None,
+        ),
+        rejects_wrong_kind: (
+            Request {
+                event: crate::event::Event {
+                    kind: 1,
+                    ..fixture_request().event
+                },
+                ..fixture_request()
+            },
+            Some("WrongKind"),
+        ),
+        rejects_wrong_relay: (
+            Request {
+                event: auth_event(
+                    EVENT_01_PRIVKEY,
+                    "wss://other.relay",
+                    CHALLENGE,
+                    OffsetDateTime::now_utc(),
+                ),
+                ..fixture_request()
+            },
+            Some("WrongRelay"),
+        ),
+        rejects_wrong_challenge: (
+            Request {
+                challenge: CHeapStr::new("cafe babe".to_string()),
+                ..fixture_request()
+            },
+            Some("WrongChallenge"),
+        ),
+        rejects_stale: (
+            Request {
+                event: auth_event(
+                    EVENT_01_PRIVKEY,
+                    RELAY_URL,
+                    CHALLENGE,
+                    OffsetDateTime::now_utc() - time::Duration::minutes(11),
+                ),
+                ..fixture_request()
+            },
+            Some("Stale"),
+        ),
+        rejects_bad_sig: (
+            Request {
+                event: crate::event::Event {
+                    sig: auth_event(
+                        EVENT_02_PRIVKEY,
+                        RELAY_URL,
+                        CHALLENGE,
+                        OffsetDateTime::now_utc(),
+                    )
+                    .sig,
+                    ..fixture_request().event
+                },
+                ..fixture_request()
+            },
+            Some("InvalidInput"),
+        ),
+    }
+}
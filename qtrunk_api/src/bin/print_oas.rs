@@ -0,0 +1,19 @@
+use deps::*;
+
+use qtrunk_api::*;
+
+/// Prints the OpenAPI document, or the AsyncAPI one if passed `asyncapi`.
+/// The OpenAPI paths are put under the prefix given after it, if any.
+fn main() {
+    let doc = match std::env::args().nth(1).as_deref() {
+        None | Some("openapi") => ApiDoc::at(std::env::args().nth(2).as_deref().unwrap_or(""))
+            .to_pretty_json()
+            .unwrap(),
+        Some("asyncapi") => serde_json::to_string_pretty(&asyncapi::document()).unwrap(),
+        Some(other) => {
+            eprintln!("unknown document {other:?}, expected openapi or asyncapi");
+            std::process::exit(2);
+        }
+    };
+    println!("{doc}");
+}
@@ -0,0 +1,130 @@
+use deps::*;
+
+use qtrunk_api::*;
+
+use tokio::io::AsyncWriteExt;
+
+#[derive(Debug, Clone, clap::Parser)]
+#[clap(version, about)]
+#[clap(propagate_version = true)]
+struct Cli {
+    #[clap(long, env = "QTRUNK_DATABASE_URL")]
+    database_url: String,
+    #[clap(subcommand)]
+    commands: Commands,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+enum Commands {
+    /// Store events read as newline delimited JSON.
+    /// Reads from stdin if no path is given.
+    Import {
+        path: Option<std::path::PathBuf>,
+        /// Number of events to verify and insert at a time.
+        #[clap(long, default_value_t = 1000)]
+        batch_size: usize,
+    },
+    /// Write out stored events as newline delimited JSON, oldest first.
+    Export {
+        /// NIP-01 filters as a JSON array. Exports everything if not given.
+        #[clap(long)]
+        filters: Option<String>,
+        /// Writes to stdout if not given.
+        #[clap(short, long)]
+        output: Option<std::path::PathBuf>,
+        /// Number of events to fetch at a time.
+        #[clap(long, default_value_t = 1000)]
+        page_size: usize,
+    },
+}
+
+fn main() -> eyre::Result<()> {
+    dotenvy::dotenv().ok();
+    setup_tracing()?;
+
+    let args = <Cli as clap::Parser>::parse();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            let db_pool = sqlx::PgPool::connect(&args.database_url).await?;
+            let store = store::PgStore::new(db_pool);
+            match args.commands {
+                Commands::Import { path, batch_size } => {
+                    let input: Box<dyn tokio::io::AsyncRead + Unpin> = match path {
+                        Some(path) => Box::new(tokio::fs::File::open(path).await?),
+                        None => Box::new(tokio::io::stdin()),
+                    };
+                    let report = bulk::import(
+                        &store,
+                        tokio::io::BufReader::new(input),
+                        batch_size,
+                        |report| {
+                            tracing::info!(
+                                read = report.read,
+                                imported = report.imported,
+                                rejected = report.rejected_count(),
+                                "importing"
+                            )
+                        },
+                    )
+                    .await?;
+                    eprintln!(
+                        "read {} events, imported {}, rejected {}",
+                        report.read,
+                        report.imported,
+                        report.rejected_count()
+                    );
+                    for (reason, count) in &report.rejected {
+                        eprintln!("{count:>10} {reason}");
+                    }
+                }
+                Commands::Export {
+                    filters,
+                    output,
+                    page_size,
+                } => {
+                    let filters: Vec<event::Filter> = match filters {
+                        Some(filters) => serde_json::from_str(&filters)?,
+                        None => vec![],
+                    };
+                    let output: Box<dyn tokio::io::AsyncWrite + Unpin> = match output {
+                        Some(path) => Box::new(tokio::fs::File::create(path).await?),
+                        None => Box::new(tokio::io::stdout()),
+                    };
+                    let mut output = tokio::io::BufWriter::new(output);
+                    let count = bulk::export(&store, &filters, &mut output, page_size, |count| {
+                        tracing::info!(count, "exporting")
+                    })
+                    .await?;
+                    output.shutdown().await?;
+                    eprintln!("exported {count} events");
+                }
+            }
+            Ok::<_, eyre::Report>(())
+        })
+}
+
+/// Like [`common::setup_tracing`] but onto stderr, stdout being for the
+/// exported events.
+fn setup_tracing() -> eyre::Result<()> {
+    color_eyre::install()?;
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+
+    use tracing_subscriber::prelude::*;
+    tracing_subscriber::registry()
+        .with(tracing_error::ErrorLayer::default())
+        .with(tracing_subscriber::EnvFilter::from_default_env())
+        .with(
+            tracing_subscriber::fmt::layer()
+                .compact()
+                .with_writer(std::io::stderr)
+                .with_timer(tracing_subscriber::fmt::time::uptime()),
+        )
+        .try_init()
+        .map_err(|err| eyre::eyre!(err))?;
+
+    Ok(())
+}
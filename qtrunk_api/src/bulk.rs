@@ -0,0 +1,379 @@
+//! Bulk import and export of events as newline delimited JSON, for seeding
+//! a relay from other relays' dumps and backing it up.
+
+use crate::interlude::*;
+
+use std::collections::BTreeMap;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::event::create::ErrorKind;
+use crate::event::{Event, Filter};
+use crate::store::{Banned, EventStore};
+
+/// Tally of an import so far.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Lines read, blank ones aside.
+    pub read: u64,
+    pub imported: u64,
+    /// Number of rejected events by reason.
+    pub rejected: BTreeMap<String, u64>,
+}
+
+impl ImportReport {
+    pub fn rejected_count(&self) -> u64 {
+        self.rejected.values().sum()
+    }
+
+    fn reject(&mut self, line: u64, reason: String, detail: &dyn std::fmt::Display) {
+        debug!(line, %reason, %detail, "event rejected");
+        *self.rejected.entry(reason).or_default() += 1;
+    }
+}
+
+/// Stores the events read off `input`, one JSON event per line. Ids and
+/// signatures are verified in parallel, `batch_size` lines at a time, after
+/// which the regular events of the batch are inserted together. Replaceable
+/// events and deletion requests are applied in order. `progress` is called
+/// after each batch.
+pub async fn import(
+    store: &dyn EventStore,
+    input: impl AsyncBufRead + Unpin,
+    batch_size: usize,
+    mut progress: impl FnMut(&ImportReport),
+) -> eyre::Result<ImportReport> {
+    let batch_size = batch_size.max(1);
+    let mut report = ImportReport::default();
+    let mut lines = input.lines();
+    let mut line_no = 0;
+    loop {
+        let mut batch = Vec::with_capacity(batch_size);
+        while batch.len() < batch_size {
+            let Some(line) = lines.next_line().await? else {
+                break;
+            };
+            line_no += 1;
+            if !line.trim().is_empty() {
+                batch.push((line_no, line));
+            }
+        }
+        if batch.is_empty() {
+            break;
+        }
+        report.read += batch.len() as u64;
+        let mut regular = vec![];
+        for (line_no, checked) in verify_batch(batch).await? {
+            let event = match checked {
+                Ok(event) => event,
+                Err((reason, detail)) => {
+                    report.reject(line_no, reason, &detail);
+                    continue;
+                }
+            };
+            if let Some(banned) = store.banned(&event).await {
+                let kind = match banned {
+                    Banned::Pubkey => ErrorKind::BannedPubkey,
+                    Banned::Event => ErrorKind::BannedEvent,
+                };
+                report.reject(line_no, kind.to_string(), &event.id);
+                continue;
+            }
+            let stored = match event.kind {
+                nn if crate::event::is_ephemeral(nn) => {
+                    report.reject(line_no, "invalid: ephemeral event".into(), &event.id);
+                    continue;
+                }
+                5 => {
+                    // the batch up to here might hold the events it refers to
+                    insert_regular(store, &mut regular, &mut report).await;
+                    store.delete_by_reference(&event).await
+                }
+                nn if crate::event::is_replaceable(nn)
+                    || crate::event::is_parameterized_replaceable(nn) =>
+                {
+                    insert_regular(store, &mut regular, &mut report).await;
+                    store.replace(&event).await
+                }
+                _ => {
+                    regular.push((line_no, event));
+                    continue;
+                }
+            };
+            match stored {
+                Ok(()) => report.imported += 1,
+                Err(rejection) => {
+                    report.reject(line_no, ErrorKind::from(rejection).to_string(), &event.id)
+                }
+            }
+        }
+        insert_regular(store, &mut regular, &mut report).await;
+        progress(&report);
+    }
+    Ok(report)
+}
+
+async fn insert_regular(
+    store: &dyn EventStore,
+    pending: &mut Vec<(u64, Event)>,
+    report: &mut ImportReport,
+) {
+    if pending.is_empty() {
+        return;
+    }
+    let (line_nos, events): (Vec<_>, Vec<_>) = pending.drain(..).unzip();
+    let results = store.insert_batch(&events).await;
+    for ((line_no, event), stored) in line_nos.into_iter().zip(&events).zip(results) {
+        match stored {
+            Ok(()) => report.imported += 1,
+            Err(rejection) => {
+                report.reject(line_no, ErrorKind::from(rejection).to_string(), &event.id)
+            }
+        }
+    }
+}
+
+/// A rejection reason and the detail behind it.
+type Invalid = (String, String);
+
+/// Parses and verifies the lines across the available cores, keeping their
+/// order.
+async fn verify_batch(
+    batch: Vec<(u64, String)>,
+) -> eyre::Result<Vec<(u64, Result<Event, Invalid>)>> {
+    let parallelism = std::thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1);
+    let chunk_size = (batch.len() + parallelism - 1) / parallelism;
+    let mut chunks = vec![];
+    let mut batch = batch.into_iter();
+    loop {
+        let chunk = batch.by_ref().take(chunk_size).collect::<Vec<_>>();
+        if chunk.is_empty() {
+            break;
+        }
+        chunks.push(tokio::task::spawn_blocking(move || {
+            chunk
+                .into_iter()
+                .map(|(line_no, line)| (line_no, verify(&line)))
+                .collect::<Vec<_>>()
+        }));
+    }
+    let mut out = vec![];
+    for chunk in chunks {
+        out.extend(chunk.await?);
+    }
+    Ok(out)
+}
+
+/// The same checks events coming over the websocket go through, save for
+/// those depending on the relay's limits and policies.
+fn verify(line: &str) -> Result<Event, Invalid> {
+    let event: Event = serde_json::from_str(line)
+        .map_err(|err| ("invalid: malformed event".to_string(), err.to_string()))?;
+    if let Err(issues) = crate::event::create::validate_request(&event) {
+        let field = issues.field_errors().into_keys().next().unwrap_or_default();
+        return Err((format!("invalid: bad {field}"), event.id));
+    }
+    let kind = match event.kind {
+        crate::auth::AUTH_EVENT_KIND => ErrorKind::AuthEvent,
+        _ if event.is_expired() => ErrorKind::Expired,
+        _ => return Ok(event),
+    };
+    Err((kind.to_string(), event.id))
+}
+
+/// Writes the unexpired events matching any of the filters, or all of them
+/// if there are none, to `output` as newline delimited JSON, oldest first.
+/// `limit`s are ignored. `progress` is called with the running count after
+/// each page of `page_size` events.
+pub async fn export(
+    store: &dyn EventStore,
+    filters: &[Filter],
+    mut output: impl AsyncWrite + Unpin,
+    page_size: usize,
+    mut progress: impl FnMut(u64),
+) -> eyre::Result<u64> {
+    let page_size = page_size.max(1);
+    let mut count = 0;
+    let mut last = None;
+    loop {
+        let page = store
+            .scan(filters, last.as_ref(), page_size)
+            .await
+            .map_err(|issues| eyre::eyre!("invalid filters: {issues}"))?;
+        let Some(tail) = page.last().cloned() else {
+            break;
+        };
+        let mut buf = vec![];
+        for event in &page {
+            serde_json::to_writer(&mut buf, event)?;
+            buf.push(b'\n');
+        }
+        output.write_all(&buf).await?;
+        count += page.len() as u64;
+        progress(count);
+        last = Some(tail);
+    }
+    output.flush().await?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use crate::event::testing::*;
+    use crate::event::Event;
+    use crate::utils::testing::BACKENDS;
+
+    fn signed_event(created_at: i64, kind: u16, tags: Vec<Vec<String>>, content: &str) -> Event {
+        let created_at = OffsetDateTime::from_unix_timestamp(created_at).unwrap();
+        sign(TEST_PRIVKEY, created_at, kind, tags, content)
+    }
+
+    fn jsonl(lines: &[String]) -> String {
+        lines.join("\n")
+    }
+
+    #[tokio::test]
+    async fn import() {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let kept = signed_event(now - 100, 1, vec![], "kept");
+        let deleted = signed_event(now - 90, 1, vec![], "deleted");
+        let deletion = signed_event(now - 80, 5, vec![vec!["e".into(), deleted.id.clone()]], "");
+        let old_profile = signed_event(now - 70, 0, vec![], "old profile");
+        let profile = signed_event(now - 60, 0, vec![], "profile");
+        let mut tampered = signed_event(now - 50, 1, vec![], "tampered");
+        tampered.content = "tempered".into();
+        let dump = jsonl(&[
+            json!(kept).to_string(),
+            json!(deleted).to_string(),
+            json!(deletion).to_string(),
+            // resubmission of the deleted event after the fact
+            json!(deleted).to_string(),
+            json!(profile).to_string(),
+            json!(old_profile).to_string(),
+            json!(tampered).to_string(),
+            json!(*EVENT_01).to_string(),
+            "".into(),
+            "not an event".into(),
+        ]);
+        for backend in BACKENDS {
+            let (testing, cx) = crate::utils::testing::cx_fn_with_backend(
+                common::function_full!(),
+                backend,
+                |_| {},
+            )
+            .await;
+            {
+                let mut progress = vec![];
+                // small batches to straddle the deletion
+                let report = super::import(&*cx.store, dump.as_bytes(), 3, |report| {
+                    progress.push(report.read)
+                })
+                .await
+                .unwrap();
+                assert_eq!(progress, vec![3, 6, 9], "{backend:?}");
+                assert_eq!(
+                    report,
+                    super::ImportReport {
+                        read: 9,
+                        imported: 4,
+                        rejected: [
+                            ("blocked: event was deleted by its author".to_string(), 1),
+                            (
+                                "duplicate: a newer version of the event is already stored".into(),
+                                1
+                            ),
+                            ("duplicate: event already recieved".into(), 1),
+                            ("invalid: bad id".into(), 1),
+                            ("invalid: malformed event".into(), 1),
+                        ]
+                        .into_iter()
+                        .collect(),
+                    },
+                    "{backend:?}"
+                );
+                let stored = cx
+                    .store
+                    .query(
+                        &serde_json::from_value::<Vec<crate::event::Filter>>(json!([{
+                            "authors": [kept.pubkey]
+                        }]))
+                        .unwrap(),
+                        100,
+                    )
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|event| event.id)
+                    .collect::<std::collections::HashSet<_>>();
+                assert_eq!(
+                    stored,
+                    [&kept.id, &deletion.id, &profile.id]
+                        .into_iter()
+                        .cloned()
+                        .collect(),
+                    "{backend:?}"
+                );
+            }
+            testing.close().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn export() {
+        for backend in BACKENDS {
+            let (testing, cx) = crate::utils::testing::cx_fn_with_backend(
+                common::function_full!(),
+                backend,
+                |_| {},
+            )
+            .await;
+            {
+                for (filters, expected) in [
+                    (
+                        json!([]),
+                        vec![&EVENT_01, &EVENT_02, &EVENT_03, &EVENT_04, &EVENT_05],
+                    ),
+                    (
+                        json!([{ "kinds": [1], "limit": 1 }]),
+                        vec![&EVENT_01, &EVENT_03, &EVENT_04, &EVENT_05],
+                    ),
+                ] {
+                    let filters: Vec<crate::event::Filter> =
+                        serde_json::from_value(filters).unwrap();
+                    let mut out = vec![];
+                    let mut progress = vec![];
+                    // pages smaller than the results
+                    let count = super::export(&*cx.store, &filters, &mut out, 2, |count| {
+                        progress.push(count)
+                    })
+                    .await
+                    .unwrap();
+                    let mut expected = expected
+                        .into_iter()
+                        .map(|event| &**event)
+                        .collect::<Vec<_>>();
+                    expected.sort_by_key(|event| (event.created_at, event.id.clone()));
+                    let lines = String::from_utf8(out)
+                        .unwrap()
+                        .lines()
+                        .map(|line| serde_json::from_str::<Event>(line).unwrap().id)
+                        .collect::<Vec<_>>();
+                    assert_eq!(
+                        lines,
+                        expected
+                            .iter()
+                            .map(|event| event.id.clone())
+                            .collect::<Vec<_>>(),
+                        "{backend:?}"
+                    );
+                    assert_eq!(count, expected.len() as u64);
+                    assert_eq!(progress.last(), Some(&count));
+                }
+            }
+            testing.close().await;
+        }
+    }
+}
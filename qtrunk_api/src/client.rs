@@ -0,0 +1,747 @@
+//! Typed access to a relay. [`InProcClient`] goes through the endpoints
+//! and the switchboard of a [`Context`] directly while [`HttpClient`] speaks
+//! NIP-01 to a relay over a websocket, reconnecting and resubscribing when
+//! the connection drops.
+
+use crate::interlude::*;
+
+use futures::{stream::BoxStream, SinkExt, StreamExt};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::Message as WsMsg;
+
+use crate::event::{Event, Filter};
+
+const MIN_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+const MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(30);
+/// How long [`HttpClient`] requests wait on the relay, reconnects included.
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// Events buffered for each [`HttpClient`] subscription.
+const SUB_BUFFER: usize = 256;
+
+#[derive(Debug, Serialize, thiserror::Error)]
+#[serde(crate = "serde", rename_all = "camelCase", tag = "error")]
+pub enum Error {
+    /// Refused by the relay, `reason` being the NIP-01 prefixed message.
+    #[error("{reason}")]
+    Rejected { reason: String },
+    #[error("connection error: {message}")]
+    Connection { message: String },
+}
+
+#[async_trait::async_trait]
+pub trait Client: Send + Sync {
+    /// Events the relay already has, or has a newer version of, count as
+    /// published.
+    async fn publish(&self, event: Event) -> Result<(), Error>;
+    /// Stored events matching any of the filters, newest first.
+    async fn query(&self, filters: Vec<Filter>) -> Result<Vec<Event>, Error>;
+    async fn count(&self, filters: Vec<Filter>) -> Result<i64, Error>;
+    /// The stored events matching the filters followed by new ones as they
+    /// come in. Ends if the relay closes the subscription or if it's not
+    /// kept up with.
+    async fn subscribe(&self, filters: Vec<Filter>) -> Result<BoxStream<'static, Event>, Error>;
+}
+
+pub struct InProcClient {
+    pub cx: SharedContext,
+}
+
+#[async_trait::async_trait]
+impl Client for InProcClient {
+    async fn publish(&self, event: Event) -> Result<(), Error> {
+        use crate::event::create::ErrorKind;
+        match crate::event::create::CreateEvent
+            .handle(&self.cx, event)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => match err.kind() {
+                ErrorKind::Duplicate | ErrorKind::Stale => Ok(()),
+                kind => Err(Error::Rejected {
+                    reason: kind.to_string(),
+                }),
+            },
+        }
+    }
+
+    async fn query(&self, filters: Vec<Filter>) -> Result<Vec<Event>, Error> {
+        crate::event::list::ListEvents
+            .handle(&self.cx, filters)
+            .await
+            .map_err(|err| Error::Rejected {
+                reason: err.to_string(),
+            })
+    }
+
+    async fn count(&self, filters: Vec<Filter>) -> Result<i64, Error> {
+        crate::event::count::CountEvents
+            .handle(&self.cx, filters)
+            .await
+            .map(|res| res.count)
+            .map_err(|err| Error::Rejected {
+                reason: err.to_string(),
+            })
+    }
+
+    async fn subscribe(&self, filters: Vec<Filter>) -> Result<BoxStream<'static, Event>, Error> {
+        if self.cx.sw.is_shutting_down() {
+            return Err(Error::Rejected {
+                reason: "error: relay is shutting down".into(),
+            });
+        }
+        let config = &self.cx.config;
+        let outbox = std::sync::Arc::new(crate::connect::Outbox::new(
+            config.limits.outbox_capacity,
+            config.limits.slow_consumer_policy,
+        ));
+        // tapped before the listing so that nothing stored meanwhile is missed
+        let tap = Tap {
+            id: self.cx.sw.tap(outbox.clone(), filters.clone()).await,
+            cx: self.cx.clone(),
+        };
+        let stored = self.query(filters).await?;
+        let seen: HashSet<String> = stored.iter().map(|event| event.id.clone()).collect();
+        let live =
+            futures::stream::unfold((tap, outbox, seen), |(tap, outbox, mut seen)| async move {
+                loop {
+                    let msg = tokio::select! {
+                        msg = outbox.pop() => msg?,
+                        _ = tap.cx.sw.shutdown_started() => return None,
+                    };
+                    let event: Event = match msg.get(2).cloned().map(serde_json::from_value) {
+                        Some(Ok(event)) => event,
+                        _ => continue,
+                    };
+                    if !seen.remove(&event.id) {
+                        return Some((event, (tap, outbox, seen)));
+                    }
+                }
+            });
+        Ok(futures::stream::iter(stored).chain(live).boxed())
+    }
+}
+
+/// Takes the in-process subscriber off the switchboard once dropped.
+struct Tap {
+    cx: SharedContext,
+    id: Uuid,
+}
+
+impl Drop for Tap {
+    fn drop(&mut self) {
+        let (cx, id) = (self.cx.clone(), self.id);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            drop(handle.spawn(async move { cx.sw.untap(&id).await }));
+        }
+    }
+}
+
+/// Talks to the relay over a single websocket, connected to in the
+/// background. Requests made while it's down get sent once reconnected,
+/// timing out after a while. Subscriptions falling more than
+/// [`SUB_BUFFER`] events behind get closed.
+pub struct HttpClient {
+    commands: mpsc::Sender<Command>,
+    next_id: AtomicU64,
+}
+
+impl HttpClient {
+    /// Connects to the relay at `url`, a `ws://` or `wss://` url. Needs to
+    /// be called from within a tokio runtime.
+    pub fn new(url: impl Into<String>) -> Self {
+        let (commands, rx) = mpsc::channel(64);
+        let url = url.into();
+        drop(tokio::spawn(async move { run(&url, rx).await }));
+        Self {
+            commands,
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    fn sub_id(&self, prefix: &str) -> String {
+        format!("{prefix}-{}", self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(Reply<T>) -> Command) -> Result<T, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .send(command(tx))
+            .await
+            .map_err(|_| connection_gone())?;
+        match tokio::time::timeout(REQUEST_TIMEOUT, rx).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(_)) => Err(connection_gone()),
+            Err(_) => Err(Error::Connection {
+                message: "timed out waiting on the relay".into(),
+            }),
+        }
+    }
+}
+
+fn connection_gone() -> Error {
+    Error::Connection {
+        message: "connection task is gone".into(),
+    }
+}
+
+#[async_trait::async_trait]
+impl Client for HttpClient {
+    async fn publish(&self, event: Event) -> Result<(), Error> {
+        self.request(|reply| Command::Publish { event, reply })
+            .await
+    }
+
+    async fn query(&self, filters: Vec<Filter>) -> Result<Vec<Event>, Error> {
+        let sub_id = self.sub_id("query");
+        self.request(|reply| Command::Query {
+            sub_id,
+            filters,
+            reply,
+        })
+        .await
+    }
+
+    async fn count(&self, filters: Vec<Filter>) -> Result<i64, Error> {
+        let sub_id = self.sub_id("count");
+        self.request(|reply| Command::Count {
+            sub_id,
+            filters,
+            reply,
+        })
+        .await
+    }
+
+    async fn subscribe(&self, filters: Vec<Filter>) -> Result<BoxStream<'static, Event>, Error> {
+        let sub_id = self.sub_id("sub");
+        let (events, rx) = mpsc::channel(SUB_BUFFER);
+        self.commands
+            .send(Command::Subscribe {
+                sub_id: sub_id.clone(),
+                filters,
+                events,
+            })
+            .await
+            .map_err(|_| connection_gone())?;
+        let guard = Unsubscribe {
+            commands: self.commands.clone(),
+            sub_id,
+        };
+        Ok(
+            futures::stream::unfold((rx, guard), |(mut rx, guard)| async move {
+                let event = rx.recv().await?;
+                Some((event, (rx, guard)))
+            })
+            .boxed(),
+        )
+    }
+}
+
+/// Closes the subscription once its stream is dropped.
+struct Unsubscribe {
+    commands: mpsc::Sender<Command>,
+    sub_id: String,
+}
+
+impl Drop for Unsubscribe {
+    fn drop(&mut self) {
+        // closed on the next event otherwise
+        _ = self.commands.try_send(Command::Close {
+            sub_id: std::mem::take(&mut self.sub_id),
+        });
+    }
+}
+
+type Reply<T> = oneshot::Sender<Result<T, Error>>;
+
+enum Command {
+    Publish {
+        event: Event,
+        reply: Reply<()>,
+    },
+    Query {
+        sub_id: String,
+        filters: Vec<Filter>,
+        reply: Reply<Vec<Event>>,
+    },
+    Count {
+        sub_id: String,
+        filters: Vec<Filter>,
+        reply: Reply<i64>,
+    },
+    Subscribe {
+        sub_id: String,
+        filters: Vec<Filter>,
+        events: mpsc::Sender<Event>,
+    },
+    Close {
+        sub_id: String,
+    },
+}
+
+struct Publish {
+    event: Event,
+    replies: Vec<Reply<()>>,
+}
+
+struct Query {
+    filters: Vec<Filter>,
+    events: Vec<Event>,
+    reply: Reply<Vec<Event>>,
+}
+
+struct Count {
+    filters: Vec<Filter>,
+    reply: Reply<i64>,
+}
+
+struct Sub {
+    filters: Vec<Filter>,
+    events: mpsc::Sender<Event>,
+    /// Set once the stored events have come through, after which only
+    /// those at `newest` get resent on resubscription.
+    caught_up: bool,
+    newest: Option<OffsetDateTime>,
+    seen: HashSet<String>,
+}
+
+/// What's awaiting an answer from the relay, replayed on reconnects.
+#[derive(Default)]
+struct State {
+    publishes: HashMap<String, Publish>,
+    queries: HashMap<String, Query>,
+    counts: HashMap<String, Count>,
+    subs: HashMap<String, Sub>,
+}
+
+fn sub_msg(verb: &str, sub_id: &str, filters: &[Filter]) -> Value {
+    let mut msg = vec![json!(verb), json!(sub_id)];
+    msg.extend(filters.iter().map(|filter| json!(filter)));
+    Value::Array(msg)
+}
+
+impl State {
+    /// Returns the message to send the relay, if any.
+    fn register(&mut self, command: Command) -> Option<Value> {
+        match command {
+            Command::Publish { event, reply } => {
+                use std::collections::hash_map::Entry;
+                match self.publishes.entry(event.id.clone()) {
+                    Entry::Occupied(mut entry) => {
+                        entry.get_mut().replies.push(reply);
+                        None
+                    }
+                    Entry::Vacant(entry) => {
+                        let msg = json!(["EVENT", event]);
+                        entry.insert(Publish {
+                            event,
+                            replies: vec![reply],
+                        });
+                        Some(msg)
+                    }
+                }
+            }
+            Command::Query {
+                sub_id,
+                filters,
+                reply,
+            } => {
+                let msg = sub_msg("REQ", &sub_id, &filters);
+                self.queries.insert(
+                    sub_id,
+                    Query {
+                        filters,
+                        events: vec![],
+                        reply,
+                    },
+                );
+                Some(msg)
+            }
+            Command::Count {
+                sub_id,
+                filters,
+                reply,
+            } => {
+                let msg = sub_msg("COUNT", &sub_id, &filters);
+                self.counts.insert(sub_id, Count { filters, reply });
+                Some(msg)
+            }
+            Command::Subscribe {
+                sub_id,
+                filters,
+                events,
+            } => {
+                let msg = sub_msg("REQ", &sub_id, &filters);
+                self.subs.insert(
+                    sub_id,
+                    Sub {
+                        filters,
+                        events,
+                        caught_up: false,
+                        newest: None,
+                        seen: default(),
+                    },
+                );
+                Some(msg)
+            }
+            Command::Close { sub_id } => {
+                self.subs.remove(&sub_id).map(|_| json!(["CLOSE", sub_id]))
+            }
+        }
+    }
+
+    /// Messages to bring a fresh connection up to speed, dropping whatever
+    /// nobody's waiting on anymore.
+    fn replay(&mut self) -> Vec<Value> {
+        self.publishes.retain(|_, publish| {
+            publish.replies.retain(|reply| !reply.is_closed());
+            !publish.replies.is_empty()
+        });
+        self.queries.retain(|_, query| !query.reply.is_closed());
+        self.counts.retain(|_, count| !count.reply.is_closed());
+        self.subs.retain(|_, sub| !sub.events.is_closed());
+
+        let mut msgs = vec![];
+        for (sub_id, sub) in &self.subs {
+            let msg = if sub.caught_up {
+                sub_msg(
+                    "REQ",
+                    sub_id,
+                    &crate::mirror::narrowed(&sub.filters, sub.newest, None),
+                )
+            } else {
+                sub_msg("REQ", sub_id, &sub.filters)
+            };
+            msgs.push(msg);
+        }
+        for (sub_id, query) in &mut self.queries {
+            query.events.clear();
+            msgs.push(sub_msg("REQ", sub_id, &query.filters));
+        }
+        for (sub_id, count) in &self.counts {
+            msgs.push(sub_msg("COUNT", sub_id, &count.filters));
+        }
+        for publish in self.publishes.values() {
+            msgs.push(json!(["EVENT", publish.event]));
+        }
+        msgs
+    }
+
+    /// Returns the message to answer the relay with, if any.
+    fn handle(&mut self, msg: Vec<Value>) -> Option<Value> {
+        let sub_id = msg.get(1).and_then(Value::as_str)?;
+        match msg.first().and_then(Value::as_str)? {
+            "OK" if msg.len() >= 3 => {
+                let publish = self.publishes.remove(sub_id)?;
+                let reason = msg.get(3).and_then(Value::as_str).unwrap_or_default();
+                let res = if msg[2].as_bool() == Some(true) || reason.starts_with("duplicate:") {
+                    Ok(())
+                } else {
+                    Err(reason.to_string())
+                };
+                for reply in publish.replies {
+                    _ = reply.send(res.clone().map_err(|reason| Error::Rejected { reason }));
+                }
+            }
+            "EVENT" if msg.len() == 3 => {
+                let event: Event = match serde_json::from_value(msg[2].clone()) {
+                    Ok(event) => event,
+                    Err(err) => {
+                        warn!(?err, "malformed event from relay");
+                        return None;
+                    }
+                };
+                if let Some(query) = self.queries.get_mut(sub_id) {
+                    query.events.push(event);
+                } else if let Some(sub) = self.subs.get_mut(sub_id) {
+                    if sub.seen.contains(&event.id) {
+                        return None;
+                    }
+                    if Some(event.created_at) > sub.newest {
+                        if sub.caught_up {
+                            sub.seen.clear();
+                        }
+                        sub.newest = Some(event.created_at);
+                    }
+                    if !sub.caught_up || Some(event.created_at) == sub.newest {
+                        sub.seen.insert(event.id.clone());
+                    }
+                    // closed rather than holding up the whole connection
+                    if let Err(err) = sub.events.try_send(event) {
+                        if let mpsc::error::TrySendError::Full(_) = err {
+                            debug!(%sub_id, "subscriber fell behind, closing");
+                        }
+                        self.subs.remove(sub_id);
+                        return Some(json!(["CLOSE", sub_id]));
+                    }
+                }
+            }
+            "EOSE" => {
+                if let Some(query) = self.queries.remove(sub_id) {
+                    _ = query.reply.send(Ok(query.events));
+                    return Some(json!(["CLOSE", sub_id]));
+                }
+                if let Some(sub) = self.subs.get_mut(sub_id) {
+                    sub.caught_up = true;
+                }
+            }
+            "COUNT" if msg.len() == 3 => {
+                let count = self.counts.remove(sub_id)?;
+                _ = count
+                    .reply
+                    .send(msg[2]["count"].as_i64().ok_or_else(|| Error::Connection {
+                        message: format!("malformed COUNT from relay: {}", msg[2]),
+                    }));
+            }
+            "CLOSED" => {
+                let reason = msg
+                    .get(2)
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string();
+                if let Some(query) = self.queries.remove(sub_id) {
+                    _ = query.reply.send(Err(Error::Rejected { reason }));
+                } else if let Some(count) = self.counts.remove(sub_id) {
+                    _ = count.reply.send(Err(Error::Rejected { reason }));
+                } else if self.subs.remove(sub_id).is_some() {
+                    debug!(%sub_id, %reason, "subscription closed by relay");
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+#[tracing::instrument(skip(commands))]
+async fn run(url: &str, mut commands: mpsc::Receiver<Command>) {
+    let mut state = State::default();
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let err = match connection(url, &mut commands, &mut state, &mut backoff).await {
+            Ok(()) => return,
+            Err(err) => err,
+        };
+        warn!(?err, ?backoff, "relay connection lost, reconnecting");
+        let sleep = tokio::time::sleep(backoff);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => break,
+                command = commands.recv() => match command {
+                    // sent once reconnected
+                    Some(command) => _ = state.register(command),
+                    None => return,
+                },
+            }
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Returns once all the handles are gone or with the error that brought
+/// the connection down.
+async fn connection(
+    url: &str,
+    commands: &mut mpsc::Receiver<Command>,
+    state: &mut State,
+    backoff: &mut std::time::Duration,
+) -> eyre::Result<()> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(url).await?;
+    *backoff = MIN_BACKOFF;
+    let send = |msg: Value| WsMsg::Text(msg.to_string());
+    for msg in state.replay() {
+        ws.send(send(msg)).await?;
+    }
+    loop {
+        tokio::select! {
+            command = commands.recv() => {
+                let Some(command) = command else {
+                    _ = ws.close(None).await;
+                    return Ok(());
+                };
+                if let Some(msg) = state.register(command) {
+                    ws.send(send(msg)).await?;
+                }
+            }
+            msg = ws.next() => {
+                let Some(msg) = msg else {
+                    eyre::bail!("relay connection ended");
+                };
+                let msg: Vec<Value> = match msg? {
+                    WsMsg::Text(text) => serde_json::from_str(&text)?,
+                    WsMsg::Binary(buf) => serde_json::from_slice(&buf)?,
+                    WsMsg::Close(frame) => eyre::bail!("relay hung up: {frame:?}"),
+                    _ => continue,
+                };
+                if let Some(msg) = state.handle(msg) {
+                    ws.send(send(msg)).await?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use futures::StreamExt;
+
+    use super::{Client, HttpClient, InProcClient};
+    use crate::event::testing::{sign, TEST_PRIVKEY};
+    use crate::event::{Event, Filter};
+
+    fn signed_event(created_at: i64, content: &str) -> Event {
+        let created_at = OffsetDateTime::from_unix_timestamp(created_at).unwrap();
+        sign(TEST_PRIVKEY, created_at, 1, vec![], content)
+    }
+
+    fn filters(value: serde_json::Value) -> Vec<Filter> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn serve(cx: &SharedContext, addr: &str) -> tokio::task::JoinHandle<hyper::Result<()>> {
+        let router = crate::router(cx.clone());
+        tokio::spawn(
+            axum::Server::bind(&addr.parse().unwrap())
+                .serve(router.into_make_service_with_connect_info::<std::net::SocketAddr>()),
+        )
+    }
+
+    async fn next_event(events: &mut futures::stream::BoxStream<'static, Event>) -> Event {
+        tokio::time::timeout(std::time::Duration::from_secs(5), events.next())
+            .await
+            .expect("timed out waiting for event")
+            .expect("subscription ended")
+    }
+
+    async fn works(test_name: &'static str, over_http: bool) {
+        let (testing, cx) = crate::utils::testing::cx_fn(test_name).await;
+        {
+            let server_handle = over_http.then(|| serve(&cx, "127.0.0.1:19009"));
+            let client: Box<dyn Client> = if over_http {
+                Box::new(HttpClient::new("ws://127.0.0.1:19009"))
+            } else {
+                Box::new(InProcClient { cx: cx.clone() })
+            };
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            let old = signed_event(now - 10, "old");
+            client.publish(old.clone()).await.unwrap();
+            // duplicates are no error
+            client.publish(old.clone()).await.unwrap();
+
+            let mut tampered = signed_event(now, "tampered");
+            tampered.content = "tempered".into();
+            match client.publish(tampered).await {
+                Err(super::Error::Rejected { reason }) => {
+                    assert!(reason.starts_with("invalid"), "{reason}")
+                }
+                res => panic!("unexpected result: {res:?}"),
+            }
+
+            let authored = json!([{ "authors": [old.pubkey] }]);
+            assert_eq!(client.count(filters(authored.clone())).await.unwrap(), 1);
+            let events = client.query(filters(json!([{}]))).await.unwrap();
+            assert_eq!(events.len(), 6);
+            assert_eq!(events[0].id, old.id);
+
+            let mut events = client.subscribe(filters(authored)).await.unwrap();
+            assert_eq!(next_event(&mut events).await.id, old.id);
+            let new = signed_event(now, "new");
+            client.publish(new.clone()).await.unwrap();
+            assert_eq!(next_event(&mut events).await.id, new.id);
+
+            if let Some(server_handle) = server_handle {
+                server_handle.abort();
+            }
+        }
+        testing.close().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn works_in_proc() {
+        works(common::function_full!(), false).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn works_over_http() {
+        works(common::function_full!(), true).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stalled_subscribers_dont_block() {
+        let (testing, cx) = crate::utils::testing::cx_fn(common::function_full!()).await;
+        {
+            let server_handle = serve(&cx, "127.0.0.1:19011");
+            let client = HttpClient::new("ws://127.0.0.1:19011");
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            let pubkey = signed_event(now, "").pubkey;
+            // never polled
+            let stalled = client
+                .subscribe(filters(json!([{ "authors": [pubkey] }])))
+                .await
+                .unwrap();
+            for ii in 0..super::SUB_BUFFER + 2 {
+                let event = signed_event(now, &format!("{ii}"));
+                tokio::time::timeout(std::time::Duration::from_secs(5), client.publish(event))
+                    .await
+                    .expect("publish was held up by the stalled subscriber")
+                    .unwrap();
+            }
+            // closed once it fell behind
+            let received = tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                stalled.collect::<Vec<_>>(),
+            )
+            .await
+            .expect("stalled subscription wasn't closed");
+            assert!(received.len() <= super::SUB_BUFFER, "{}", received.len());
+
+            server_handle.abort();
+        }
+        testing.close().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reconnects() {
+        let (testing, cx) = crate::utils::testing::cx_fn(common::function_full!()).await;
+        {
+            let server_handle = serve(&cx, "127.0.0.1:19010");
+            let client = HttpClient::new("ws://127.0.0.1:19010");
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            let before = signed_event(now - 10, "before");
+            let mut events = client
+                .subscribe(filters(json!([{ "authors": [before.pubkey] }])))
+                .await
+                .unwrap();
+            client.publish(before.clone()).await.unwrap();
+            assert_eq!(next_event(&mut events).await.id, before.id);
+
+            // the relay restarts
+            cx.sw.shutdown(std::time::Duration::from_secs(5)).await;
+            server_handle.abort();
+            _ = server_handle.await;
+            let cx = crate::utils::testing::state_fn(&testing);
+            drop(tokio::spawn(crate::connect::start_switchboard(cx.clone())));
+            let server_handle = serve(&cx, "127.0.0.1:19010");
+
+            // the subscription picks up from where it was without repeats
+            let after = signed_event(now, "after");
+            client.publish(after.clone()).await.unwrap();
+            assert_eq!(next_event(&mut events).await.id, after.id);
+            assert_eq!(
+                client
+                    .count(filters(json!([{ "authors": [before.pubkey] }])))
+                    .await
+                    .unwrap(),
+                2
+            );
+
+            server_handle.abort();
+        }
+        testing.close().await;
+    }
+}
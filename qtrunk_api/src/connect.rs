@@ -1,27 +1,48 @@
 use crate::interlude::*;
 
 use axum::extract::{
-    ws::{CloseFrame, Message as WsMsg, WebSocket, WebSocketUpgrade},
+    ws::{
+        rejection::WebSocketUpgradeRejection, CloseFrame, Message as WsMsg, WebSocket,
+        WebSocketUpgrade,
+    },
     ConnectInfo, State,
 };
 use futures::{sink::SinkExt, stream::StreamExt};
 use serde_json::Value;
 use std::collections::HashMap;
-use tokio::{sync::mpsc::Sender, sync::RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::sync::RwLock;
 
 use crate::event::{Event, Filter};
 
-#[derive(Debug)]
-pub struct Subscription {
-    id: CHeapStr,
-    filters: Vec<Filter>,
-}
+mod index;
+mod outbox;
+pub use index::SubIndex;
+pub use outbox::{Outbox, SlowConsumerPolicy};
+
+pub const TAG: common::Tag = common::Tag {
+    name: "relay",
+    desc: "NIP-01 websocket and NIP-11 relay information.",
+};
+
+/// How long a client that's been cut off for falling behind is given to
+/// receive the NOTICE.
+const CUT_OFF_GRACE: std::time::Duration = std::time::Duration::from_secs(1);
 
 #[derive(Debug)]
 pub struct Client {
     id: Uuid,
-    subs: RwLock<Vec<Subscription>>,
-    tx: Sender<Value>,
+    outbox: std::sync::Arc<Outbox>,
+    /// NIP-42 challenge issued to the connection.
+    challenge: CHeapStr,
+    authed_pubkeys: std::sync::Arc<RwLock<Vec<String>>>,
+}
+
+impl Client {
+    /// Hex pubkeys that have completed NIP-42 auth on this connection.
+    pub async fn authed_pubkeys(&self) -> Vec<String> {
+        self.authed_pubkeys.read().await.clone()
+    }
 }
 
 #[derive(Default, educe::Educe)]
@@ -29,75 +50,254 @@ pub struct Client {
 pub struct Switchboard {
     #[educe(Debug(ignore))]
     clients: RwLock<HashMap<Uuid, Client>>,
+    #[educe(Debug(ignore))]
+    subs: RwLock<SubIndex>,
+    /// Messages dropped from the outboxes of disconnected clients.
+    dropped_msgs: AtomicU64,
+    /// Clients cut off for falling behind.
+    slow_disconnects: AtomicU64,
+    shutting_down: AtomicBool,
+    #[educe(Debug(ignore))]
+    shutdown: tokio::sync::Notify,
 }
 
-#[tracing::instrument(skip(cx), err)]
-pub async fn pub_event(cx: &Context, event: &Event) -> eyre::Result<()> {
-    use redis::AsyncCommands;
-    let mut conn = cx.redis.get().await?;
-    conn.publish(cx.config.event_hose_redis_channel.as_str(), event)
-        .await?;
-    Ok(())
-}
+impl Switchboard {
+    /// Hex pubkeys that have completed NIP-42 auth on the given connection.
+    /// Returns `None` if the client's not connected.
+    pub async fn authed_pubkeys(&self, client_id: &Uuid) -> Option<Vec<String>> {
+        // the clients guard is dropped before awaiting the pubkeys lock
+        let authed = self
+            .clients
+            .read()
+            .await
+            .get(client_id)?
+            .authed_pubkeys
+            .clone();
+        let authed = authed.read().await.clone();
+        Some(authed)
+    }
 
-pub async fn start_switchboard(cx: SharedContext) -> eyre::Result<()> {
-    /* // even though we recieve all events through a redis pub/sub (to allow horizontal scaling)
-    // we buffer the redis events in a tokio mpsc for...idk, pre-optimzation reasons
-    let (sw_tx, mut sw_rx) = tokio::sync::mpsc::unbounded_channel::<Event>(); */
-
-    // subscribe to the redis channel while on the main "task"
-    let mut conn = cx.redis.dedicated_connection().await?.into_pubsub();
-    conn.subscribe(cx.config.event_hose_redis_channel.as_str())
-        .await?;
-
-    /* // span a separate task for the polling though
-    let redis_loop_handle = tokio::spawn(async move {
-        let mut stream = conn.into_on_message();
-        while let Some(msg) = stream.next().await {
-            let event: Event = msg.get_payload().unwrap_or_log();
-            sw_tx.send(event).unwrap_or_log()
+    /// Queues the event for the subscriptions it matches. Never waits on
+    /// the clients.
+    pub async fn dispatch(&self, event: &Event) {
+        let matching = self.subs.read().await.matching(event);
+        if matching.is_empty() {
+            return;
+        }
+        let clients = self.clients.read().await;
+        for (client_id, sub_id) in matching {
+            let Some(client) = clients.get(&client_id) else {
+                continue;
+            };
+            let dropped = client.outbox.push(json!(["EVENT", *sub_id, event]));
+            trace!(%event.id, %client.id, %sub_id, dropped, "event queued for client");
         }
-    }); */
-
-    // while let Some(event) = sw_rx.recv().await {
-    let mut stream = conn.into_on_message();
-    while let Some(msg) = stream.next().await {
-        let event: Event = msg.get_payload().unwrap_or_log();
-        trace!(?event, "event recieved for switiching");
-        // FIXME: stress test this
-        let clients = cx.sw.clients.read().await;
+    }
+
+    /// Total messages dropped from the outboxes of slow clients.
+    pub async fn dropped_msgs(&self) -> u64 {
+        let clients = self.clients.read().await;
         clients
             .values()
-            // creat a future for each client
-            .map(|client| async {
-                let subs = client.subs.read().await;
-                for sub in &*subs {
-                    if let Some(filter) = sub.filters.iter().find(|filter| filter.matches(&event)) {
-                        let res = client.tx.send(json!(["EVENT", *sub.id, event])).await;
-                        // client must have disconnected
-                        if res.is_err() {
-                            break;
-                        }
-                        trace!(%event.id, %client.id, ?filter, "event sent to client according to filter");
-                    }
-                }
-            })
-            // mass poll them concurrently
-            .collect::<futures::stream::futures_unordered::FuturesUnordered<_>>()
-            .for_each_concurrent(None, |_| async {})
-            .await
+            .map(|client| client.outbox.dropped())
+            .sum::<u64>()
+            + self.dropped_msgs.load(Ordering::Relaxed)
+    }
+
+    /// Number of clients cut off for falling behind.
+    pub fn slow_disconnects(&self) -> u64 {
+        self.slow_disconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    /// Resolves once the relay starts shutting down.
+    pub(crate) async fn shutdown_started(&self) {
+        // registered before the check so that the notification can't be missed
+        let notified = self.shutdown.notified();
+        if self.is_shutting_down() {
+            return;
+        }
+        notified.await;
+    }
+
+    /// Sees off the connected clients, waiting up to `grace` for them to
+    /// leave. New connections and subscriptions are refused from here on.
+    pub async fn shutdown(&self, grace: std::time::Duration) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        self.shutdown.notify_waiters();
+        let drained = async {
+            while !self.clients.read().await.is_empty() {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        };
+        if tokio::time::timeout(grace, drained).await.is_err() {
+            let count = self.clients.read().await.len();
+            warn!(
+                count,
+                "clients still connected at the end of the shutdown grace"
+            );
+        }
     }
-    // redis_loop_handle.abort();
-    Ok(())
+}
+
+impl Switchboard {
+    /// Connects an in-process client with a single `sub` subscription,
+    /// returning its id. It's up to the caller to [`Self::untap`] it.
+    pub(crate) async fn tap(&self, outbox: std::sync::Arc<Outbox>, filters: Vec<Filter>) -> Uuid {
+        let client = Client {
+            id: Uuid::new_v4(),
+            outbox,
+            challenge: "in-process".into(),
+            authed_pubkeys: default(),
+        };
+        let id = client.id;
+        self.subs.write().await.insert(id, "sub".into(), filters);
+        self.clients.write().await.insert(id, client);
+        id
+    }
+
+    pub(crate) async fn untap(&self, id: &Uuid) {
+        self.subs.write().await.remove_client(id);
+        self.clients.write().await.remove(id);
+    }
+}
+
+#[cfg(test)]
+impl Switchboard {
+    /// Connects a client with a single `sub` subscription, returning its
+    /// outbox.
+    pub(crate) async fn test_subscriber(&self, filters: Vec<Filter>) -> std::sync::Arc<Outbox> {
+        let outbox = std::sync::Arc::new(Outbox::new(1024, default()));
+        self.tap(outbox.clone(), filters).await;
+        outbox
+    }
+}
+
+/// Follows the event hose, dispatching to the local clients.
+pub async fn start_switchboard(cx: SharedContext) -> eyre::Result<()> {
+    let mut backoff = std::time::Duration::from_millis(100);
+    while let Err(err) = crate::manage::load_overrides(&cx).await {
+        warn!(?err, ?backoff, "error loading overrides, retrying");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(std::time::Duration::from_secs(30));
+    }
+    cx.hose.follow(&cx).await
+}
+
+/// Dispatches an event that came off the hose.
+pub async fn switch(cx: &Context, event: &Event) {
+    trace!(?event, "event recieved for switiching");
+    // NIP-86 bans apply to events already in flight
+    if cx.manage.is_banned(event) {
+        return;
+    }
+    cx.sw.dispatch(event).await;
 }
 
 pub async fn handler(
     State(cx): State<SharedContext>,
     ConnectInfo(addr): ConnectInfo<std::net::SocketAddr>,
-    ws: WebSocketUpgrade,
-) -> impl IntoResponse {
-    // tokio::sync::RwLock<>
-    ws.on_upgrade(move |socket| handle_client(cx, socket, addr))
+    headers: http::HeaderMap,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> axum::response::Response {
+    // NIP-11 documents are served on the same route as the websocket
+    if crate::info::is_info_request(&headers) {
+        return crate::info::response(&cx);
+    }
+    if cx.sw.is_shutting_down() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "relay is shutting down").into_response();
+    }
+    let ws = match ws {
+        Ok(ws) => ws,
+        Err(err) => return err.into_response(),
+    };
+    ws.max_message_size(cx.config.limits.max_message_length)
+        .max_frame_size(cx.config.limits.max_message_length)
+        .on_upgrade(move |socket| handle_client(cx, socket, addr))
+}
+
+/// OpenAPI operation for [`handler`].
+pub fn operation() -> utoipa::openapi::path::Operation {
+    use utoipa::openapi::*;
+    path::OperationBuilder::new()
+        .operation_id(Some("connect"))
+        .tag(TAG.name)
+        .summary(Some("Connect to the relay"))
+        .description(Some(
+            "Upgrades to the websocket NIP-01 messages are exchanged over, \
+            described by the AsyncAPI document served at `asyncapi.json`. \
+            Requests that `Accept` `application/nostr+json` get the NIP-11 \
+            relay information document instead.",
+        ))
+        .response(
+            "101",
+            ResponseBuilder::new().description("Switching to the websocket"),
+        )
+        .response(
+            "200",
+            ResponseBuilder::new()
+                .description("Relay information document")
+                .content(
+                    crate::info::MEDIA_TYPE,
+                    ContentBuilder::new()
+                        .schema(Ref::from_schema_name("RelayInformationDocument"))
+                        .build(),
+                ),
+        )
+        .response(
+            "503",
+            ResponseBuilder::new().description("Relay is shutting down"),
+        )
+        .build()
+}
+
+/// Parses the subscription id and filters of REQ and COUNT messages.
+fn parse_sub_msg(msg: &[Value]) -> eyre::Result<(&str, Vec<Filter>)> {
+    let sub_id = msg[1]
+        .as_str()
+        .ok_or_else(|| eyre::eyre!("invalid {} msg: invalid subscription id on {msg:?}", msg[0]))?;
+    let filters = msg[2..]
+        .iter()
+        .map(|val| {
+            serde_json::from_value(val.clone()).map_err(|err| {
+                eyre::eyre!("invalid {} msg: invalid filter on {msg:?} | {err}", msg[0])
+            })
+        })
+        .collect::<Result<Vec<Filter>, _>>()?;
+    Ok((sub_id, filters))
+}
+
+/// Checks the subscription id and filters of REQ and COUNT messages against
+/// the limits, returning the CLOSED message if they're exceeded.
+fn check_sub_msg(limits: &crate::Limits, sub_id: &str, filters: &[Filter]) -> Option<String> {
+    if sub_id.len() > limits.max_subid_length {
+        return Some(format!(
+            "invalid: subscription id is longer than {}",
+            limits.max_subid_length
+        ));
+    }
+    if filters.len() > limits.max_filters {
+        return Some(format!("invalid: more than {} filters", limits.max_filters));
+    }
+    None
+}
+
+/// Answers a NIP-77 message, returning the NEG-ERR reason on failure.
+fn answer_neg_msg(neg: &crate::negentropy::Negentropy, msg: &Value) -> Result<String, String> {
+    let msg = msg
+        .as_str()
+        .and_then(|hex| {
+            data_encoding::HEXLOWER_PERMISSIVE
+                .decode(hex.as_bytes())
+                .ok()
+        })
+        .ok_or_else(|| "invalid: message is not hex".to_string())?;
+    neg.reconcile(&msg)
+        .map(|answer| data_encoding::HEXLOWER.encode(&answer))
+        .map_err(|err| format!("invalid: {err}"))
 }
 
 #[tracing::instrument(skip(cx, socket))]
@@ -112,39 +312,107 @@ async fn handle_client(cx: SharedContext, socket: WebSocket, addr: std::net::Soc
     // the web socket pipes
     let (mut ws_tx, mut ws_rx) = socket.split();
     // the switchboard pipes
-    let (sw_tx, mut sw_rx) = tokio::sync::mpsc::channel::<Value>(32);
+    let sw_tx = std::sync::Arc::new(Outbox::new(
+        cx.config.limits.outbox_capacity,
+        cx.config.limits.slow_consumer_policy,
+    ));
     let (close_tx, mut close_rx) = tokio::sync::mpsc::channel::<(u16, String)>(1);
+    let challenge = CHeapStr::new(Uuid::new_v4().to_string());
     {
         let mut clients = cx.sw.clients.write().await;
         clients.insert(
             id,
             Client {
                 id,
-                subs: default(),
-                tx: sw_tx.clone(),
+                outbox: sw_tx.clone(),
+                challenge: challenge.clone(),
+                authed_pubkeys: default(),
             },
         );
     }
-    let mut tx_task = tokio::spawn(async move {
-        'sel: loop {
-            tokio::select! {
-                biased; // handle close messages first since they're originate from rx errors
-                Some((code, reason)) = close_rx.recv() => {
-                    ws_tx.send(WsMsg::Close(Some(
-                            CloseFrame{ code, reason:reason.into() }
-                        )))
-                        .await.unwrap_or_log();
-                    break 'sel;
-                },
-                msg = sw_rx.recv() => {
-                    let Some(msg) = msg else {
+    sw_tx.push(json!(["AUTH", *challenge]));
+    // when the client was last heard from, in millis since it connected
+    let connected = tokio::time::Instant::now();
+    let last_seen = std::sync::Arc::new(AtomicU64::new(0));
+    let mut tx_task = tokio::spawn({
+        let sw_rx = sw_tx.clone();
+        let cx = cx.clone();
+        let last_seen = last_seen.clone();
+        async move {
+            let idle_timeout = cx.config.idle_timeout.unsigned_abs();
+            let ping_interval = cx.config.ping_interval.unsigned_abs();
+            let mut ping = tokio::time::interval_at(connected + ping_interval, ping_interval);
+            ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            'sel: loop {
+                tokio::select! {
+                    biased; // handle close messages first since they're originate from rx errors
+                    Some((code, reason)) = close_rx.recv() => {
+                        ws_tx.send(WsMsg::Close(Some(
+                                CloseFrame{ code, reason:reason.into() }
+                            )))
+                            .await.unwrap_or_log();
                         break 'sel;
-                    };
-                    ws_tx
-                        // TODO: consider using feed here
-                        .send(WsMsg::Text(serde_json::to_string(&msg).unwrap_or_log()))
-                        .await
-                        .unwrap_or_log()
+                    },
+                    _ = cx.sw.shutdown_started() => {
+                        let notice = json!(["NOTICE", "relay is shutting down"]);
+                        _ = tokio::time::timeout(cx.config.shutdown_grace.unsigned_abs(), async {
+                            ws_tx.send(WsMsg::Text(notice.to_string())).await?;
+                            ws_tx.send(WsMsg::Close(Some(CloseFrame {
+                                code: axum::extract::ws::close_code::AWAY,
+                                reason: "shutting down".into(),
+                            })))
+                            .await
+                        })
+                        .await;
+                        break 'sel;
+                    }
+                    _ = ping.tick() => {
+                        let idle = connected.elapsed().saturating_sub(
+                            std::time::Duration::from_millis(last_seen.load(Ordering::Relaxed)),
+                        );
+                        if idle >= idle_timeout {
+                            trace!(%id, "client timed out");
+                            let close = WsMsg::Close(Some(CloseFrame {
+                                code: axum::extract::ws::close_code::POLICY,
+                                reason: "idle timeout".into(),
+                            }));
+                            _ = tokio::time::timeout(CUT_OFF_GRACE, ws_tx.send(close)).await;
+                            break 'sel;
+                        }
+                        let sent = tokio::time::timeout(idle_timeout, ws_tx.send(WsMsg::Ping(vec![])));
+                        if !matches!(sent.await, Ok(Ok(()))) {
+                            break 'sel;
+                        }
+                    }
+                    msg = sw_rx.pop() => {
+                        let Some(msg) = msg else {
+                            // cut off for falling behind
+                            let notice = json!([
+                                "NOTICE",
+                                "error: disconnected for not keeping up with messages"
+                            ]);
+                            _ = tokio::time::timeout(CUT_OFF_GRACE, async {
+                                ws_tx.send(WsMsg::Text(notice.to_string())).await?;
+                                ws_tx.send(WsMsg::Close(Some(CloseFrame {
+                                    code: axum::extract::ws::close_code::POLICY,
+                                    reason: "slow consumer".into(),
+                                })))
+                                .await
+                            })
+                            .await;
+                            break 'sel;
+                        };
+                        tokio::select! {
+                            // TODO: consider using feed here
+                            res = ws_tx.send(WsMsg::Text(serde_json::to_string(&msg).unwrap_or_log())) => {
+                                res.unwrap_or_log()
+                            }
+                            // don't keep waiting on a client that's been cut off
+                            _ = sw_rx.cut_off() => {}
+                            // nor on one that's stopped reading
+                            _ = tokio::time::sleep(idle_timeout) => break 'sel,
+                        }
+                    }
                 }
             }
         }
@@ -153,7 +421,11 @@ async fn handle_client(cx: SharedContext, socket: WebSocket, addr: std::net::Soc
         let cx2 = cx.clone();
         tokio::spawn(async move {
             let cx = cx2;
+            // NIP-77 reconciliations by subscription id
+            let mut neg_sessions = HashMap::<String, crate::negentropy::Negentropy>::new();
             while let Some(Ok(msg)) = ws_rx.next().await {
+                // pongs count too
+                last_seen.store(connected.elapsed().as_millis() as u64, Ordering::Relaxed);
                 let mut msg: Vec<Value> = match msg {
                     WsMsg::Text(str) => serde_json::from_str(&str)
                         .map_err(|err| eyre::eyre!("unexpected msg recieved: {err} | {str}"))?,
@@ -169,33 +441,74 @@ async fn handle_client(cx: SharedContext, socket: WebSocket, addr: std::net::Soc
                 };
                 match kind {
                     "EVENT" if msg.len() == 2 => {
-                        let event = serde_json::from_value(msg.pop().unwrap()).map_err(|err| {
-                            eyre::eyre!(
-                                "unexpected msg recieved: invalid EVENT msg {msg:?} | {err}"
-                            )
-                        })?;
+                        let event: Event =
+                            serde_json::from_value(msg.pop().unwrap()).map_err(|err| {
+                                eyre::eyre!(
+                                    "unexpected msg recieved: invalid EVENT msg {msg:?} | {err}"
+                                )
+                            })?;
                         trace!(?event, "client sent Event");
-                        let res = crate::event::create::CreateEvent.handle(&cx, event).await;
+                        // the author's limit is charged once the signature's
+                        // been checked
+                        if let Some(limit) = crate::rate_limit::exceeded(
+                            &cx,
+                            &format!("ip:{}", addr.ip()),
+                            cx.config.limits.ip_event_rate,
+                        )
+                        .await
+                        {
+                            sw_tx.push(json!([
+                                "OK",
+                                event.id,
+                                false,
+                                crate::event::create::ErrorKind::RateLimited {
+                                    count: limit.count,
+                                    window_secs: limit.window_secs(),
+                                }
+                                .to_string()
+                            ]));
+                            continue;
+                        }
+                        let origin = crate::event::policy::Origin {
+                            authed_pubkeys: cx.sw.authed_pubkeys(&id).await.unwrap_or_default(),
+                            ip: Some(addr.ip()),
+                        };
+                        let res = crate::event::create::CreateEvent
+                            .handle_from(&cx, event, &origin)
+                            .await;
                         let res = match res {
                             Ok(ok) => ok.to_nostr_ok(),
                             Err(err) => err.to_nostr_ok(),
                         };
-                        sw_tx.send(res).await.unwrap_or_log();
+                        sw_tx.push(res);
                     }
                     "REQ" if msg.len() >= 3 => {
-                        let sub_id = msg[1].as_str().ok_or_else(|| {
-                            eyre::eyre!("invalid REQ msg: invalid subscription id on {msg:?}")
-                        })?;
-                        let filters = msg[2..]
-                            .iter()
-                            .map(|val| {
-                                serde_json::from_value(val.clone()).map_err(|err| {
-                                    eyre::eyre!(
-                                        "invalid REQ msg: invalid filter on {msg:?} | {err}"
+                        let (sub_id, filters) = parse_sub_msg(&msg)?;
+                        if cx.sw.is_shutting_down() {
+                            sw_tx.push(json!(["CLOSED", sub_id, "error: relay is shutting down"]));
+                            continue;
+                        }
+                        let limits = &cx.config.limits;
+                        if let Some(reason) = check_sub_msg(limits, sub_id, &filters) {
+                            sw_tx.push(json!(["CLOSED", sub_id, reason]));
+                            continue;
+                        }
+                        {
+                            let subs = cx.sw.subs.read().await;
+                            if !subs.contains(&id, &sub_id.into())
+                                && subs.sub_count(&id) >= limits.max_subscriptions
+                            {
+                                sw_tx.push(json!([
+                                    "CLOSED",
+                                    sub_id,
+                                    format!(
+                                        "blocked: no more than {} open subscriptions",
+                                        limits.max_subscriptions
                                     )
-                                })
-                            })
-                            .collect::<Result<Vec<Filter>, _>>()?;
+                                ]));
+                                continue;
+                            }
+                        }
                         let events = crate::event::list::ListEvents
                             .handle(&cx, filters.clone())
                             .await
@@ -206,54 +519,170 @@ async fn handle_client(cx: SharedContext, socket: WebSocket, addr: std::net::Soc
                                 err => Err(err).unwrap_or_log(),
                             })?;
                         for event in events {
-                            sw_tx
-                                .send(json!(["EVENT", sub_id, event]))
-                                .await
-                                .unwrap_or_log();
+                            sw_tx.push(json!(["EVENT", sub_id, event]));
                         }
-                        sw_tx.send(json!(["EOSE", sub_id])).await.unwrap_or_log();
-
-                        let clients = cx.sw.clients.read().await;
-                        let client = clients.get(&id).expect_or_log("client not found under id");
-                        let mut subs = client.subs.write().await;
+                        sw_tx.push(json!(["EOSE", sub_id]));
 
                         let sub_id = CHeapStr::new(sub_id.to_string());
                         trace!(%sub_id, ?filters, "client created subscription");
-                        if let Some(sub) = subs.iter_mut().find(|sub| sub.id == sub_id) {
-                            sub.filters = filters;
-                        } else {
-                            subs.push(Subscription {
-                                id: sub_id,
-                                filters,
-                            });
+                        cx.sw.subs.write().await.insert(id, sub_id, filters);
+                    }
+                    "COUNT" if msg.len() >= 3 => {
+                        let (sub_id, filters) = parse_sub_msg(&msg)?;
+                        if let Some(reason) = check_sub_msg(&cx.config.limits, sub_id, &filters) {
+                            sw_tx.push(json!(["CLOSED", sub_id, reason]));
+                            continue;
                         }
+                        let res = crate::event::count::CountEvents
+                            .handle(&cx, filters)
+                            .await
+                            .map_err(|err| match err {
+                                crate::event::list::Error::InvalidInput { issues } => {
+                                    eyre::eyre!("error during COUNT: {issues}")
+                                }
+                                err => Err(err).unwrap_or_log(),
+                            })?;
+                        sw_tx.push(json!(["COUNT", sub_id, res]));
                     }
-                    // FIXME: test this
-                    "CLOSE" if msg.len() == 2 => {
-                        let sub_id = msg[1].as_str().ok_or_else(|| {
-                            eyre::eyre!("invalid CLOSE msg: invalid subscription id on {msg:?}")
+                    "AUTH" if msg.len() == 2 => {
+                        let event = serde_json::from_value(msg.pop().unwrap()).map_err(|err| {
+                            eyre::eyre!("unexpected msg recieved: invalid AUTH msg {msg:?} | {err}")
                         })?;
+                        trace!(?event, "client sent AUTH");
                         let clients = cx.sw.clients.read().await;
                         let client = clients.get(&id).expect_or_log("client not found under id");
-                        let mut subs = client.subs.write().await;
-
-                        let mut found = false;
-                        for (ii, sub) in subs.iter().enumerate() {
-                            if *sub.id == sub_id {
-                                subs.swap_remove(ii);
-                                found = true;
-                                break;
+                        let res = crate::auth::Authenticate
+                            .handle(
+                                &cx,
+                                crate::auth::Request {
+                                    event,
+                                    challenge: client.challenge.clone(),
+                                },
+                            )
+                            .await;
+                        let res = match res {
+                            Ok(ok) => {
+                                let mut authed = client.authed_pubkeys.write().await;
+                                if !authed.contains(&ok.pubkey) {
+                                    trace!(pubkey = %ok.pubkey, "client authenticated");
+                                    authed.push(ok.pubkey.clone());
+                                }
+                                ok.to_nostr_ok()
+                            }
+                            Err(err) => err.to_nostr_ok(),
+                        };
+                        sw_tx.push(res);
+                    }
+                    "NEG-OPEN" if msg.len() == 4 => {
+                        let (sub_id, filters) = parse_sub_msg(&msg[..3])?;
+                        let limits = &cx.config.limits;
+                        if let Some(reason) = check_sub_msg(limits, sub_id, &filters) {
+                            sw_tx.push(json!(["NEG-ERR", sub_id, reason]));
+                            continue;
+                        }
+                        // a NEG-OPEN under an open id replaces it
+                        if neg_sessions.remove(sub_id).is_none()
+                            && neg_sessions.len() >= limits.max_subscriptions
+                        {
+                            sw_tx.push(json!([
+                                "NEG-ERR",
+                                sub_id,
+                                format!(
+                                    "blocked: no more than {} open reconciliations",
+                                    limits.max_subscriptions
+                                )
+                            ]));
+                            continue;
+                        }
+                        let items = match cx
+                            .store
+                            .negentropy_items(&filters, limits.max_negentropy_items + 1)
+                            .await
+                        {
+                            Ok(items) => items,
+                            Err(issues) => {
+                                sw_tx.push(json!([
+                                    "NEG-ERR",
+                                    sub_id,
+                                    format!("invalid: {issues}")
+                                ]));
+                                continue;
+                            }
+                        };
+                        if items.len() > limits.max_negentropy_items {
+                            sw_tx.push(json!([
+                                "NEG-ERR",
+                                sub_id,
+                                format!(
+                                    "blocked: query matches more than {} events",
+                                    limits.max_negentropy_items
+                                )
+                            ]));
+                            continue;
+                        }
+                        // answers are kept within what the relay itself accepts,
+                        // hex taking twice the bytes
+                        let neg = crate::negentropy::Negentropy::new(
+                            items,
+                            limits.max_message_length / 2,
+                        );
+                        match answer_neg_msg(&neg, &msg[3]) {
+                            Ok(answer) => {
+                                trace!(%sub_id, ?filters, "client opened reconciliation");
+                                sw_tx.push(json!(["NEG-MSG", sub_id, answer]));
+                                neg_sessions.insert(sub_id.to_string(), neg);
+                            }
+                            Err(reason) => {
+                                sw_tx.push(json!(["NEG-ERR", sub_id, reason]));
                             }
                         }
+                    }
+                    "NEG-MSG" if msg.len() == 3 => {
+                        let sub_id = msg[1].as_str().ok_or_else(|| {
+                            eyre::eyre!("invalid NEG-MSG msg: invalid subscription id on {msg:?}")
+                        })?;
+                        let Some(neg) = neg_sessions.get(sub_id) else {
+                            sw_tx.push(json!([
+                                "NEG-ERR",
+                                sub_id,
+                                "closed: no reconciliation open under the subscription id"
+                            ]));
+                            continue;
+                        };
+                        match answer_neg_msg(neg, &msg[2]) {
+                            Ok(answer) => {
+                                sw_tx.push(json!(["NEG-MSG", sub_id, answer]));
+                            }
+                            Err(reason) => {
+                                neg_sessions.remove(sub_id);
+                                sw_tx.push(json!(["NEG-ERR", sub_id, reason]));
+                            }
+                        }
+                    }
+                    "NEG-CLOSE" if msg.len() == 2 => {
+                        let sub_id = msg[1].as_str().ok_or_else(|| {
+                            eyre::eyre!("invalid NEG-CLOSE msg: invalid subscription id on {msg:?}")
+                        })?;
+                        let found = neg_sessions.remove(sub_id).is_some();
+                        trace!(%sub_id, found, "client closed reconciliation");
+                    }
+                    // FIXME: test this
+                    "CLOSE" if msg.len() == 2 => {
+                        let sub_id = msg[1].as_str().ok_or_else(|| {
+                            eyre::eyre!("invalid CLOSE msg: invalid subscription id on {msg:?}")
+                        })?;
+                        let found = cx
+                            .sw
+                            .subs
+                            .write()
+                            .await
+                            .remove(&id, &CHeapStr::new(sub_id.to_string()));
                         trace!(%sub_id, found, "client closed subscription");
                         if !found {
-                            sw_tx
-                                .send(json!([
-                                    "NOTICE",
-                                    format!("no subscription found to close under id {sub_id}")
-                                ]))
-                                .await
-                                .unwrap_or_log();
+                            sw_tx.push(json!([
+                                "NOTICE",
+                                format!("no subscription found to close under id {sub_id}")
+                            ]));
                         }
                     }
                     _ => return Err(eyre::eyre!("invalid msg recieved: {msg:?}")),
@@ -286,11 +715,18 @@ async fn handle_client(cx: SharedContext, socket: WebSocket, addr: std::net::Soc
     {
         let mut clients = cx.sw.clients.write().await;
         let client = clients.remove(&id).unwrap_or_log();
-        let subs = client.subs.read().await;
+        cx.sw
+            .dropped_msgs
+            .fetch_add(client.outbox.dropped(), Ordering::Relaxed);
+        if client.outbox.is_cut_off() {
+            cx.sw.slow_disconnects.fetch_add(1, Ordering::Relaxed);
+        }
+        let sub_count = cx.sw.subs.write().await.remove_client(&id);
         trace!(
             %id,
             %connected_at,
-            sub_count = subs.len(),
+            sub_count,
+            dropped_msgs = client.outbox.dropped(),
             "client disconnected"
         );
     }
@@ -301,8 +737,6 @@ mod tests {
     use super::*;
     use crate::event::testing::*;
 
-    const TEST_PRIVKEY: &str = "95dfc6261ec6c66b3ec68e1b019cf6420e1d676c29c1241ec5dea551ed89e338";
-
     fn fixture_request_json() -> serde_json::Value {
         let content = "The stars are a burning sun";
 
@@ -341,109 +775,695 @@ mod tests {
         })
     }
 
-    #[test]
-    fn suite() {
-        common::utils::testing::setup_tracing_once();
-        let future = async {
-            use tokio_tungstenite::tungstenite::Message as WsMsg;
-            let (testing, cx) = crate::utils::testing::cx_fn(common::function_full!()).await;
-            {
-                let addr = "127.0.0.1:19000";
-                let router = crate::router(cx);
-                let server_handle =
-                    tokio::spawn(axum::Server::bind(&addr.parse().unwrap()).serve(
-                        router.into_make_service_with_connect_info::<std::net::SocketAddr>(),
-                    ));
-                let (mut ws_stream, response) =
-                    tokio_tungstenite::connect_async("ws://127.0.0.1:19000")
-                        .await
-                        .map_err(|err| {
-                            if let tokio_tungstenite::tungstenite::Error::Http(err) = &err {
-                                if let Some(body) = err.body() {
-                                    let body = String::from_utf8(body.clone());
-                                    error!(?body, ?err);
+    common::table_tests! {
+        suite,
+        (backend, port),
+        {
+            common::utils::testing::setup_tracing_once();
+            let future = async {
+                use tokio_tungstenite::tungstenite::Message as WsMsg;
+                let (testing, cx) = crate::utils::testing::cx_fn_with_backend(
+                    common::function_full!(),
+                    backend,
+                    |_| {},
+                )
+                .await;
+                {
+                    let addr = format!("127.0.0.1:{port}");
+                    let router = crate::router(cx.clone());
+                    let server_handle =
+                        tokio::spawn(axum::Server::bind(&addr.parse().unwrap()).serve(
+                            router.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+                        ));
+                    let (mut ws_stream, response) =
+                        tokio_tungstenite::connect_async(format!("ws://{addr}"))
+                            .await
+                            .map_err(|err| {
+                                if let tokio_tungstenite::tungstenite::Error::Http(err) = &err {
+                                    if let Some(body) = err.body() {
+                                        let body = String::from_utf8(body.clone());
+                                        error!(?body, ?err);
+                                    }
                                 }
+                                err
+                            })?;
+                    info!(?response);
+                    // test AUTH
+                    {
+                        let challenge = match ws_stream.next().await {
+                            Some(Ok(WsMsg::Text(val))) => {
+                                let resp: Vec<Value> = serde_json::from_str(&val[..])?;
+                                assert_eq!(resp[0], "AUTH", "expected challenge first: {resp:?}");
+                                resp[1].as_str().unwrap().to_string()
                             }
-                            err
-                        })?;
-                info!(?response);
-                let sub_id = Uuid::new_v4().to_string();
-                // test REQ
-                {
-                    ws_stream
-                        .send(WsMsg::Binary(serde_json::to_vec(&json!([
-                            "REQ",
-                            sub_id,
-                            {}
-                        ]))?))
-                        .await?;
-                    let mut event_ctr = 0;
-                    while let Some(Ok(msg)) = ws_stream.next().await {
-                        let resp = match msg {
-                            WsMsg::Text(val) => val,
-                            WsMsg::Pong(_) | WsMsg::Ping(_) | WsMsg::Close(_) => continue,
-                            msg => panic!("unexpected message {msg}"),
+                            msg => panic!("unexpected message {msg:?}"),
                         };
-                        let resp: Vec<Value> = serde_json::from_str(&resp[..])?;
-                        let kind = resp[0].as_str().unwrap();
-                        match kind {
-                            "EVENT" => event_ctr += 1,
-                            "EOSE" => {
+                        // wrong challenge
+                        let event = crate::auth::testing::auth_event(
+                            EVENT_01_PRIVKEY,
+                            &cx.config.relay_url,
+                            "cafe babe",
+                            OffsetDateTime::now_utc(),
+                        );
+                        ws_stream
+                            .send(WsMsg::Text(serde_json::to_string(&json!(["AUTH", event]))?))
+                            .await?;
+                        match ws_stream.next().await {
+                            Some(Ok(WsMsg::Text(val))) => {
+                                let resp: Value = serde_json::from_str(&val[..])?;
                                 check_json(
-                                    ("expected", &json!(["EOSE", sub_id])),
-                                    ("response", &Value::Array(resp)),
+                                    ("expected", &json!(["OK", event.id, false])),
+                                    ("response", &resp),
                                 );
-                                assert_eq!(event_ctr, 5);
-                                break;
                             }
-                            _ => panic!("unexpected event kind {kind}: {resp:?}"),
-                        }
-                    }
-                }
-                // test EVENT
-                {
-                    let event = fixture_request_json();
-                    ws_stream
-                        .send(WsMsg::Binary(serde_json::to_vec(&json!(["EVENT", event]))?))
-                        .await?;
-                    while let Some(Ok(msg)) = ws_stream.next().await {
-                        let resp = match msg {
-                            WsMsg::Text(val) => val,
-                            WsMsg::Pong(_) | WsMsg::Ping(_) | WsMsg::Close(_) => continue,
-                            msg => panic!("unexpected message {msg}"),
+                            msg => panic!("unexpected message {msg:?}"),
                         };
-                        let resp: Vec<Value> = serde_json::from_str(&resp[..])?;
-                        let kind = resp[0].as_str().unwrap();
-                        match kind {
-                            "EVENT" => {
+                        let event = crate::auth::testing::auth_event(
+                            EVENT_01_PRIVKEY,
+                            &cx.config.relay_url,
+                            &challenge,
+                            OffsetDateTime::now_utc(),
+                        );
+                        ws_stream
+                            .send(WsMsg::Text(serde_json::to_string(&json!(["AUTH", event]))?))
+                            .await?;
+                        match ws_stream.next().await {
+                            Some(Ok(WsMsg::Text(val))) => {
+                                let resp: Value = serde_json::from_str(&val[..])?;
                                 check_json(
-                                    ("expected", &json!(["EVENT", sub_id, event])),
-                                    ("response", &Value::Array(resp)),
+                                    ("expected", &json!(["OK", event.id, true])),
+                                    ("response", &resp),
                                 );
-                                break;
                             }
-                            "OK" => {
+                            msg => panic!("unexpected message {msg:?}"),
+                        };
+                        let client_id = *cx.sw.clients.read().await.keys().next().unwrap();
+                        assert_eq!(
+                            cx.sw.authed_pubkeys(&client_id).await,
+                            Some(vec![event.pubkey.clone()])
+                        );
+                    }
+                    let sub_id = Uuid::new_v4().to_string();
+                    // test REQ
+                    {
+                        ws_stream
+                            .send(WsMsg::Binary(serde_json::to_vec(&json!([
+                                "REQ",
+                                sub_id,
+                                {}
+                            ]))?))
+                            .await?;
+                        let mut event_ctr = 0;
+                        while let Some(Ok(msg)) = ws_stream.next().await {
+                            let resp = match msg {
+                                WsMsg::Text(val) => val,
+                                WsMsg::Pong(_) | WsMsg::Ping(_) | WsMsg::Close(_) => continue,
+                                msg => panic!("unexpected message {msg}"),
+                            };
+                            let resp: Vec<Value> = serde_json::from_str(&resp[..])?;
+                            let kind = resp[0].as_str().unwrap();
+                            match kind {
+                                "EVENT" => event_ctr += 1,
+                                "EOSE" => {
+                                    check_json(
+                                        ("expected", &json!(["EOSE", sub_id])),
+                                        ("response", &Value::Array(resp)),
+                                    );
+                                    assert_eq!(event_ctr, 5);
+                                    break;
+                                }
+                                _ => panic!("unexpected event kind {kind}: {resp:?}"),
+                            }
+                        }
+                    }
+                    // test COUNT
+                    {
+                        ws_stream
+                            .send(WsMsg::Text(serde_json::to_string(&json!([
+                                "COUNT",
+                                sub_id,
+                                { "kinds": [1] }
+                            ]))?))
+                            .await?;
+                        match ws_stream.next().await {
+                            Some(Ok(WsMsg::Text(val))) => {
+                                let resp: Value = serde_json::from_str(&val[..])?;
                                 check_json(
-                                    ("expected", &json!(["OK", event["id"], true])),
-                                    ("response", &Value::Array(resp)),
+                                    ("expected", &json!(["COUNT", sub_id, { "count": 4 }])),
+                                    ("response", &resp),
                                 );
                             }
-                            _ => panic!("unexpected event kind {kind}: {resp:?}"),
+                            msg => panic!("unexpected message {msg:?}"),
+                        };
+                    }
+                    let event = fixture_request_json();
+                    // test EVENT
+                    {
+                        ws_stream
+                            .send(WsMsg::Binary(serde_json::to_vec(&json!(["EVENT", event]))?))
+                            .await?;
+                        let (mut got_ok, mut got_event) = (false, false);
+                        while let Some(Ok(msg)) = ws_stream.next().await {
+                            let resp = match msg {
+                                WsMsg::Text(val) => val,
+                                WsMsg::Pong(_) | WsMsg::Ping(_) | WsMsg::Close(_) => continue,
+                                msg => panic!("unexpected message {msg}"),
+                            };
+                            let resp: Vec<Value> = serde_json::from_str(&resp[..])?;
+                            let kind = resp[0].as_str().unwrap();
+                            match kind {
+                                "EVENT" => {
+                                    check_json(
+                                        ("expected", &json!(["EVENT", sub_id, event])),
+                                        ("response", &Value::Array(resp)),
+                                    );
+                                    got_event = true;
+                                }
+                                "OK" => {
+                                    check_json(
+                                        ("expected", &json!(["OK", event["id"], true])),
+                                        ("response", &Value::Array(resp)),
+                                    );
+                                    got_ok = true;
+                                }
+                                _ => panic!("unexpected event kind {kind}: {resp:?}"),
+                            }
+                            // the OK and the EVENT from the switchboard can arrive in any order
+                            if got_ok && got_event {
+                                break;
+                            }
                         }
                     }
+                    // test deletion requests reach live subscribers
+                    {
+                        let deletion = sign(
+                            TEST_PRIVKEY,
+                            OffsetDateTime::now_utc(),
+                            5,
+                            vec![vec![
+                                "e".to_string(),
+                                event["id"].as_str().unwrap().to_string(),
+                            ]],
+                            "",
+                        );
+                        ws_stream
+                            .send(WsMsg::Binary(serde_json::to_vec(&json!([
+                                "EVENT", deletion
+                            ]))?))
+                            .await?;
+                        let (mut got_ok, mut got_event) = (false, false);
+                        while let Some(Ok(msg)) = ws_stream.next().await {
+                            let resp = match msg {
+                                WsMsg::Text(val) => val,
+                                WsMsg::Pong(_) | WsMsg::Ping(_) | WsMsg::Close(_) => continue,
+                                msg => panic!("unexpected message {msg}"),
+                            };
+                            let resp: Vec<Value> = serde_json::from_str(&resp[..])?;
+                            let kind = resp[0].as_str().unwrap();
+                            match kind {
+                                "EVENT" => {
+                                    check_json(
+                                        ("expected", &json!(["EVENT", sub_id, deletion])),
+                                        ("response", &Value::Array(resp)),
+                                    );
+                                    got_event = true;
+                                }
+                                "OK" => {
+                                    check_json(
+                                        ("expected", &json!(["OK", deletion.id, true])),
+                                        ("response", &Value::Array(resp)),
+                                    );
+                                    got_ok = true;
+                                }
+                                _ => panic!("unexpected event kind {kind}: {resp:?}"),
+                            }
+                            // the OK and the EVENT from the switchboard can arrive in any order
+                            if got_ok && got_event {
+                                break;
+                            }
+                        }
+                    }
+                    server_handle.abort();
+                    // let (mut ws_tx, mut ws_rx) = ws_stream.split();
+                }
+                testing.close().await;
+                Ok::<_, eyre::Report>(())
+            };
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async { tokio::time::timeout(std::time::Duration::new(30, 0), future).await })
+                .unwrap_or_log()
+                .unwrap_or_log();
+        }
+    }
+
+    suite! {
+        pg: (crate::utils::testing::Backend::Pg, 19000),
+        mem: (crate::utils::testing::Backend::Mem, 19004),
+    }
+
+    fn test_client(capacity: usize, policy: SlowConsumerPolicy) -> Client {
+        Client {
+            id: Uuid::new_v4(),
+            outbox: std::sync::Arc::new(Outbox::new(capacity, policy)),
+            challenge: "challenge".into(),
+            authed_pubkeys: default(),
+        }
+    }
+
+    common::table_tests! {
+        slow_consumers tokio,
+        (policy, frozen_queued, frozen_dropped),
+        {
+            const EVENT_COUNT: usize = 100;
+            let sw = Switchboard::default();
+            let frozen = test_client(8, policy);
+            let healthy = test_client(1024, policy);
+            let (frozen_outbox, healthy_outbox) = (frozen.outbox.clone(), healthy.outbox.clone());
+            for client in [&frozen, &healthy] {
+                sw.subs.write().await.insert(
+                    client.id,
+                    "sub".into(),
+                    serde_json::from_value(json!([{ "kinds": [1] }])).unwrap(),
+                );
+            }
+            {
+                let mut clients = sw.clients.write().await;
+                clients.insert(frozen.id, frozen);
+                clients.insert(healthy.id, healthy);
+            }
+            let reader = tokio::spawn(async move {
+                for _ in 0..EVENT_COUNT {
+                    healthy_outbox.pop().await.unwrap();
+                }
+            });
+            // nobody reads from the frozen client but dispatch must not wait on it
+            tokio::time::timeout(std::time::Duration::from_secs(5), async {
+                for _ in 0..EVENT_COUNT {
+                    sw.dispatch(&EVENT_01).await;
                 }
-                server_handle.abort();
-                // let (mut ws_tx, mut ws_rx) = ws_stream.split();
+            })
+            .await
+            .expect("dispatch was held up by the frozen client");
+            tokio::time::timeout(std::time::Duration::from_secs(5), reader)
+                .await
+                .expect("healthy client didn't get all its events")
+                .unwrap();
+            assert_eq!(frozen_outbox.len(), frozen_queued);
+            assert_eq!(frozen_outbox.dropped(), frozen_dropped);
+            assert_eq!(sw.dropped_msgs().await, frozen_dropped);
+        },
+        multi_thread: true,
+    }
+
+    slow_consumers! {
+        drop_oldest: (SlowConsumerPolicy::DropOldest, 8, 92),
+        disconnect: (SlowConsumerPolicy::Disconnect, 0, 100),
+    }
+
+    async fn next_json<S>(ws: &mut S) -> Value
+    where
+        S: futures::Stream<
+                Item = Result<
+                    tokio_tungstenite::tungstenite::Message,
+                    tokio_tungstenite::tungstenite::Error,
+                >,
+            > + Unpin,
+    {
+        match ws.next().await {
+            Some(Ok(tokio_tungstenite::tungstenite::Message::Text(val))) => {
+                serde_json::from_str(&val[..]).unwrap()
             }
-            testing.close().await;
-            Ok::<_, eyre::Report>(())
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+
+    fn signed_event(content: &str) -> Event {
+        sign(TEST_PRIVKEY, OffsetDateTime::now_utc(), 1, vec![], content)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn enforces_limits() {
+        use tokio_tungstenite::tungstenite::Message as WsMsg;
+        let (testing, cx) =
+            crate::utils::testing::cx_fn_with_config(common::function_full!(), |config| {
+                config.limits.max_subscriptions = 1;
+                config.limits.max_filters = 2;
+                config.limits.max_subid_length = 8;
+                config.limits.ip_event_rate = Some(crate::rate_limit::RateLimit {
+                    count: 1,
+                    window: std::time::Duration::from_secs(60 * 60),
+                });
+            })
+            .await;
+        {
+            let addr = "127.0.0.1:19002";
+            let router = crate::router(cx.clone());
+            let server_handle = tokio::spawn(
+                axum::Server::bind(&addr.parse().unwrap())
+                    .serve(router.into_make_service_with_connect_info::<std::net::SocketAddr>()),
+            );
+            let (mut ws, _) = tokio_tungstenite::connect_async("ws://127.0.0.1:19002")
+                .await
+                .unwrap();
+            let challenge = next_json(&mut ws).await;
+            assert_eq!(challenge[0], "AUTH");
+
+            for (msg, expected) in [
+                (
+                    json!(["REQ", "waytoolong", { "limit": 0 }]),
+                    json!([
+                        "CLOSED",
+                        "waytoolong",
+                        "invalid: subscription id is longer than 8"
+                    ]),
+                ),
+                (
+                    json!(["REQ", "a", { "limit": 0 }, { "limit": 0 }, { "limit": 0 }]),
+                    json!(["CLOSED", "a", "invalid: more than 2 filters"]),
+                ),
+                (
+                    json!(["COUNT", "a", { "limit": 0 }, { "limit": 0 }, { "limit": 0 }]),
+                    json!(["CLOSED", "a", "invalid: more than 2 filters"]),
+                ),
+                (json!(["REQ", "a", { "limit": 0 }]), json!(["EOSE", "a"])),
+                (
+                    json!(["REQ", "b", { "limit": 0 }]),
+                    json!(["CLOSED", "b", "blocked: no more than 1 open subscriptions"]),
+                ),
+                // replacing an open subscription is fine
+                (json!(["REQ", "a", { "limit": 0 }]), json!(["EOSE", "a"])),
+            ] {
+                ws.send(WsMsg::Text(msg.to_string())).await.unwrap();
+                check_json(
+                    ("expected", &expected),
+                    ("response", &next_json(&mut ws).await),
+                );
+            }
+
+            let event = signed_event("first");
+            ws.send(WsMsg::Text(json!(["EVENT", event]).to_string()))
+                .await
+                .unwrap();
+            // the open subscription gets it too
+            let mut msgs = vec![next_json(&mut ws).await, next_json(&mut ws).await];
+            msgs.sort_by_key(|msg| msg[0] == "EVENT");
+            check_json(
+                ("expected", &json!([["OK", event.id, true], ["EVENT", "a"]])),
+                ("response", &json!(msgs)),
+            );
+
+            let event = signed_event("second");
+            ws.send(WsMsg::Text(json!(["EVENT", event]).to_string()))
+                .await
+                .unwrap();
+            let ok = next_json(&mut ws).await;
+            check_json(
+                ("expected", &json!(["OK", event.id, false])),
+                ("response", &ok),
+            );
+            assert!(
+                ok[3].as_str().unwrap().starts_with("rate-limited:"),
+                "{ok:?}"
+            );
+
+            server_handle.abort();
+        }
+        testing.close().await;
+    }
+
+    fn serve(cx: &SharedContext, addr: &str) -> tokio::task::JoinHandle<hyper::Result<()>> {
+        let router = crate::router(cx.clone());
+        tokio::spawn(
+            axum::Server::bind(&addr.parse().unwrap())
+                .serve(router.into_make_service_with_connect_info::<std::net::SocketAddr>()),
+        )
+    }
+
+    /// Waits for the switchboard to let go of the disconnected clients.
+    async fn clients_gone(cx: &Context) {
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !cx.sw.clients.read().await.is_empty() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("client wasn't removed from the switchboard");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keepalive() {
+        use tokio_tungstenite::tungstenite::Message as WsMsg;
+        let (testing, cx) =
+            crate::utils::testing::cx_fn_with_config(common::function_full!(), |config| {
+                config.ping_interval = time::Duration::milliseconds(100);
+                config.idle_timeout = time::Duration::milliseconds(500);
+            })
+            .await;
+        {
+            let server_handle = serve(&cx, "127.0.0.1:19005");
+            let (mut ws, _) = tokio_tungstenite::connect_async("ws://127.0.0.1:19005")
+                .await
+                .unwrap();
+            assert_eq!(next_json(&mut ws).await[0], "AUTH");
+
+            // reading answers the pings which keeps the connection alive
+            let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(1);
+            let mut pings = 0;
+            while let Ok(msg) = tokio::time::timeout_at(deadline, ws.next()).await {
+                match msg {
+                    Some(Ok(WsMsg::Ping(_))) => pings += 1,
+                    msg => panic!("unexpected message {msg:?}"),
+                }
+            }
+            assert!(pings >= 5, "only {pings} pings in a second");
+
+            // stop answering
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            loop {
+                match ws.next().await {
+                    Some(Ok(WsMsg::Ping(_))) => continue,
+                    Some(Ok(WsMsg::Close(Some(frame)))) => {
+                        assert_eq!(frame.reason, "idle timeout");
+                        break;
+                    }
+                    // answering the queued pings can race the server hanging up
+                    Some(Err(_)) | None => break,
+                    msg => panic!("unexpected message {msg:?}"),
+                }
+            }
+            clients_gone(&cx).await;
+
+            server_handle.abort();
+        }
+        testing.close().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn graceful_shutdown() {
+        use tokio_tungstenite::tungstenite::{
+            protocol::frame::coding::CloseCode, Error as WsError, Message as WsMsg,
         };
-        tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()
-            .unwrap()
-            .block_on(async { tokio::time::timeout(std::time::Duration::new(30, 0), future).await })
-            .unwrap_or_log()
-            .unwrap_or_log();
+        let (testing, cx) = crate::utils::testing::cx_fn(common::function_full!()).await;
+        {
+            let server_handle = serve(&cx, "127.0.0.1:19006");
+            let (mut ws, _) = tokio_tungstenite::connect_async("ws://127.0.0.1:19006")
+                .await
+                .unwrap();
+            assert_eq!(next_json(&mut ws).await[0], "AUTH");
+            ws.send(WsMsg::Text(json!(["REQ", "a", { "limit": 0 }]).to_string()))
+                .await
+                .unwrap();
+            assert_eq!(next_json(&mut ws).await, json!(["EOSE", "a"]));
+
+            let shutdown = tokio::spawn({
+                let cx = cx.clone();
+                async move { cx.sw.shutdown(std::time::Duration::from_secs(5)).await }
+            });
+            assert_eq!(
+                next_json(&mut ws).await,
+                json!(["NOTICE", "relay is shutting down"])
+            );
+            match ws.next().await {
+                Some(Ok(WsMsg::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Away),
+                msg => panic!("unexpected message {msg:?}"),
+            }
+            tokio::time::timeout(std::time::Duration::from_secs(5), shutdown)
+                .await
+                .expect("shutdown didn't wrap up")
+                .unwrap();
+            assert!(cx.sw.clients.read().await.is_empty());
+
+            // latecomers are turned away
+            match tokio_tungstenite::connect_async("ws://127.0.0.1:19006").await {
+                Err(WsError::Http(response)) => {
+                    assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE)
+                }
+                res => panic!("unexpected result {res:?}"),
+            }
+
+            server_handle.abort();
+        }
+        testing.close().await;
+    }
+
+    type Ws = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    async fn connect(url: &str) -> Ws {
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        assert_eq!(next_json(&mut ws).await[0], "AUTH");
+        ws
+    }
+
+    /// The reconciliation items of all the events stored at the relay.
+    async fn items_of(ws: &mut Ws) -> Vec<crate::negentropy::Item> {
+        use tokio_tungstenite::tungstenite::Message as WsMsg;
+        ws.send(WsMsg::Text(json!(["REQ", "items", {}]).to_string()))
+            .await
+            .unwrap();
+        let mut items = vec![];
+        loop {
+            let msg = next_json(ws).await;
+            if msg[0] == "EOSE" {
+                break;
+            }
+            let event: Event = serde_json::from_value(msg[2].clone()).unwrap();
+            let mut id = [0; 32];
+            data_encoding::HEXLOWER
+                .decode_mut(event.id.as_bytes(), &mut id)
+                .unwrap();
+            items.push(crate::negentropy::Item {
+                timestamp: event.created_at.unix_timestamp() as u64,
+                id,
+            });
+        }
+        ws.send(WsMsg::Text(json!(["CLOSE", "items"]).to_string()))
+            .await
+            .unwrap();
+        items
+    }
+
+    /// Reconciles the items against the relay, returning the hex ids only
+    /// the items have, those only the relay has and the number of rounds
+    /// it took.
+    async fn reconcile(
+        ws: &mut Ws,
+        items: Vec<crate::negentropy::Item>,
+    ) -> (
+        std::collections::HashSet<String>,
+        std::collections::HashSet<String>,
+        usize,
+    ) {
+        use tokio_tungstenite::tungstenite::Message as WsMsg;
+        let neg = crate::negentropy::Negentropy::new(items, 0);
+        let hex = |buf: &[u8]| data_encoding::HEXLOWER.encode(buf);
+        let (mut have, mut need) = (vec![], vec![]);
+        let mut msg = json!(["NEG-OPEN", "sync", {}, hex(&neg.initiate())]);
+        let mut rounds = 0;
+        loop {
+            rounds += 1;
+            ws.send(WsMsg::Text(msg.to_string())).await.unwrap();
+            let answer = next_json(ws).await;
+            assert_eq!(answer[0], "NEG-MSG", "{answer:?}");
+            let answer = data_encoding::HEXLOWER
+                .decode(answer[2].as_str().unwrap().as_bytes())
+                .unwrap();
+            match neg
+                .reconcile_with_ids(&answer, &mut have, &mut need)
+                .unwrap()
+            {
+                Some(next) => msg = json!(["NEG-MSG", "sync", hex(&next)]),
+                None => break,
+            }
+        }
+        let ids = |ids: Vec<[u8; 32]>| ids.iter().map(|id| hex(id)).collect();
+        (ids(have), ids(need), rounds)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn negentropy_sync() {
+        use crate::negentropy::{Item, Negentropy};
+        use crate::utils::testing::Backend;
+        use tokio_tungstenite::tungstenite::Message as WsMsg;
+        let (testing, cx) =
+            crate::utils::testing::cx_fn_with_config(common::function_full!(), |config| {
+                // small enough for the answers to be spread across rounds
+                config.limits.max_message_length = 2048;
+                config.limits.max_limit = 1000;
+            })
+            .await;
+        let (peer_testing, peer) = crate::utils::testing::cx_fn_with_backend(
+            common::function_full!(),
+            Backend::Mem,
+            |config| {
+                config.limits.max_message_length = 2048;
+                config.limits.max_limit = 1000;
+            },
+        )
+        .await;
+        {
+            let mut only_relay = std::collections::HashSet::new();
+            let mut only_peer = std::collections::HashSet::new();
+            for ii in 0..100 {
+                let event = signed_event(&format!("sync {ii}"));
+                if ii % 3 != 1 {
+                    cx.store.insert(&event).await.unwrap();
+                }
+                if ii % 3 != 0 {
+                    peer.store.insert(&event).await.unwrap();
+                }
+                match ii % 3 {
+                    0 => only_relay.insert(event.id),
+                    1 => only_peer.insert(event.id),
+                    _ => false,
+                };
+            }
+
+            let server_handle = serve(&cx, "127.0.0.1:19007");
+            let peer_handle = serve(&peer, "127.0.0.1:19012");
+            let mut ws = connect("ws://127.0.0.1:19007").await;
+            let mut peer_ws = connect("ws://127.0.0.1:19012").await;
+
+            // the peer's events reconciled against the relay
+            let items = items_of(&mut peer_ws).await;
+            let (have, need, rounds) = reconcile(&mut ws, items).await;
+            assert!(rounds > 2, "answers weren't spread across rounds");
+            assert_eq!(have, only_peer);
+            assert_eq!(need, only_relay);
+            // and the other way around
+            let items = items_of(&mut ws).await;
+            let (have, need, _) = reconcile(&mut peer_ws, items).await;
+            assert_eq!(have, only_relay);
+            assert_eq!(need, only_peer);
+
+            let hex = |buf: &[u8]| data_encoding::HEXLOWER.encode(buf);
+            ws.send(WsMsg::Text(json!(["NEG-CLOSE", "sync"]).to_string()))
+                .await
+                .unwrap();
+            let closed = Item {
+                timestamp: 0,
+                id: [0; 32],
+            };
+            ws.send(WsMsg::Text(
+                json!([
+                    "NEG-MSG",
+                    "sync",
+                    hex(&Negentropy::new(vec![closed], 0).initiate())
+                ])
+                .to_string(),
+            ))
+            .await
+            .unwrap();
+            assert_eq!(next_json(&mut ws).await[0], "NEG-ERR");
+
+            peer_handle.abort();
+            server_handle.abort();
+        }
+        peer_testing.close().await;
+        testing.close().await;
     }
 }
@@ -0,0 +1,266 @@
+//! Inverted index over the filters of open subscriptions so that only the
+//! ones an event could match get evaluated.
+
+use crate::interlude::*;
+
+use std::collections::{HashMap, HashSet};
+
+use crate::event::{Event, Filter};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Id(String),
+    Author(String),
+    Kind(u16),
+    Tag(char, String),
+}
+
+/// A filter by the client, subscription and its position in the REQ.
+type FilterKey = (Uuid, CHeapStr, usize);
+
+#[derive(Debug, Default)]
+pub struct SubIndex {
+    subs: HashMap<Uuid, HashMap<CHeapStr, Vec<Filter>>>,
+    postings: HashMap<Key, HashSet<FilterKey>>,
+    /// Filters that don't constrain any of the indexed fields.
+    unindexed: HashSet<FilterKey>,
+}
+
+/// Keys to file the filter under, picking the most selective field. An
+/// event can only match the filter if it has one of them. `None` if none
+/// of the indexed fields are constrained.
+fn filter_keys(filter: &Filter) -> Option<Vec<Key>> {
+    if let Some(ids) = &filter.ids {
+        return Some(
+            ids.iter()
+                .map(|id| Key::Id(id.to_ascii_lowercase()))
+                .collect(),
+        );
+    }
+    if let Some(authors) = &filter.authors {
+        return Some(
+            authors
+                .iter()
+                .map(|pubkey| Key::Author(pubkey.to_ascii_lowercase()))
+                .collect(),
+        );
+    }
+    // every tag key must be matched so any one will do
+    if let Some((char, values)) = filter
+        .tags
+        .as_ref()
+        .and_then(|tags| tags.iter().min_by_key(|(char, _)| **char))
+    {
+        return Some(
+            values
+                .iter()
+                .map(|value| Key::Tag(*char, value.clone()))
+                .collect(),
+        );
+    }
+    filter
+        .kinds
+        .as_ref()
+        .map(|kinds| kinds.iter().map(|kind| Key::Kind(*kind)).collect())
+}
+
+fn event_keys(event: &Event) -> impl Iterator<Item = Key> + '_ {
+    [
+        Key::Id(event.id.to_ascii_lowercase()),
+        Key::Author(event.pubkey.to_ascii_lowercase()),
+        Key::Kind(event.kind),
+    ]
+    .into_iter()
+    .chain(event.tags.iter().filter_map(|tag| {
+        let mut chars = tag.get(0)?.chars();
+        let char = chars.next()?;
+        if chars.next().is_some() {
+            return None;
+        }
+        Some(Key::Tag(char, tag.get(1)?.clone()))
+    }))
+}
+
+impl SubIndex {
+    /// Adds the subscription, replacing any under the same id.
+    pub fn insert(&mut self, client_id: Uuid, sub_id: CHeapStr, filters: Vec<Filter>) {
+        self.remove(&client_id, &sub_id);
+        for (idx, filter) in filters.iter().enumerate() {
+            let filter_key = (client_id, sub_id.clone(), idx);
+            match filter_keys(filter) {
+                Some(keys) => {
+                    for key in keys {
+                        self.postings
+                            .entry(key)
+                            .or_default()
+                            .insert(filter_key.clone());
+                    }
+                }
+                None => {
+                    self.unindexed.insert(filter_key);
+                }
+            }
+        }
+        self.subs
+            .entry(client_id)
+            .or_default()
+            .insert(sub_id, filters);
+    }
+
+    pub fn contains(&self, client_id: &Uuid, sub_id: &CHeapStr) -> bool {
+        self.subs
+            .get(client_id)
+            .map(|subs| subs.contains_key(sub_id))
+            .unwrap_or_default()
+    }
+
+    /// Number of subscriptions the client has open.
+    pub fn sub_count(&self, client_id: &Uuid) -> usize {
+        self.subs
+            .get(client_id)
+            .map(|subs| subs.len())
+            .unwrap_or_default()
+    }
+
+    /// Returns false if there was no such subscription.
+    pub fn remove(&mut self, client_id: &Uuid, sub_id: &CHeapStr) -> bool {
+        let Some(subs) = self.subs.get_mut(client_id) else {
+            return false;
+        };
+        let Some(filters) = subs.remove(sub_id) else {
+            return false;
+        };
+        if subs.is_empty() {
+            self.subs.remove(client_id);
+        }
+        for (idx, filter) in filters.iter().enumerate() {
+            let filter_key = (*client_id, sub_id.clone(), idx);
+            match filter_keys(filter) {
+                Some(keys) => {
+                    for key in keys {
+                        if let Some(set) = self.postings.get_mut(&key) {
+                            set.remove(&filter_key);
+                            if set.is_empty() {
+                                self.postings.remove(&key);
+                            }
+                        }
+                    }
+                }
+                None => {
+                    self.unindexed.remove(&filter_key);
+                }
+            }
+        }
+        true
+    }
+
+    /// Removes all the subscriptions of the client, returning how many.
+    pub fn remove_client(&mut self, client_id: &Uuid) -> usize {
+        let sub_ids = self
+            .subs
+            .get(client_id)
+            .map(|subs| subs.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        for sub_id in &sub_ids {
+            self.remove(client_id, sub_id);
+        }
+        sub_ids.len()
+    }
+
+    /// Subscriptions with at least one filter that matches the event.
+    pub fn matching(&self, event: &Event) -> Vec<(Uuid, CHeapStr)> {
+        let mut out: Vec<(Uuid, CHeapStr)> = vec![];
+        let mut seen = HashSet::new();
+        let candidates = event_keys(event)
+            .filter_map(|key| self.postings.get(&key))
+            .flatten()
+            .chain(self.unindexed.iter());
+        for (client_id, sub_id, idx) in candidates {
+            if seen.contains(&(client_id, sub_id)) {
+                continue;
+            }
+            let filter = &self.subs[client_id][sub_id][*idx];
+            if filter.matches(event) {
+                seen.insert((client_id, sub_id));
+                out.push((*client_id, sub_id.clone()));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use super::SubIndex;
+    use crate::event::testing::*;
+    use crate::event::Filter;
+
+    fn filters(json: serde_json::Value) -> Vec<Filter> {
+        serde_json::from_value(json).unwrap()
+    }
+
+    common::table_tests! {
+        matching,
+        (filters_json, expected),
+        {
+            let client_id = Uuid::new_v4();
+            let mut index = SubIndex::default();
+            index.insert(client_id, "sub".into(), filters(filters_json.clone()));
+            // noise
+            index.insert(client_id, "other".into(), filters(json!([{ "ids": [EVENT_01.id] }])));
+            let event = &*EVENT_04;
+            let mut expected_subs = vec![];
+            if expected {
+                expected_subs.push((client_id, CHeapStr::from("sub")));
+            }
+            assert_eq!(index.matching(event), expected_subs);
+            // agrees with a scan
+            assert_eq!(
+                filters(filters_json).iter().any(|filter| filter.matches(event)),
+                expected
+            );
+        }
+    }
+
+    matching! {
+        by_id: (json!([{ "ids": [EVENT_04.id.to_uppercase()] }]), true),
+        by_author: (json!([{ "authors": [EVENT_04.pubkey] }]), true),
+        by_kind: (json!([{ "kinds": [1] }]), true),
+        by_tag: (json!([{ "#e": [EVENT_03.id] }]), true),
+        by_other_tag: (json!([{ "#e": [EVENT_03.id], "#p": [EVENT_03.id] }]), false),
+        unindexed: (json!([{ "since": EVENT_04.created_at.unix_timestamp() }]), true),
+        empty_filter: (json!([{}]), true),
+        indexed_but_not_matching: (json!([{ "authors": [EVENT_04.pubkey], "kinds": [0] }]), false),
+        any_filter: (json!([{ "kinds": [0] }, { "#e": [EVENT_03.id] }]), true),
+        no_match: (json!([{ "ids": [EVENT_01.id] }, { "kinds": [0] }]), false),
+    }
+
+    #[test]
+    fn removal() {
+        let (client_a, client_b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut index = SubIndex::default();
+        index.insert(client_a, "one".into(), filters(json!([{ "kinds": [1] }])));
+        index.insert(client_a, "two".into(), filters(json!([{}])));
+        index.insert(client_b, "one".into(), filters(json!([{ "kinds": [1] }])));
+        assert_eq!(index.matching(&EVENT_04).len(), 3);
+
+        // replacing
+        index.insert(client_a, "one".into(), filters(json!([{ "kinds": [0] }])));
+        assert_eq!(index.matching(&EVENT_04).len(), 2);
+
+        assert!(index.remove(&client_a, &"two".into()));
+        assert!(!index.remove(&client_a, &"two".into()));
+        assert_eq!(
+            index.matching(&EVENT_04),
+            vec![(client_b, CHeapStr::from("one"))]
+        );
+
+        assert_eq!(index.remove_client(&client_a), 1);
+        assert_eq!(index.remove_client(&client_b), 1);
+        assert!(index.subs.is_empty());
+        assert!(index.postings.is_empty());
+        assert!(index.unindexed.is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+//! Bounded per-client queues of outgoing messages. Pushing never waits on
+//! the client so a slow reader can't hold up anyone else.
+
+use crate::interlude::*;
+
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// What to do when a client's outbox is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "serde", rename_all = "camelCase")]
+pub enum SlowConsumerPolicy {
+    /// Make room by discarding the oldest queued subscription EVENT.
+    /// Replies to the client's own messages are never dropped, the client
+    /// getting cut off if nothing else is left to drop.
+    #[default]
+    DropOldest,
+    /// Cut the client off with a NOTICE.
+    Disconnect,
+}
+
+#[derive(Debug)]
+pub struct Outbox {
+    queue: std::sync::Mutex<VecDeque<Value>>,
+    notify: tokio::sync::Notify,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    dropped: AtomicU64,
+    cut_off: AtomicBool,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: SlowConsumerPolicy) -> Self {
+        Self {
+            queue: default(),
+            notify: default(),
+            capacity: capacity.max(1),
+            policy,
+            dropped: default(),
+            cut_off: default(),
+        }
+    }
+
+    /// Queues the message. Returns the number of messages dropped to make
+    /// room for it. Messages pushed after the client's been cut off are
+    /// dropped.
+    pub fn push(&self, msg: Value) -> u64 {
+        if self.is_cut_off() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return 1;
+        }
+        let dropped = {
+            let mut queue = self.queue.lock().unwrap_or_log();
+            if queue.len() < self.capacity {
+                queue.push_back(msg);
+                0
+            } else {
+                match self.policy {
+                    SlowConsumerPolicy::DropOldest => match queue.iter().position(is_event) {
+                        Some(idx) => {
+                            queue.remove(idx);
+                            queue.push_back(msg);
+                            1
+                        }
+                        None if is_event(&msg) => 1,
+                        None => self.clear_and_cut_off(&mut queue),
+                    },
+                    SlowConsumerPolicy::Disconnect => self.clear_and_cut_off(&mut queue),
+                }
+            }
+        };
+        self.dropped.fetch_add(dropped, Ordering::Relaxed);
+        if self.is_cut_off() {
+            self.notify.notify_waiters();
+        }
+        self.notify.notify_one();
+        dropped
+    }
+
+    /// Clears the queue and marks the client as cut off, returning the
+    /// number of messages dropped including the one being pushed.
+    fn clear_and_cut_off(&self, queue: &mut VecDeque<Value>) -> u64 {
+        let dropped = queue.len() as u64 + 1;
+        queue.clear();
+        self.cut_off.store(true, Ordering::Relaxed);
+        dropped
+    }
+
+    /// Waits for the next message. `None` once the client's been cut off.
+    pub async fn pop(&self) -> Option<Value> {
+        loop {
+            if self.is_cut_off() {
+                return None;
+            }
+            if let Some(msg) = self.queue.lock().unwrap_or_log().pop_front() {
+                return Some(msg);
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Resolves once the client's been cut off for falling behind.
+    pub async fn cut_off(&self) {
+        while !self.is_cut_off() {
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn is_cut_off(&self) -> bool {
+        self.cut_off.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap_or_log().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Whether the message carries an event for a subscription, as opposed to a
+/// reply to one of the client's messages.
+fn is_event(msg: &Value) -> bool {
+    msg.get(0).and_then(Value::as_str) == Some("EVENT")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let outbox = Outbox::new(2, SlowConsumerPolicy::DropOldest);
+        assert_eq!(outbox.push(json!(["EVENT", "sub", 1])), 0);
+        assert_eq!(outbox.push(json!(["EVENT", "sub", 2])), 0);
+        assert_eq!(outbox.push(json!(["EVENT", "sub", 3])), 1);
+        assert_eq!(outbox.dropped(), 1);
+        assert_eq!(outbox.pop().await, Some(json!(["EVENT", "sub", 2])));
+        assert_eq!(outbox.pop().await, Some(json!(["EVENT", "sub", 3])));
+        assert!(outbox.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_replies() {
+        let outbox = Outbox::new(3, SlowConsumerPolicy::DropOldest);
+        outbox.push(json!(["EOSE", "sub"]));
+        for ii in 0..2 {
+            outbox.push(json!(["EVENT", "sub", ii]));
+        }
+        assert_eq!(outbox.push(json!(["OK", "id", true])), 1);
+        assert_eq!(outbox.push(json!(["COUNT", "cnt", { "count": 1 }])), 1);
+        // nothing left to make room with but the events coming in
+        assert_eq!(outbox.push(json!(["EVENT", "sub", 2])), 1);
+        assert_eq!(outbox.dropped(), 3);
+        assert_eq!(outbox.pop().await, Some(json!(["EOSE", "sub"])));
+        assert_eq!(outbox.pop().await, Some(json!(["OK", "id", true])));
+        assert_eq!(
+            outbox.pop().await,
+            Some(json!(["COUNT", "cnt", { "count": 1 }]))
+        );
+
+        // cut off rather than losing a reply
+        for ii in 0..3 {
+            outbox.push(json!(["OK", format!("{ii}"), true]));
+        }
+        assert!(!outbox.is_cut_off());
+        assert_eq!(outbox.push(json!(["CLOSED", "sub", "error: closed"])), 4);
+        assert!(outbox.is_cut_off());
+    }
+
+    #[tokio::test]
+    async fn disconnect() {
+        let outbox = Outbox::new(2, SlowConsumerPolicy::Disconnect);
+        assert_eq!(outbox.push(json!(1)), 0);
+        assert_eq!(outbox.push(json!(2)), 0);
+        assert!(!outbox.is_cut_off());
+        assert_eq!(outbox.push(json!(3)), 3);
+        assert!(outbox.is_cut_off());
+        assert_eq!(outbox.pop().await, None);
+        tokio::time::timeout(std::time::Duration::from_millis(100), outbox.cut_off())
+            .await
+            .unwrap();
+        assert_eq!(outbox.push(json!(4)), 1);
+        assert_eq!(outbox.dropped(), 4);
+    }
+
+    #[tokio::test]
+    async fn pop_waits_for_push() {
+        let outbox = std::sync::Arc::new(Outbox::new(2, SlowConsumerPolicy::DropOldest));
+        let popper = tokio::spawn({
+            let outbox = outbox.clone();
+            async move { outbox.pop().await }
+        });
+        tokio::task::yield_now().await;
+        outbox.push(json!("hi"));
+        assert_eq!(popper.await.unwrap(), Some(json!("hi")));
+    }
+}
@@ -5,18 +5,23 @@ use std::collections::HashMap;
 use deps::redis::FromRedisValue;
 use serde_json::Value;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 #[serde(crate = "serde")]
 pub struct Event {
+    /// Hex encoded sha256 of the serialized event.
     pub id: String,
+    /// Hex encoded x-only public key of the author.
     pub pubkey: String,
+    /// Unix timestamp in seconds.
     #[serde(with = "time::serde::timestamp")]
+    #[schema(value_type = i64)]
     pub created_at: OffsetDateTime,
     // #[sqlx(try_from = "i64")]
     pub kind: u16,
     // #[sqlx(try_from = "serde_json::Value")]
     pub tags: Vec<Vec<String>>,
     pub content: String,
+    /// Hex encoded schnorr signature of the `id`.
     pub sig: String,
 }
 
@@ -128,6 +133,50 @@ impl FromRedisValue for Event {
     }
 }
 
+impl Event {
+    /// The value of the first `d` tag, as used to parameterize replaceable
+    /// events. Missing values and missing `d` tags are treated as empty strings.
+    pub fn d_tag(&self) -> &str {
+        self.tags
+            .iter()
+            .find(|tag| matches!(tag.get(0).map(|st| &st[..]), Some("d")))
+            .and_then(|tag| tag.get(1))
+            .map(|st| &st[..])
+            .unwrap_or("")
+    }
+
+    /// NIP-40: the timestamp from the first `expiration` tag. Unparsable
+    /// values are ignored.
+    pub fn expires_at(&self) -> Option<OffsetDateTime> {
+        self.tags
+            .iter()
+            .find(|tag| matches!(tag.get(0).map(|st| &st[..]), Some("expiration")))
+            .and_then(|tag| tag.get(1))
+            .and_then(|ts| ts.parse().ok())
+            .and_then(|ts| OffsetDateTime::from_unix_timestamp(ts).ok())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expires_at(), Some(ts) if ts <= OffsetDateTime::now_utc())
+    }
+}
+
+/// NIP-01 ephemeral kinds: these are never persisted.
+pub fn is_ephemeral(kind: u16) -> bool {
+    (20000..30000).contains(&kind)
+}
+
+/// NIP-01 replaceable kinds: only the latest per `(pubkey, kind)` is kept.
+pub fn is_replaceable(kind: u16) -> bool {
+    kind == 0 || kind == 3 || (10000..20000).contains(&kind)
+}
+
+/// NIP-01 parameterized replaceable kinds: only the latest per
+/// `(pubkey, kind, d)` is kept.
+pub fn is_parameterized_replaceable(kind: u16) -> bool {
+    (30000..40000).contains(&kind)
+}
+
 pub fn id_for_event(
     pubkey: &str,
     created_at: OffsetDateTime,
@@ -188,6 +237,8 @@ pub struct Filter {
     pub until: Option<OffsetDateTime>,
     pub limit: Option<usize>,
     pub tags: Option<HashMap<char, Vec<String>>>,
+    /// NIP-50 search query.
+    pub search: Option<String>,
 }
 
 impl Filter {
@@ -198,43 +249,59 @@ impl Filter {
             && self.since.is_none()
             && self.until.is_none()
             && self.tags.is_none()
+            && self.search.is_none()
+    }
+
+    /// The NIP-50 search query without any `key:value` extensions, which
+    /// aren't supported. `None` if nothing's left.
+    pub fn search_query(&self) -> Option<String> {
+        let query = self
+            .search
+            .as_deref()?
+            .split_whitespace()
+            .filter(|term| !is_search_extension(term))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if query.is_empty() {
+            None
+        } else {
+            Some(query)
+        }
     }
-    // TODO: test suite for this
+
+    /// Whether the event would be returned by the stored events query for
+    /// the filter, `limit` aside. The two must agree.
     pub fn matches(&self, event: &Event) -> bool {
+        if event.is_expired() {
+            return false;
+        }
         [
-            self.ids.as_deref().map(|list| list.contains(&event.id)),
-            self.kinds.as_deref().map(|list| list.contains(&event.kind)),
-            self.since.map(|ts| ts < event.created_at),
-            self.until.map(|ts| ts > event.created_at),
-            self.authors
+            // hex values are decoded before being compared in the db
+            self.ids
                 .as_deref()
-                .map(|list| list.contains(&event.pubkey)),
+                .map(|list| list.iter().any(|id| id.eq_ignore_ascii_case(&event.id))),
+            self.kinds.as_deref().map(|list| list.contains(&event.kind)),
+            // both bounds are inclusive
+            self.since.map(|ts| ts <= event.created_at),
+            self.until.map(|ts| ts >= event.created_at),
+            self.authors.as_deref().map(|list| {
+                list.iter()
+                    .any(|pubkey| pubkey.eq_ignore_ascii_case(&event.pubkey))
+            }),
+            // every tag key in the filter must be matched by at least one tag
             self.tags.as_ref().map(|map| {
-                let mut match_ctr = 0;
-                for tag in &event.tags {
-                    // we're only interested in [key, value, ...] tags
-                    if tag.len() < 2 {
-                        continue;
-                    }
-                    let mut chars = tag[0][1..].chars();
-                    // the key is an empty string
-                    let Some(char) = chars.next() else {
-                        continue;
-                    };
-                    // the key has more than one char, not eligible
-                    if chars.next().is_some() {
-                        continue;
-                    }
-                    // filter is not interested in this tag
-                    let Some(list) = map.get(&char) else {
-                        continue;
-                    };
-                    if list.contains(&tag[1]) {
-                        match_ctr += 1;
-                    }
-                }
-                match_ctr == map.len()
+                map.iter().all(|(char, list)| {
+                    event.tags.iter().any(|tag| {
+                        // we're only interested in [key, value, ...] tags
+                        // with single char keys
+                        tag.len() >= 2
+                            && tag[0].chars().eq(std::iter::once(*char))
+                            && list.contains(&tag[1])
+                    })
+                })
             }),
+            self.search_query()
+                .map(|query| search_matches(&query, &event.content)),
         ]
         .into_iter()
         .flatten()
@@ -242,6 +309,72 @@ impl Filter {
     }
 }
 
+/// NIP-50 extensions look like `key:value`, e.g. `language:en`.
+fn is_search_extension(term: &str) -> bool {
+    match term.split_once(':') {
+        Some((key, _)) => {
+            !key.is_empty()
+                && key
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+        }
+        None => false,
+    }
+}
+
+fn search_words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|ch: char| !ch.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+/// Approximates `websearch_to_tsquery` for live events: whitespace separated terms
+/// are AND'd, `or` makes alternatives of its neighbours and `-` negates a term.
+fn search_matches(query: &str, content: &str) -> bool {
+    let content = search_words(content).collect::<std::collections::HashSet<_>>();
+    // each clause is satisfied by any of its alternatives
+    let mut clauses: Vec<Vec<bool>> = vec![];
+    let mut or_pending = false;
+    for term in query.split_whitespace() {
+        if term.eq_ignore_ascii_case("or") {
+            or_pending = !clauses.is_empty();
+            continue;
+        }
+        let (negated, term) = match term.strip_prefix('-') {
+            Some(term) => (true, term),
+            None => (false, term),
+        };
+        let mut words = search_words(term).peekable();
+        // terms made up of only punctuation are ignored
+        if words.peek().is_none() {
+            continue;
+        }
+        let found = words.all(|word| content.contains(&word));
+        let satisfied = found != negated;
+        match clauses.last_mut() {
+            Some(alternatives) if or_pending => alternatives.push(satisfied),
+            _ => clauses.push(vec![satisfied]),
+        }
+        or_pending = false;
+    }
+    clauses
+        .iter()
+        .all(|alternatives| alternatives.iter().any(|val| *val))
+}
+
+/// Approximates `ts_rank` for stores without full text search: the number of
+/// times the query's non-negated words occur in the content.
+pub(crate) fn search_rank(query: &str, content: &str) -> f32 {
+    let words = query
+        .split_whitespace()
+        .filter(|term| !term.starts_with('-') && !term.eq_ignore_ascii_case("or"))
+        .flat_map(search_words)
+        .collect::<std::collections::HashSet<_>>();
+    search_words(content)
+        .filter(|word| words.contains(word))
+        .count() as f32
+}
+
 impl<'de> Deserialize<'de> for Filter {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -259,6 +392,7 @@ impl<'de> Deserialize<'de> for Filter {
             // #[serde(with = "time::serde::timestamp::option")]
             pub until: Option<i64>,
             pub limit: Option<usize>,
+            pub search: Option<String>,
         }
         // but use go through a json object step first to find all the tag filters
         let json: serde_json::Map<String, Value> = Deserialize::deserialize(deserializer)?;
@@ -316,17 +450,157 @@ impl<'de> Deserialize<'de> for Filter {
             limit: inner.limit,
             authors: inner.authors,
             tags: if !tags.is_empty() { Some(tags) } else { None },
+            search: inner.search,
         })
     }
 }
 
+impl Serialize for Filter {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(None)?;
+        if let Some(ids) = &self.ids {
+            map.serialize_entry("ids", ids)?;
+        }
+        if let Some(authors) = &self.authors {
+            map.serialize_entry("authors", authors)?;
+        }
+        if let Some(kinds) = &self.kinds {
+            map.serialize_entry("kinds", kinds)?;
+        }
+        if let Some(since) = self.since {
+            map.serialize_entry("since", &since.unix_timestamp())?;
+        }
+        if let Some(until) = self.until {
+            map.serialize_entry("until", &until.unix_timestamp())?;
+        }
+        if let Some(limit) = self.limit {
+            map.serialize_entry("limit", &limit)?;
+        }
+        for (tag, values) in self.tags.iter().flatten() {
+            map.serialize_entry(&format!("#{tag}"), values)?;
+        }
+        if let Some(search) = &self.search {
+            map.serialize_entry("search", search)?;
+        }
+        map.end()
+    }
+}
+
+impl<'s> ToSchema<'s> for Filter {
+    fn schema() -> (
+        &'s str,
+        utoipa::openapi::RefOr<utoipa::openapi::schema::Schema>,
+    ) {
+        use utoipa::openapi::*;
+        let strings = || ArrayBuilder::new().items(Object::with_type(SchemaType::String));
+        let timestamp = || ObjectBuilder::new().schema_type(SchemaType::Integer);
+        (
+            "Filter",
+            ObjectBuilder::new()
+                .description(Some(
+                    "Matches events that pass all the given conditions. \
+                    Tag conditions are keyed by the tag name prefixed with `#`, \
+                    only single letter tags being indexed.",
+                ))
+                .property("ids", strings())
+                .property("authors", strings())
+                .property(
+                    "kinds",
+                    ArrayBuilder::new().items(Object::with_type(SchemaType::Integer)),
+                )
+                .property(
+                    "since",
+                    timestamp().description(Some("Unix timestamp, inclusive.")),
+                )
+                .property(
+                    "until",
+                    timestamp().description(Some("Unix timestamp, inclusive.")),
+                )
+                .property(
+                    "limit",
+                    ObjectBuilder::new()
+                        .schema_type(SchemaType::Integer)
+                        .minimum(Some(0.))
+                        .description(Some("Most recent events to return initially.")),
+                )
+                .property(
+                    "search",
+                    ObjectBuilder::new()
+                        .schema_type(SchemaType::String)
+                        .description(Some("NIP-50 full text search query.")),
+                )
+                .additional_properties(Some(Schema::Array(strings().build())))
+                .example(Some(json!({
+                    "kinds": [1],
+                    "#e": ["b042eae42505d83996af3694f47224128596c89a3ea1a7fd27ea43c8e559cf20"],
+                    "since": 1692815146,
+                    "limit": 10
+                })))
+                .into(),
+        )
+    }
+}
+
+pub mod count;
 pub mod create;
+pub mod expire;
 pub mod list;
+pub mod policy;
+pub mod pow;
 
 pub mod testing {
     use super::*;
 
     use once_cell::sync::Lazy;
+
+    // the keys used by `gen_events` to sign the fixtures
+    pub const EVENT_01_PRIVKEY: &str =
+        "767ab216ccc49825dc3fc1be67afde623e954d0161799c7d45de8a38988b7de3";
+    pub const EVENT_02_PRIVKEY: &str =
+        "f72657e01156d2c9b251111e73d58236dfb7de5ca69e1b53f0a938528f16c265";
+    pub const EVENT_03_PRIVKEY: &str =
+        "10ce5bdc0dc22f26fd7142142ba02f8686e9f428ca1b8b04966652d39053334e";
+    pub const EVENT_05_PRIVKEY: &str =
+        "07d3cbe0f94c13b75c5c99f9086f101879d769303d0db7b562248dc796297fce";
+    /// Signs the events tests make up of their own.
+    pub const TEST_PRIVKEY: &str =
+        "95dfc6261ec6c66b3ec68e1b019cf6420e1d676c29c1241ec5dea551ed89e338";
+
+    /// The hex pubkey for the hex `privkey`.
+    pub fn pubkey_of(privkey: &str) -> String {
+        let key = data_encoding::HEXLOWER.decode(privkey.as_bytes()).unwrap();
+        let key = k256::schnorr::SigningKey::from_bytes(&key[..]).unwrap();
+        data_encoding::HEXLOWER.encode(&key.verifying_key().to_bytes()[..])
+    }
+
+    /// An event signed with `privkey`, `created_at` truncated to the second.
+    pub fn sign(
+        privkey: &str,
+        created_at: OffsetDateTime,
+        kind: u16,
+        tags: Vec<Vec<String>>,
+        content: &str,
+    ) -> Event {
+        let key = data_encoding::HEXLOWER.decode(privkey.as_bytes()).unwrap();
+        let key = k256::schnorr::SigningKey::from_bytes(&key[..]).unwrap();
+        let pubkey = pubkey_of(privkey);
+        let created_at = OffsetDateTime::from_unix_timestamp(created_at.unix_timestamp()).unwrap();
+        let (id, sig) = hex_id_and_sig_for_event(&key, &pubkey, created_at, kind, &tags, content);
+        Event {
+            id,
+            pubkey,
+            created_at,
+            kind,
+            tags,
+            content: content.into(),
+            sig,
+        }
+    }
+
     pub const EVENT_01_ID: &str =
         "b042eae42505d83996af3694f47224128596c89a3ea1a7fd27ea43c8e559cf20";
 
@@ -402,3 +676,62 @@ pub mod testing {
     }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use super::testing::*;
+
+    common::table_tests! {
+        search_matches,
+        (search, event, expected),
+        {
+            let filter: crate::event::Filter = serde_json::from_value(json!({ "search": search })).unwrap();
+            assert_eq!(filter.matches(&event), expected, "{search} on {:?}", event.content);
+        }
+    }
+
+    search_matches! {
+        matches_word: ("kermit", EVENT_03.clone(), true),
+        is_case_insensitive: ("KERMIT frog", EVENT_03.clone(), true),
+        requires_all_terms: ("kermit piggy", EVENT_03.clone(), false),
+        supports_or: ("piggy or kermit", EVENT_03.clone(), true),
+        supports_negation: ("kermit -frog", EVENT_03.clone(), false),
+        matches_hyphenated: ("henson-gate", EVENT_05.clone(), true),
+        ignores_extensions: ("kermit include:spam", EVENT_03.clone(), true),
+        extensions_only_matches_all: ("language:en", EVENT_01.clone(), true),
+    }
+
+    #[test]
+    fn expired_events_dont_match() {
+        let filter: crate::event::Filter = serde_json::from_value(json!({})).unwrap();
+        let expiring_in = |secs: i64| crate::event::Event {
+            tags: vec![vec![
+                "expiration".into(),
+                (OffsetDateTime::now_utc().unix_timestamp() + secs).to_string(),
+            ]],
+            ..EVENT_01.clone()
+        };
+        assert!(filter.matches(&expiring_in(60)));
+        assert!(!filter.matches(&expiring_in(-60)));
+    }
+
+    #[test]
+    fn filters_roundtrip() {
+        let json = json!({
+            "ids": ["aa"],
+            "authors": ["bb"],
+            "kinds": [1, 7],
+            "since": 1692815146,
+            "until": 1692815500,
+            "limit": 10,
+            "#e": ["cc"],
+            "search": "kermit",
+        });
+        let filter: crate::event::Filter = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(filter).unwrap(), json);
+        let empty: crate::event::Filter = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(serde_json::to_value(empty).unwrap(), json!({}));
+    }
+}
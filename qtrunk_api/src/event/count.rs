@@ -0,0 +1,102 @@
+use crate::interlude::*;
+
+#[derive(Clone, Copy, Debug)]
+pub struct CountEvents;
+
+/// `limit` is ignored when counting.
+pub type Request = super::list::Request;
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "serde")]
+pub struct Response {
+    pub count: i64,
+}
+
+pub type Error = super::list::Error;
+
+#[async_trait::async_trait]
+impl crate::Endpoint for CountEvents {
+    type Request = Request;
+    type Response = Response;
+    type Error = Error;
+    type Cx = Context;
+
+    #[tracing::instrument(skip(cx))]
+    async fn handle(
+        &self,
+        cx: &Self::Cx,
+        request: Self::Request,
+    ) -> Result<Self::Response, Self::Error> {
+        let count = cx.store.count(&request).await?;
+        Ok(Response { count })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use crate::event::testing::*;
+
+    common::table_tests! {
+        integ tokio,
+        (request_json, expected_json),
+        {
+            let (testing, cx) = crate::utils::testing::cx_fn(common::function_full!()).await;
+            {
+                let filter = serde_json::from_value(request_json).unwrap();
+                let ok = match crate::event::count::CountEvents.handle(&cx, filter).await{
+                    Ok(value) => json!(value),
+                    Err(value) => json!(value),
+                };
+                tracing::info!(?ok);
+                check_json(
+                    ("expected", &expected_json),
+                    ("response", &ok),
+                );
+            }
+            testing.close().await;
+        },
+        multi_thread: true,
+    }
+
+    integ! {
+        counts_all: (
+            json!([{}]),
+            json!({ "count": 5 })
+        ),
+        counts_replies: (
+            json!([{
+                "#e": [EVENT_03.id]
+            }]),
+            json!({ "count": 2 })
+        ),
+        counts_author: (
+            json!([{
+                "authors": [EVENT_03.pubkey]
+            }]),
+            json!({ "count": 2 })
+        ),
+        ignores_limit: (
+            json!([{
+                "kinds": [1],
+                "limit": 1
+            }]),
+            json!({ "count": 4 })
+        ),
+        combines_filters: (
+            json!([
+                { "ids": [EVENT_01.id] },
+                { "kinds": [0] },
+                { "ids": [EVENT_01.id] }
+            ]),
+            json!({ "count": 2 })
+        ),
+        rejects_invalid_filters: (
+            json!([{
+                "ids": ["not hex"]
+            }]),
+            json!({ "error": "invalidInput" })
+        ),
+    }
+}
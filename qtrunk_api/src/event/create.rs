@@ -7,7 +7,7 @@ pub struct CreateEvent;
 
 pub type Request = Event;
 
-fn validate_request(
+pub(crate) fn validate_request(
     req: &Request,
 ) -> Result<
     (
@@ -152,6 +152,31 @@ pub struct Error {
 pub enum ErrorKind {
     #[error("duplicate: event already recieved")]
     Duplicate,
+    #[error("duplicate: a newer version of the event is already stored")]
+    Stale,
+    #[error("error: event was being concurrently replaced, try again")]
+    Conflict,
+    #[error("blocked: event was deleted by its author")]
+    Deleted,
+    #[error("invalid: auth events are only accepted through AUTH messages")]
+    AuthEvent,
+    #[error("invalid: event has expired")]
+    Expired,
+    #[error("invalid: event has more than {max} tags")]
+    TooManyTags { max: usize },
+    #[error("invalid: content is longer than {max} bytes")]
+    ContentTooLong { max: usize },
+    #[error("pow: difficulty {actual} is less than {required}")]
+    Pow { required: u8, actual: u32 },
+    #[error("rate-limited: at most {count} event(s) every {window_secs}s")]
+    RateLimited { count: u32, window_secs: u64 },
+    #[error("blocked: pubkey is banned")]
+    BannedPubkey,
+    #[error("blocked: event is banned")]
+    BannedEvent,
+    /// Refused by the write policy.
+    #[error("{reason}")]
+    Rejected { reason: String },
     #[error("invalid:{issues}")]
     InvalidInput {
         #[from]
@@ -162,63 +187,150 @@ pub enum ErrorKind {
 }
 
 impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
     pub fn to_nostr_ok(self) -> serde_json::Value {
         let Self { event_id, kind } = self;
         json!(["OK", event_id, false, format!("{kind}")])
     }
 }
 
-async fn pg_insert_event(
-    request: &Request,
-    id_bytes: &[u8],
-    pubkey_bytes: &[u8],
-    sig_bytes: &[u8],
-    executor: impl sqlx::PgExecutor<'_>,
-) -> Result<(), Error> {
-    sqlx::query!(
-        r#"
-INSERT INTO public.events (
-    id
-    ,pubkey
-    ,created_at
-    ,kind
-    ,tags
-    ,content
-    ,sig
-) VALUES
-(
-    $1 
-    ,$2
-    ,$3
-    ,$4
-    ,$5
-    ,$6
-    ,$7
-)
-                        "#,
-        id_bytes,
-        pubkey_bytes,
-        request.created_at,
-        request.kind as i32,
-        &json!(request.tags),
-        request.content.as_str(),
-        sig_bytes,
-    )
-    .execute(executor)
-    .await
-    .map(|_| ())
-    .map_err(|err| {
-        if let sqlx::Error::Database(boxed) = &err {
-            if let Some("events_pkey") = boxed.constraint() {
-                return ErrorKind::Duplicate;
+impl From<crate::store::Rejection> for ErrorKind {
+    fn from(value: crate::store::Rejection) -> Self {
+        use crate::store::Rejection;
+        match value {
+            Rejection::Duplicate => Self::Duplicate,
+            Rejection::Stale => Self::Stale,
+            Rejection::Conflict => Self::Conflict,
+            Rejection::Deleted => Self::Deleted,
+        }
+    }
+}
+
+impl CreateEvent {
+    /// Like [`Endpoint::handle`] but with what's known of the publisher,
+    /// for the [`WritePolicy`](crate::event::policy::WritePolicy) to go on.
+    #[tracing::instrument(skip(self, cx), err)]
+    pub async fn handle_from(
+        &self,
+        cx: &Context,
+        request: Request,
+        origin: &crate::event::policy::Origin,
+    ) -> Result<Response, Error> {
+        // checked before the costlier signature validation
+        let limits = &cx.config.limits;
+        if request.tags.len() > limits.max_event_tags {
+            return Err(Error {
+                event_id: request.id,
+                kind: ErrorKind::TooManyTags {
+                    max: limits.max_event_tags,
+                },
+            });
+        }
+        if request.content.len() > limits.max_content_length {
+            return Err(Error {
+                event_id: request.id,
+                kind: ErrorKind::ContentTooLong {
+                    max: limits.max_content_length,
+                },
+            });
+        }
+        let (id_bytes, _, _) = validate_request(&request)
+            .map_err(ValidationErrors::from)
+            .map_err(|kind| Error {
+                event_id: request.id.clone(),
+                kind: kind.into(),
+            })?;
+        // only once the signature's checked so that forged events can't
+        // use up an author's budget
+        if origin.ip.is_some() {
+            let key = format!("pubkey:{}", request.pubkey.to_ascii_lowercase());
+            if let Some(limit) =
+                crate::rate_limit::exceeded(cx, &key, limits.pubkey_event_rate).await
+            {
+                return Err(Error {
+                    event_id: request.id,
+                    kind: ErrorKind::RateLimited {
+                        count: limit.count,
+                        window_secs: limit.window_secs(),
+                    },
+                });
+            }
+        }
+        // NIP-42: auth events must never be stored or broadcast
+        if request.kind == crate::auth::AUTH_EVENT_KIND {
+            return Err(Error {
+                event_id: request.id,
+                kind: ErrorKind::AuthEvent,
+            });
+        }
+        if request.is_expired() {
+            return Err(Error {
+                event_id: request.id,
+                kind: ErrorKind::Expired,
+            });
+        }
+        // NIP-13
+        let required = cx.config.limits.pow_difficulty_for(request.kind);
+        if required > 0 {
+            let actual = crate::event::pow::difficulty(&request, &id_bytes[..]);
+            if actual < required as u32 {
+                return Err(Error {
+                    event_id: request.id,
+                    kind: ErrorKind::Pow { required, actual },
+                });
+            }
+        }
+        if let Some(banned) = cx.store.banned(&request).await {
+            return Err(Error {
+                event_id: request.id,
+                kind: match banned {
+                    crate::store::Banned::Pubkey => ErrorKind::BannedPubkey,
+                    crate::store::Banned::Event => ErrorKind::BannedEvent,
+                },
+            });
+        }
+        {
+            use crate::event::policy::{Verdict, WritePolicy};
+            match cx.config.write_policy.check(cx, &request, origin).await {
+                Verdict::Accept => {}
+                Verdict::Reject(reason) => {
+                    return Err(Error {
+                        event_id: request.id,
+                        kind: ErrorKind::Rejected { reason },
+                    })
+                }
+                Verdict::ShadowAccept => {
+                    debug!(%request.id, "event shadow accepted");
+                    return Ok(Response { id: request.id });
+                }
             }
         }
-        panic!("db error: {err}");
-    })
-    .map_err(|kind| Error {
-        event_id: request.id.clone(),
-        kind,
-    })
+        let stored = match request.kind {
+            // ephemeral, not persisted
+            nn if crate::event::is_ephemeral(nn) => Ok(()),
+            // deletion requests
+            5 => cx.store.delete_by_reference(&request).await,
+            // replaceable, parameterized or not
+            nn if crate::event::is_replaceable(nn)
+                || crate::event::is_parameterized_replaceable(nn) =>
+            {
+                cx.store.replace(&request).await
+            }
+            // regular
+            _ => cx.store.insert(&request).await,
+        };
+        if let Err(rejection) = stored {
+            return Err(Error {
+                event_id: request.id,
+                kind: rejection.into(),
+            });
+        }
+        cx.hose.publish(&request).await.unwrap_or_log();
+        Ok(Response { id: request.id })
+    }
 }
 
 #[async_trait::async_trait]
@@ -228,122 +340,12 @@ impl Endpoint for CreateEvent {
     type Error = Error;
     type Cx = Context;
 
-    #[tracing::instrument(skip(cx), err)]
     async fn handle(
         &self,
         cx: &Self::Cx,
         request: Self::Request,
     ) -> Result<Self::Response, Self::Error> {
-        let (id_bytes, pubkey, sig) = validate_request(&request)
-            .map_err(ValidationErrors::from)
-            .map_err(|kind| Error {
-                event_id: request.id.clone(),
-                kind: kind.into(),
-            })?;
-        match request.kind {
-            // ephemeral
-            nn if (20000..30000).contains(&nn) => { /* not persisted */ }
-            // replaceable
-            nn if nn == 0 || nn == 3 || (10000..20000).contains(&nn) => match &cx.db {
-                crate::Db::Pg { db_pool } => {
-                    let mut tx = db_pool.begin().await.unwrap_or_log();
-                    sqlx::query!(
-                        r#"
-DELETE FROM public.events WHERE kind = $1
-                        "#,
-                        request.kind as i32,
-                    )
-                    .execute(&mut *tx)
-                    .await
-                    .unwrap_or_log();
-                    pg_insert_event(
-                        &request,
-                        &id_bytes[..],
-                        &pubkey.to_bytes()[..],
-                        &sig.to_bytes()[..],
-                        &mut *tx,
-                    )
-                    .await?;
-                    tx.commit().await.unwrap_or_log();
-                }
-            },
-            // parameterized replcable
-            nn if (30000..40000).contains(&nn) => {
-                let parameter = request
-                    .tags
-                    .iter()
-                    .find(|tag| matches!(tag.get(0).map(|st| &st[..]), Some("d")))
-                    .and_then(|tag| tag.get(1))
-                    .map(|st| &st[..]);
-                match &cx.db {
-                    crate::Db::Pg { db_pool } => {
-                        let mut tx = db_pool.begin().await.unwrap_or_log();
-                        match parameter {
-                            // handle empty param
-                            None | Some("") => {
-                                sqlx::query!(
-                                    r#"
-DELETE 
-    FROM 
-        public.events 
-    WHERE 
-        kind = $1 
-        AND (
-            tags @? '$ ? (@[0] == "d" && @[1] == "")'
-            OR tags @? '$ ? (@[0] == "d" && @.size() == 1)'
-            OR NOT tags @? '$ ? (@[0] == "d")'
-        )
-                                "#,
-                                    request.kind as i32,
-                                )
-                                .execute(&mut *tx)
-                                .await
-                                .map(|ok| info!(?ok, "empty param delete ok"))
-                                .unwrap_or_log();
-                            }
-                            Some(param) => {
-                                sqlx::query!(
-                                    r#"
-DELETE FROM public.events WHERE kind = $1 AND tags @> $2
-                                "#,
-                                    request.kind as i32,
-                                    json!([["d", param]])
-                                )
-                                .execute(&mut *tx)
-                                .await
-                                .unwrap_or_log();
-                            }
-                        }
-                        pg_insert_event(
-                            &request,
-                            &id_bytes[..],
-                            &pubkey.to_bytes()[..],
-                            &sig.to_bytes()[..],
-                            &mut *tx,
-                        )
-                        .await?;
-                        tx.commit().await.unwrap_or_log();
-                    }
-                }
-            }
-            // regular
-            _ => match &cx.db {
-                crate::Db::Pg { db_pool } => {
-                    pg_insert_event(
-                        &request,
-                        &id_bytes[..],
-                        &pubkey.to_bytes()[..],
-                        &sig.to_bytes()[..],
-                        db_pool,
-                    )
-                    .await?;
-                }
-            },
-        }
-        crate::connect::pub_event(cx, &request)
-            .await
-            .unwrap_or_log();
-        Ok(Response { id: request.id })
+        self.handle_from(cx, request, &default()).await
     }
 }
 
@@ -356,8 +358,6 @@ mod tests {
 
     use crate::event::testing::*;
 
-    const TEST_PRIVKEY: &str = "95dfc6261ec6c66b3ec68e1b019cf6420e1d676c29c1241ec5dea551ed89e338";
-
     fn fixture_request() -> Request {
         serde_json::from_value(fixture_request_json()).unwrap()
     }
@@ -516,26 +516,46 @@ mod tests {
         integ tokio,
         (request_json, expected_json, extra_ass),
         {
-            let (mut testing, cx) = crate::utils::testing::cx_fn(common::function_full!()).await;
-            {
-                let event = serde_json::from_value(request_json).unwrap();
-                let ok = match crate::event::create::CreateEvent.handle(&cx, event).await{
+            // set up ahead so that the contexts outlive the borrows of `extra_ass`
+            let mut runs = vec![];
+            for backend in crate::utils::testing::BACKENDS {
+                let (testing, cx) = crate::utils::testing::cx_fn_with_backend(
+                    common::function_full!(),
+                    backend,
+                    |_| {},
+                )
+                .await;
+                runs.push((backend, testing, cx));
+            }
+            for (backend, _, cx) in &runs {
+                let event = serde_json::from_value(request_json.clone()).unwrap();
+                let ok = match crate::event::create::CreateEvent.handle(cx, event).await{
                     Ok(value) => value.to_nostr_ok(),
                     Err(value) => value.to_nostr_ok(),
                 };
-                tracing::info!(?ok);
+                tracing::info!(?backend, ?ok);
                 check_json(
                     ("expected", &expected_json),
                     ("response", &ok),
                 );
-                extra_ass(&cx).await;
+                extra_ass(cx).await;
+            }
+            for (_, testing, _) in runs {
+                testing.close().await;
             }
-            testing.close().await;
         },
         multi_thread: true,
     }
 
+    /// Publishes a newer version of the event and checks that it's the one kept.
     async fn test_replacement(cx: &Context, event: Event) {
+        let event = fix_id_and_sig(
+            Event {
+                created_at: event.created_at + time::Duration::seconds(1),
+                ..event
+            },
+            TEST_PRIVKEY,
+        );
         let ok = crate::event::create::CreateEvent
             .handle(cx, event.clone())
             .await
@@ -558,6 +578,57 @@ mod tests {
         );
     }
 
+    fn deletion_request(privkey: &str, created_at_offset: i64, tags: Vec<Vec<String>>) -> Event {
+        sign(
+            privkey,
+            fixture_request().created_at + time::Duration::seconds(created_at_offset),
+            5,
+            tags,
+            "posted by mistake",
+        )
+    }
+
+    fn addressable_request(created_at_offset: i64) -> Event {
+        sign(
+            TEST_PRIVKEY,
+            fixture_request().created_at + time::Duration::seconds(created_at_offset),
+            30315,
+            vec![vec!["d".into(), "general".into()]],
+            &format!("silly goosing at {created_at_offset}"),
+        )
+    }
+
+    fn replaceable_request(
+        privkey: &str,
+        kind: u16,
+        created_at_offset: i64,
+        content: &str,
+    ) -> Event {
+        let tags = if crate::event::is_parameterized_replaceable(kind) {
+            vec![vec!["d".into(), "general".into()]]
+        } else {
+            vec![]
+        };
+        sign(
+            privkey,
+            fixture_request().created_at + time::Duration::seconds(created_at_offset),
+            kind,
+            tags,
+            content,
+        )
+    }
+
+    async fn list_ids(cx: &Context, filter: serde_json::Value) -> Vec<String> {
+        let filter = serde_json::from_value(filter).unwrap();
+        crate::event::list::ListEvents
+            .handle(cx, filter)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| event.id)
+            .collect()
+    }
+
     integ! {
         works: (
             fixture_request_json(),
@@ -573,6 +644,27 @@ mod tests {
             ]),
             |_|async{},
         ),
+        // checked before the signature
+        rejects_too_many_tags: (
+            json!(Event {
+                tags: vec![vec!["t".to_string(), "spam".to_string()]; 2001],
+                ..EVENT_01.clone()
+            }),
+            serde_json::json!([
+                "OK", EVENT_01_ID, false, "invalid: event has more than 2000 tags"
+            ]),
+            |_|async{},
+        ),
+        rejects_too_long_content: (
+            json!(Event {
+                content: "a".repeat(64 * 1024 + 1),
+                ..EVENT_01.clone()
+            }),
+            serde_json::json!([
+                "OK", EVENT_01_ID, false, "invalid: content is longer than 65536 bytes"
+            ]),
+            |_|async{},
+        ),
         kind_0_is_replaceable: (
             json!(
                 fix_id_and_sig(
@@ -839,7 +931,7 @@ mod tests {
                             vec!["d".into(),"music".into()],
                             vec![
                                 "expiration".into(),
-                                (fixture_request().created_at.unix_timestamp() + (4 * 60)).to_string()
+                                (OffsetDateTime::now_utc().unix_timestamp() + (4 * 60)).to_string()
                             ]
                         ],
                         ..fixture_request()
@@ -973,5 +1065,341 @@ mod tests {
                 )).await;
             }),
         ),
+        replacement_is_per_author: (
+            json!(replaceable_request(TEST_PRIVKEY, 0, 0, "{}")),
+            serde_json::json!([
+                "OK", replaceable_request(TEST_PRIVKEY, 0, 0, "{}").id, true
+            ]),
+            |cx| Box::pin(async move {
+                assert_eq!(
+                    list_ids(cx, json!([{ "kinds": [0] }])).await,
+                    vec![
+                        EVENT_02_ID.to_string(),
+                        replaceable_request(TEST_PRIVKEY, 0, 0, "{}").id,
+                    ]
+                );
+            }),
+        ),
+        parameterized_replacement_is_per_author: (
+            json!(replaceable_request(TEST_PRIVKEY, 30315, 0, "mine")),
+            serde_json::json!([
+                "OK", replaceable_request(TEST_PRIVKEY, 30315, 0, "mine").id, true
+            ]),
+            |cx| Box::pin(async move {
+                let theirs = replaceable_request(EVENT_01_PRIVKEY, 30315, 0, "theirs");
+                crate::event::create::CreateEvent.handle(cx, theirs.clone()).await.unwrap();
+                let mut ids = list_ids(cx, json!([{ "kinds": [30315] }])).await;
+                ids.sort();
+                let mut expected = vec![
+                    replaceable_request(TEST_PRIVKEY, 30315, 0, "mine").id,
+                    theirs.id,
+                ];
+                expected.sort();
+                assert_eq!(ids, expected);
+            }),
+        ),
+        rejects_stale_replacement: (
+            json!(replaceable_request(TEST_PRIVKEY, 10000, 10, "newer")),
+            serde_json::json!([
+                "OK", replaceable_request(TEST_PRIVKEY, 10000, 10, "newer").id, true
+            ]),
+            |cx| Box::pin(async move {
+                let older = replaceable_request(TEST_PRIVKEY, 10000, 0, "older");
+                let ok = match crate::event::create::CreateEvent.handle(cx, older.clone()).await {
+                    Ok(value) => value.to_nostr_ok(),
+                    Err(value) => value.to_nostr_ok(),
+                };
+                check_json(
+                    ("expected", &json!(["OK", older.id, false])),
+                    ("response", &ok),
+                );
+                assert!(ok[3].as_str().unwrap().starts_with("duplicate:"), "{ok:?}");
+                assert_eq!(
+                    list_ids(cx, json!([{ "kinds": [10000] }])).await,
+                    vec![replaceable_request(TEST_PRIVKEY, 10000, 10, "newer").id]
+                );
+            }),
+        ),
+        replacement_ties_keep_lowest_id: (
+            json!(replaceable_request(TEST_PRIVKEY, 30315, 0, "one")),
+            serde_json::json!([
+                "OK", replaceable_request(TEST_PRIVKEY, 30315, 0, "one").id, true
+            ]),
+            |cx| Box::pin(async move {
+                let one = replaceable_request(TEST_PRIVKEY, 30315, 0, "one");
+                let two = replaceable_request(TEST_PRIVKEY, 30315, 0, "two");
+                let ok = match crate::event::create::CreateEvent.handle(cx, two.clone()).await {
+                    Ok(value) => value.to_nostr_ok(),
+                    Err(value) => value.to_nostr_ok(),
+                };
+                let lowest = if one.id < two.id { &one } else { &two };
+                check_json(
+                    ("expected", &json!(["OK", two.id, lowest.id == two.id])),
+                    ("response", &ok),
+                );
+                assert_eq!(
+                    list_ids(cx, json!([{ "kinds": [30315] }])).await,
+                    vec![lowest.id.clone()]
+                );
+            }),
+        ),
+        concurrent_replacements_keep_newest: (
+            json!(replaceable_request(TEST_PRIVKEY, 3, 0, "")),
+            serde_json::json!([
+                "OK", replaceable_request(TEST_PRIVKEY, 3, 0, "").id, true
+            ]),
+            |cx| Box::pin(async move {
+                let older = replaceable_request(TEST_PRIVKEY, 3, 1, "older");
+                let newer = replaceable_request(TEST_PRIVKEY, 3, 2, "newer");
+                let (_, res) = tokio::join!(
+                    crate::event::create::CreateEvent.handle(cx, older),
+                    crate::event::create::CreateEvent.handle(cx, newer.clone()),
+                );
+                res.unwrap();
+                assert_eq!(
+                    list_ids(cx, json!([{ "kinds": [3] }])).await,
+                    vec![newer.id]
+                );
+            }),
+        ),
+        address_deletion_goes_by_the_first_d_tag: (
+            json!(fix_id_and_sig(
+                Event {
+                    kind: 30315,
+                    tags: vec![vec!["d".into(), "a".into()], vec!["d".into(), "b".into()]],
+                    ..fixture_request()
+                },
+                TEST_PRIVKEY,
+            )),
+            serde_json::json!([
+                "OK",
+                fix_id_and_sig(
+                    Event {
+                        kind: 30315,
+                        tags: vec![vec!["d".into(), "a".into()], vec!["d".into(), "b".into()]],
+                        ..fixture_request()
+                    },
+                    TEST_PRIVKEY,
+                ).id,
+                true,
+            ]),
+            |cx| Box::pin(async move {
+                let stored = list_ids(cx, json!([{ "kinds": [30315] }])).await;
+                assert_eq!(stored.len(), 1);
+                let address = |d_tag: &str| {
+                    vec!["a".into(), format!("30315:{}:{d_tag}", fixture_request().pubkey)]
+                };
+                crate::event::create::CreateEvent
+                    .handle(cx, deletion_request(TEST_PRIVKEY, 60, vec![address("b")]))
+                    .await
+                    .unwrap();
+                assert_eq!(list_ids(cx, json!([{ "kinds": [30315] }])).await, stored);
+                crate::event::create::CreateEvent
+                    .handle(cx, deletion_request(TEST_PRIVKEY, 120, vec![address("a")]))
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    list_ids(cx, json!([{ "kinds": [30315] }])).await,
+                    Vec::<String>::new()
+                );
+            }),
+        ),
+        superseded_versions_get_archived: (
+            json!(replaceable_request(TEST_PRIVKEY, 30315, 0, "first")),
+            serde_json::json!([
+                "OK", replaceable_request(TEST_PRIVKEY, 30315, 0, "first").id, true
+            ]),
+            |cx| Box::pin(async move {
+                use crate::store::EventStore;
+                let first = replaceable_request(TEST_PRIVKEY, 30315, 0, "first");
+                let second = replaceable_request(TEST_PRIVKEY, 30315, 1, "second");
+                let third = replaceable_request(TEST_PRIVKEY, 30315, 2, "third");
+                for event in [&second, &third] {
+                    crate::event::create::CreateEvent.handle(cx, event.clone()).await.unwrap();
+                }
+                let archived_ids = |events: Vec<Event>| {
+                    events.into_iter().map(|event| event.id).collect::<Vec<_>>()
+                };
+                assert_eq!(
+                    archived_ids(cx.store.archived(&first.pubkey, 30315, "general", 10).await),
+                    vec![second.id.clone(), first.id.clone()]
+                );
+                assert_eq!(
+                    archived_ids(cx.store.archived(&first.pubkey, 30315, "other", 10).await),
+                    Vec::<String>::new()
+                );
+                assert_eq!(cx.store.prune_archive(None, Some(1)).await, 1);
+                assert_eq!(
+                    archived_ids(cx.store.archived(&first.pubkey, 30315, "general", 10).await),
+                    vec![second.id.clone()]
+                );
+                let later = OffsetDateTime::now_utc() + time::Duration::minutes(1);
+                assert_eq!(cx.store.prune_archive(Some(later), None).await, 1);
+                assert_eq!(
+                    list_ids(cx, json!([{ "kinds": [30315] }])).await,
+                    vec![third.id]
+                );
+            }),
+        ),
+        kind_5_deletes_referenced_events: (
+            json!(deletion_request(EVENT_01_PRIVKEY, 0, vec![vec!["e".into(), EVENT_01_ID.into()]])),
+            serde_json::json!([
+                "OK",
+                deletion_request(EVENT_01_PRIVKEY, 0, vec![vec!["e".into(), EVENT_01_ID.into()]]).id,
+                true,
+            ]),
+            |cx| Box::pin(async move {
+                assert_eq!(
+                    list_ids(cx, json!([{ "ids": [EVENT_01_ID] }])).await,
+                    Vec::<String>::new()
+                );
+                // the deletion request itself is kept around
+                assert_eq!(
+                    list_ids(cx, json!([{ "kinds": [5] }])).await,
+                    vec![deletion_request(EVENT_01_PRIVKEY, 0, vec![vec!["e".into(), EVENT_01_ID.into()]]).id]
+                );
+                let err = crate::event::create::CreateEvent
+                    .handle(cx, EVENT_01.clone())
+                    .await
+                    .err()
+                    .expect("re-submission of deleted event succeeded")
+                    .to_nostr_ok();
+                check_json(
+                    ("expected", &serde_json::json!([
+                        "OK", EVENT_01_ID, false, "blocked: event was deleted by its author"
+                    ])),
+                    ("response", &err),
+                );
+            }),
+        ),
+        kind_5_ignores_events_by_others: (
+            json!(deletion_request(TEST_PRIVKEY, 0, vec![vec!["e".into(), EVENT_01_ID.into()]])),
+            serde_json::json!([
+                "OK",
+                deletion_request(TEST_PRIVKEY, 0, vec![vec!["e".into(), EVENT_01_ID.into()]]).id,
+                true,
+            ]),
+            |cx| Box::pin(async move {
+                assert_eq!(
+                    list_ids(cx, json!([{ "ids": [EVENT_01_ID] }])).await,
+                    vec![EVENT_01_ID.to_string()]
+                );
+            }),
+        ),
+        kind_5_deletes_addressable_events: (
+            json!(deletion_request(
+                TEST_PRIVKEY,
+                60,
+                vec![vec!["a".into(), format!("30315:{}:general", fixture_request().pubkey)]]
+            )),
+            serde_json::json!([
+                "OK",
+                deletion_request(
+                    TEST_PRIVKEY,
+                    60,
+                    vec![vec!["a".into(), format!("30315:{}:general", fixture_request().pubkey)]]
+                ).id,
+                true,
+            ]),
+            |cx| Box::pin(async move {
+                // versions older than the deletion request are rejected
+                let err = crate::event::create::CreateEvent
+                    .handle(cx, addressable_request(0))
+                    .await
+                    .err()
+                    .expect("re-submission of deleted event succeeded")
+                    .to_nostr_ok();
+                check_json(
+                    ("expected", &serde_json::json!([
+                        "OK", addressable_request(0).id, false, "blocked: event was deleted by its author"
+                    ])),
+                    ("response", &err),
+                );
+                // newer versions are accepted
+                crate::event::create::CreateEvent
+                    .handle(cx, addressable_request(120))
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    list_ids(cx, json!([{ "kinds": [30315] }])).await,
+                    vec![addressable_request(120).id]
+                );
+                // and removed by later deletion requests
+                crate::event::create::CreateEvent
+                    .handle(cx, deletion_request(
+                        TEST_PRIVKEY,
+                        180,
+                        vec![vec!["a".into(), format!("30315:{}:general", fixture_request().pubkey)]]
+                    ))
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    list_ids(cx, json!([{ "kinds": [30315] }])).await,
+                    Vec::<String>::new()
+                );
+            }),
+        ),
+    }
+
+    #[tokio::test]
+    async fn forged_events_dont_use_up_the_pubkey_rate() {
+        let (testing, cx) =
+            crate::utils::testing::cx_fn_with_config(common::function_full!(), |config| {
+                config.limits.pubkey_event_rate = Some(crate::rate_limit::RateLimit {
+                    count: 1,
+                    window: std::time::Duration::from_secs(60 * 60),
+                });
+            })
+            .await;
+        {
+            let origin = crate::event::policy::Origin {
+                authed_pubkeys: vec![],
+                ip: Some(std::net::Ipv4Addr::LOCALHOST.into()),
+            };
+            let handle = |event: Event| {
+                let (cx, origin) = (&cx, &origin);
+                async move {
+                    match crate::event::create::CreateEvent
+                        .handle_from(cx, event, origin)
+                        .await
+                    {
+                        Ok(value) => value.to_nostr_ok(),
+                        Err(value) => value.to_nostr_ok(),
+                    }
+                }
+            };
+            let forged = Event {
+                sig: EVENT_01.sig.clone(),
+                ..fixture_request()
+            };
+            for _ in 0..2 {
+                let ok = handle(forged.clone()).await;
+                assert!(ok[3].as_str().unwrap().starts_with("invalid:"), "{ok:?}");
+            }
+            check_json(
+                ("expected", &json!(["OK", fixture_request().id, true])),
+                ("response", &handle(fixture_request()).await),
+            );
+            let newer = fix_id_and_sig(
+                Event {
+                    content: "another".into(),
+                    ..fixture_request()
+                },
+                TEST_PRIVKEY,
+            );
+            check_json(
+                (
+                    "expected",
+                    &json!([
+                        "OK",
+                        newer.id,
+                        false,
+                        "rate-limited: at most 1 event(s) every 3600s"
+                    ]),
+                ),
+                ("response", &handle(newer.clone()).await),
+            );
+        }
+        testing.close().await;
     }
 }
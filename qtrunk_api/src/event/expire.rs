@@ -0,0 +1,130 @@
+//! NIP-40: purging of expired events.
+
+use crate::interlude::*;
+
+/// Deletes stored events that have expired, returning how many.
+pub async fn reap_expired(cx: &Context) -> u64 {
+    cx.store.reap_expired().await
+}
+
+/// Deletes the archived versions of replaceable events past the retention
+/// settings, returning how many.
+pub async fn prune_archive(cx: &Context) -> u64 {
+    let config = &cx.config;
+    if config.archive_retention.is_none() && config.archive_max_versions.is_none() {
+        return 0;
+    }
+    cx.store
+        .prune_archive(
+            config
+                .archive_retention
+                .map(|retention| OffsetDateTime::now_utc() - retention),
+            config.archive_max_versions,
+        )
+        .await
+}
+
+/// Periodically purges expired events and prunes the archive. Runs
+/// alongside the switchboard.
+pub async fn start_reaper(cx: SharedContext) -> eyre::Result<()> {
+    let mut interval = tokio::time::interval(cx.config.expired_reap_interval.unsigned_abs());
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let count = reap_expired(&cx).await;
+        if count > 0 {
+            debug!(count, "reaped expired events");
+        }
+        let count = prune_archive(&cx).await;
+        if count > 0 {
+            debug!(count, "pruned archived events");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use crate::event::testing::{sign, TEST_PRIVKEY};
+    use crate::event::Event;
+
+    fn expiring_event(expires_in: i64) -> Event {
+        let created_at = OffsetDateTime::now_utc();
+        let tags = vec![vec![
+            "expiration".to_string(),
+            (created_at.unix_timestamp() + expires_in).to_string(),
+        ]];
+        sign(
+            TEST_PRIVKEY,
+            created_at,
+            1,
+            tags,
+            "this message will self destruct",
+        )
+    }
+
+    async fn count_ids(cx: &Context, id: &str) -> i64 {
+        let filter = serde_json::from_value(json!([{ "ids": [id] }])).unwrap();
+        crate::event::count::CountEvents
+            .handle(cx, filter)
+            .await
+            .unwrap()
+            .count
+    }
+
+    #[tokio::test]
+    async fn rejects_expired() {
+        let (testing, cx) = crate::utils::testing::cx_fn(common::function_full!()).await;
+        {
+            let event = expiring_event(-60);
+            let ok = match crate::event::create::CreateEvent
+                .handle(&cx, event.clone())
+                .await
+            {
+                Ok(value) => value.to_nostr_ok(),
+                Err(value) => value.to_nostr_ok(),
+            };
+            check_json(
+                (
+                    "expected",
+                    &json!(["OK", event.id, false, "invalid: event has expired"]),
+                ),
+                ("response", &ok),
+            );
+        }
+        testing.close().await;
+    }
+
+    #[tokio::test]
+    async fn expired_events_are_hidden_then_reaped() {
+        let (testing, cx) = crate::utils::testing::cx_fn(common::function_full!()).await;
+        {
+            let event = expiring_event(60);
+            crate::event::create::CreateEvent
+                .handle(&cx, event.clone())
+                .await
+                .unwrap();
+            assert_eq!(count_ids(&cx, &event.id).await, 1);
+            assert_eq!(super::reap_expired(&cx).await, 0);
+
+            // fast forward
+            sqlx::query(
+                "UPDATE events SET expires_at = NOW() - interval '1 second' WHERE expires_at IS NOT NULL",
+            )
+            .execute(&testing.pg_pools["qtrunk"].pool)
+            .await
+            .unwrap();
+            assert_eq!(count_ids(&cx, &event.id).await, 0);
+            let filter = serde_json::from_value(json!([{ "ids": [event.id] }])).unwrap();
+            let listed = crate::event::list::ListEvents
+                .handle(&cx, filter)
+                .await
+                .unwrap();
+            assert!(listed.is_empty(), "{listed:?}");
+
+            assert_eq!(super::reap_expired(&cx).await, 1);
+        }
+        testing.close().await;
+    }
+}
@@ -1,8 +1,4 @@
 use crate::interlude::*;
-// TODO
-// - The limit property of a filter is only valid for the initial query and MUST
-//   be ignored afterwards. When limit: n is present it is assumed that the events
-//   returned in the initial query will be the last n events ordered by the created_at.
 
 use super::{Event, Filter};
 
@@ -25,212 +21,6 @@ pub enum Error {
     Internal { message: String },
 }
 
-struct Args {
-    ctr: usize,
-    inner: sqlx::postgres::PgArguments,
-}
-impl Args {
-    fn add<'q, T>(&mut self, val: T) -> String
-    where
-        T: sqlx::Encode<'q, sqlx::Postgres> + sqlx::Type<sqlx::Postgres> + 'static + Send + Sync,
-    {
-        use sqlx::Arguments;
-        self.inner.add(val);
-        self.ctr += 1;
-        format!("${ctr}", ctr = self.ctr)
-    }
-}
-
-fn pg_query(request: &Request) -> Result<(String, sqlx::postgres::PgArguments), Error> {
-    let mut args = Args {
-        ctr: default(),
-        inner: default(),
-    };
-    /* let mut rng = rand::thread_rng();
-    let gen_border = || {
-        use rand::*;
-        const ABET: &[u8] = b"ABCDEFGHIJKLMNOPabcdefghijklmnop";
-        let idx: [usize; 10] = rng.gen();
-        let mut out = [0u8; 10];
-        for ii in idx {
-            out[ii] = ABET[ii % ABET.len()];
-        }
-        out
-    }; */
-
-    let mut limit = None;
-    let where_clause = request
-        .iter()
-        // look for and store the max limit among the filters
-        .inspect(|filter| match (limit, filter.limit) {
-            (None, Some(_)) => {
-                limit = filter.limit;
-            }
-            (Some(cur), Some(fl)) => limit = Some(cur.max(fl)),
-            _ => {}
-        })
-        // we want the index for error reporting purposes
-        .enumerate()
-        .map(|(idx, filter)| {
-            // map each filter option to a clause
-            [
-                filter.ids.as_deref().map(|items| {
-                    let items = items
-                        .iter()
-                        .map(|hex| {
-                            data_encoding::HEXLOWER_PERMISSIVE
-                                .decode(hex.as_bytes())
-                                .map_err(|_| hex)
-                        })
-                        .collect::<Result<Vec<_>, _>>()
-                        .map_err(|hex| {
-                            let mut issues = validator::ValidationErrors::new();
-                            issues.add(
-                                "ids",
-                                validator::ValidationError {
-                                    code: "invalid_hex".into(),
-                                    message: Some("error decoding hex values in `ids`".into()),
-                                    params: [
-                                        (std::borrow::Cow::from("value"), serde_json::json!(hex)),
-                                        (
-                                            std::borrow::Cow::from("filter_idx"),
-                                            serde_json::json!(idx),
-                                        ),
-                                    ]
-                                    .into_iter()
-                                    .collect(),
-                                },
-                            );
-                            issues
-                        })
-                        .map_err(ValidationErrors::from)?;
-                    let ph = args.add(items);
-                    Ok::<_, Error>(format!("id = ANY ({ph})"))
-                }),
-                filter.authors.as_deref().map(|items| {
-                    let items = items
-                        .iter()
-                        .map(|hex| {
-                            data_encoding::HEXLOWER_PERMISSIVE
-                                .decode(hex.as_bytes())
-                                .map_err(|_| hex)
-                        })
-                        .collect::<Result<Vec<_>, _>>()
-                        .map_err(|hex| {
-                            let mut issues = validator::ValidationErrors::new();
-                            issues.add(
-                                "authors",
-                                validator::ValidationError {
-                                    code: "invalid_hex".into(),
-                                    message: Some("error decoding hex values in `authors`".into()),
-                                    params: [
-                                        (std::borrow::Cow::from("value"), serde_json::json!(hex)),
-                                        (
-                                            std::borrow::Cow::from("filter_idx"),
-                                            serde_json::json!(idx),
-                                        ),
-                                    ]
-                                    .into_iter()
-                                    .collect(),
-                                },
-                            );
-                            issues
-                        })
-                        .map_err(ValidationErrors::from)?;
-                    let ph = args.add(items);
-                    Ok::<_, Error>(format!("pubkey = ANY ({ph})"))
-                }),
-                filter.kinds.as_deref().map(|items| {
-                    let items = items.iter().map(|kind| *kind as i64).collect::<Vec<_>>();
-                    let ph = args.add(items);
-                    Ok::<_, Error>(format!("kind = ANY ({ph})"))
-                }),
-                filter.since.map(|ts| {
-                    let ph = args.add(ts);
-                    Ok::<_, Error>(format!("created_at > ({ph})"))
-                }),
-                filter.until.map(|ts| {
-                    let ph = args.add(ts);
-                    Ok::<_, Error>(format!("created_at < ({ph})"))
-                }),
-                filter.tags.as_ref().map(|tags| {
-                    Ok::<_, Error>(
-                        tags.iter()
-                            .map(|(char, needles)| {
-                                let jsonpaths = needles
-                                    .iter()
-                                    .map(|val| {
-                                        format!(r#"$ ? (@[0] == "{char}" && @[1] == "{val}")"#)
-                                    })
-                                    .collect::<Vec<_>>();
-                                let ph = args.add(dbg!(jsonpaths));
-                                format!("tags @? ANY({ph}::jsonpath[])")
-                            })
-                            .fold("true".to_string(), |mut acc, val| {
-                                acc.push_str(" AND (");
-                                acc.push_str(&val[..]);
-                                acc.push(')');
-                                acc
-                            }),
-                    )
-                }), // TODO: tags
-            ]
-            .into_iter()
-            // remove all the None values
-            .flatten()
-            // TODO: is it possible to avoid allocation to a Vec here?
-            .collect::<Result<Vec<_>, _>>()
-            .map(|clauses| {
-                clauses
-                    .into_iter()
-                    // combine the clauses using AND
-                    .fold("true".to_string(), |mut acc, val| {
-                        acc.push_str(" AND (");
-                        acc.push_str(&val[..]);
-                        acc.push(')');
-                        acc
-                    })
-            })
-        })
-        .collect::<Result<Vec<_>, _>>()?
-        .into_iter()
-        .filter(|cl| !cl.is_empty())
-        .fold("WHERE false".to_string(), |mut acc, val| {
-            acc.push_str(" OR (");
-            acc.push_str(&val[..]);
-            acc.push(')');
-            acc
-        });
-
-    let where_clause = if where_clause == "WHERE false" {
-        String::new()
-    } else {
-        where_clause
-    };
-
-    let limit = limit.unwrap_or(100).min(100) as i64;
-    let limit = args.add(limit);
-    Ok((
-        format!(
-            r#"
-SELECT
-    encode(id, 'hex') as "id"
-    ,encode(pubkey, 'hex') as "pubkey"
-    ,created_at
-    ,kind
-    ,tags
-    ,content
-    ,encode(sig, 'hex') as "sig"
-FROM events
-{where_clause}
-ORDER BY created_at DESC, id ASC
-LIMIT {limit}
-        "#,
-        ),
-        args.inner,
-    ))
-}
-
 #[async_trait::async_trait]
 impl crate::Endpoint for ListEvents {
     type Request = Request;
@@ -244,16 +34,7 @@ impl crate::Endpoint for ListEvents {
         cx: &Self::Cx,
         request: Self::Request,
     ) -> Result<Self::Response, Self::Error> {
-        // let limit = request.limit.unwrap_or(100);
-        let items = match &cx.db {
-            crate::Db::Pg { db_pool } => {
-                let (query, args) = pg_query(&request)?;
-                sqlx::query_as_with(&query[..], args)
-                    .fetch_all(db_pool)
-                    .await
-                    .unwrap_or_log()
-            }
-        };
+        let items = cx.store.query(&request, cx.config.limits.max_limit).await?;
         Ok(items)
     }
 }
@@ -264,6 +45,7 @@ mod tests {
 
     // use super::Request;
     use crate::event::testing::*;
+    use crate::event::Event;
 
     /*
     // TODO: serialization and validation tests for Filter
@@ -331,17 +113,35 @@ mod tests {
             }]),
             json!([*EVENT_02])
         ),
+        supports_search_filter: (
+            json!([{
+                "search": "kermit"
+            }]),
+            json!([*EVENT_03])
+        ),
+        search_orders_by_relevance: (
+            json!([{
+                "search": "information or kermit"
+            }]),
+            json!([*EVENT_03, *EVENT_04])
+        ),
+        search_ignores_extensions: (
+            json!([{
+                "search": "henson-gate language:en"
+            }]),
+            json!([*EVENT_05, *EVENT_04])
+        ),
         supports_since_filter: (
             json!([{
                 "since":  EVENT_03.created_at.unix_timestamp()
             }]),
-            json!([*EVENT_05, *EVENT_04, ])
+            json!([*EVENT_05, *EVENT_04, *EVENT_03])
         ),
         supports_until_filter: (
             json!([{
                 "until":  EVENT_03.created_at.unix_timestamp()
             }]),
-            json!([*EVENT_02, *EVENT_01, ])
+            json!([*EVENT_03, *EVENT_02, *EVENT_01, ])
         ),
         supports_tag_filter: (
             json!([{
@@ -350,4 +150,219 @@ mod tests {
             json!([*EVENT_05, *EVENT_04])
         ),
     }
+
+    fn event_ids(events: &[Event]) -> Vec<&str> {
+        events.iter().map(|event| &event.id[..]).collect()
+    }
+
+    common::table_tests! {
+        limits tokio,
+        (max_limit, request_json, expected),
+        {
+            let (testing, cx) = crate::utils::testing::cx_fn_with_config(
+                common::function_full!(),
+                |config| config.limits.max_limit = max_limit,
+            )
+            .await;
+            {
+                let filter = serde_json::from_value(request_json).unwrap();
+                let ok = crate::event::list::ListEvents.handle(&cx, filter).await.unwrap();
+                let expected: Vec<&Event> = expected;
+                assert_eq!(
+                    event_ids(&ok[..]),
+                    expected.iter().map(|event| &event.id[..]).collect::<Vec<_>>()
+                );
+            }
+            testing.close().await;
+        },
+        multi_thread: true,
+    }
+
+    limits! {
+        limit_is_per_filter: (
+            100,
+            json!([{ "kinds": [1], "limit": 1 }, { "kinds": [0], "limit": 1 }]),
+            vec![&*EVENT_05, &*EVENT_02]
+        ),
+        limit_keeps_the_newest: (
+            100,
+            json!([{ "limit": 3 }]),
+            vec![&*EVENT_05, &*EVENT_04, &*EVENT_03]
+        ),
+        limit_is_capped: (
+            2,
+            json!([{ "limit": 3 }]),
+            vec![&*EVENT_05, &*EVENT_04]
+        ),
+        missing_limit_uses_cap: (
+            2,
+            json!([{}]),
+            vec![&*EVENT_05, &*EVENT_04]
+        ),
+        zero_limit_returns_nothing: (
+            100,
+            json!([{ "limit": 0 }]),
+            vec![]
+        ),
+        zero_limit_skips_only_its_filter: (
+            100,
+            json!([{ "limit": 0 }, { "ids": [EVENT_03.id] }]),
+            vec![&*EVENT_03]
+        ),
+        overlapping_filters_dont_duplicate: (
+            100,
+            json!([{ "ids": [EVENT_03.id] }, { "authors": [EVENT_03.pubkey] }]),
+            vec![&*EVENT_04, &*EVENT_03]
+        ),
+    }
+
+    // Checks that `Filter::matches` agrees with the stored events query on
+    // the fixture events.
+    common::table_tests! {
+        conformance tokio,
+        (filter_json, expected),
+        {
+            let (testing, cx) = crate::utils::testing::cx_fn(common::function_full!()).await;
+            {
+                let expected: Vec<&Event> = expected;
+                let mut expected = expected
+                    .iter()
+                    .map(|event| &event.id[..])
+                    .collect::<Vec<_>>();
+                expected.sort();
+
+                let filter: crate::event::Filter =
+                    serde_json::from_value(filter_json).unwrap();
+                let mut matched = [&*EVENT_01, &*EVENT_02, &*EVENT_03, &*EVENT_04, &*EVENT_05]
+                    .into_iter()
+                    .filter(|event| filter.matches(event))
+                    .map(|event| &event.id[..])
+                    .collect::<Vec<_>>();
+                matched.sort();
+                assert_eq!(matched, expected, "matches");
+
+                let listed = crate::event::list::ListEvents
+                    .handle(&cx, vec![filter])
+                    .await
+                    .unwrap();
+                let mut listed = event_ids(&listed[..]);
+                listed.sort();
+                assert_eq!(listed, expected, "list");
+            }
+            testing.close().await;
+        },
+        multi_thread: true,
+    }
+
+    conformance! {
+        empty: (json!({}), vec![&*EVENT_01, &*EVENT_02, &*EVENT_03, &*EVENT_04, &*EVENT_05]),
+        ids: (json!({ "ids": [EVENT_03.id] }), vec![&*EVENT_03]),
+        ids_are_case_insensitive: (
+            json!({ "ids": [EVENT_03.id.to_uppercase()] }),
+            vec![&*EVENT_03]
+        ),
+        authors: (
+            json!({ "authors": [EVENT_03.pubkey] }),
+            vec![&*EVENT_03, &*EVENT_04]
+        ),
+        kinds: (json!({ "kinds": [0] }), vec![&*EVENT_02]),
+        since_is_inclusive: (
+            json!({ "since": EVENT_04.created_at.unix_timestamp() }),
+            vec![&*EVENT_04, &*EVENT_05]
+        ),
+        until_is_inclusive: (
+            json!({ "until": EVENT_01.created_at.unix_timestamp() }),
+            vec![&*EVENT_01, &*EVENT_02]
+        ),
+        since_equal_until: (
+            json!({
+                "since": EVENT_03.created_at.unix_timestamp(),
+                "until": EVENT_03.created_at.unix_timestamp(),
+            }),
+            vec![&*EVENT_03]
+        ),
+        tags: (
+            json!({ "#e": [EVENT_03.id] }),
+            vec![&*EVENT_04, &*EVENT_05]
+        ),
+        single_char_tag_keys: (
+            json!({ "#p": [EVENT_02.tags[0][1]] }),
+            vec![&*EVENT_02]
+        ),
+        all_tag_keys_must_match: (
+            json!({ "#e": [EVENT_03.id], "#p": [EVENT_02.tags[0][1]] }),
+            vec![]
+        ),
+        tag_values_are_escaped: (
+            json!({ "#e": [r#"x") || (@[0] == "e"#] }),
+            vec![]
+        ),
+        search: (json!({ "search": "kermit" }), vec![&*EVENT_03]),
+        combined: (
+            json!({
+                "kinds": [1],
+                "authors": [EVENT_03.pubkey],
+                "#e": [EVENT_03.id],
+            }),
+            vec![&*EVENT_04]
+        ),
+    }
+
+    fn tagged_event(value: &str) -> Event {
+        let tags = vec![
+            vec!["t".to_string(), value.to_string()],
+            vec!["t".to_string(), "other".to_string()],
+        ];
+        crate::event::testing::sign(EVENT_01_PRIVKEY, EVENT_01.created_at, 1, tags, "tagged")
+    }
+
+    // Tag values are compared verbatim, whatever they contain.
+    common::table_tests! {
+        tag_values tokio,
+        value,
+        {
+            for backend in crate::utils::testing::BACKENDS {
+                let (testing, cx) = crate::utils::testing::cx_fn_with_backend(
+                    common::function_full!(),
+                    backend,
+                    |_| {},
+                )
+                .await;
+                {
+                    let event = tagged_event(value);
+                    crate::event::create::CreateEvent
+                        .handle(&cx, event.clone())
+                        .await
+                        .unwrap();
+                    for (needles, expected) in [
+                        (json!([value]), vec![&event.id[..]]),
+                        (json!(["nope", value]), vec![&event.id[..]]),
+                        (json!([format!("{value}x")]), vec![]),
+                        (
+                            json!([value.chars().take(value.chars().count() - 1).collect::<String>()]),
+                            vec![],
+                        ),
+                    ] {
+                        let filter = serde_json::from_value(json!([{ "#t": needles }])).unwrap();
+                        let listed = crate::event::list::ListEvents
+                            .handle(&cx, filter)
+                            .await
+                            .unwrap();
+                        assert_eq!(event_ids(&listed[..]), expected, "{backend:?} {needles}");
+                    }
+                }
+                testing.close().await;
+            }
+        },
+        multi_thread: true,
+    }
+
+    tag_values! {
+        double_quotes: r#"she said "hi""#,
+        single_quotes: "it's",
+        backslashes: r"C:\windows\",
+        jsonpath_syntax: r#"x") || (@[0] == "t"#,
+        unicode: "ሰላም ዓለም 🌍",
+        combining_marks: "e\u{301}te\u{301}",
+    }
 }
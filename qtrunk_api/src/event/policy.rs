@@ -0,0 +1,383 @@
+//! Write policies decide which events the relay takes in. They run after
+//! the event's signature has been validated.
+
+use crate::interlude::*;
+
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
+
+use super::Event;
+
+/// What's known of the publisher of an event.
+#[derive(Debug, Clone, Default)]
+pub struct Origin {
+    /// Pubkeys that completed NIP-42 auth on the publishing connection.
+    pub authed_pubkeys: Vec<String>,
+    /// Set for events published by clients of the relay, which are the
+    /// ones the publishing rate limits apply to.
+    pub ip: Option<std::net::IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    /// The reason is sent in the OK message and should carry one of the
+    /// NIP-01 prefixes.
+    Reject(String),
+    /// Pretend to accept the event but drop it.
+    ShadowAccept,
+}
+
+#[async_trait::async_trait]
+pub trait WritePolicy: std::fmt::Debug + Send + Sync {
+    async fn check(&self, cx: &Context, event: &Event, origin: &Origin) -> Verdict;
+}
+
+/// Runs the policies in order. The first that doesn't accept the event
+/// decides. Accepts everything if empty.
+#[derive(Debug, Default)]
+pub struct Chain(pub Vec<Box<dyn WritePolicy>>);
+
+impl Chain {
+    pub fn with(mut self, policy: impl WritePolicy + 'static) -> Self {
+        self.0.push(Box::new(policy));
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl WritePolicy for Chain {
+    async fn check(&self, cx: &Context, event: &Event, origin: &Origin) -> Verdict {
+        for policy in &self.0 {
+            match policy.check(cx, event, origin).await {
+                Verdict::Accept => {}
+                verdict => return verdict,
+            }
+        }
+        Verdict::Accept
+    }
+}
+
+/// Only accepts events of the given kinds.
+#[derive(Debug, Clone)]
+pub struct AllowKinds(pub Vec<RangeInclusive<u16>>);
+
+#[async_trait::async_trait]
+impl WritePolicy for AllowKinds {
+    async fn check(&self, _cx: &Context, event: &Event, _origin: &Origin) -> Verdict {
+        if self.0.iter().any(|range| range.contains(&event.kind)) {
+            Verdict::Accept
+        } else {
+            Verdict::Reject(format!("blocked: kind {} is not accepted", event.kind))
+        }
+    }
+}
+
+/// Decides whether a pubkey may publish.
+#[async_trait::async_trait]
+pub trait PubkeyLookup: Send + Sync {
+    async fn is_allowed(&self, cx: &Context, pubkey: &str) -> eyre::Result<bool>;
+}
+
+#[async_trait::async_trait]
+impl PubkeyLookup for HashSet<String> {
+    async fn is_allowed(&self, _cx: &Context, pubkey: &str) -> eyre::Result<bool> {
+        Ok(self.contains(&pubkey.to_ascii_lowercase()))
+    }
+}
+
+/// Only accepts events from the pubkeys allowed by the lookup, e.g. the
+/// ones known to another service.
+#[derive(educe::Educe)]
+#[educe(Debug)]
+pub struct AllowPubkeys {
+    #[educe(Debug(ignore))]
+    lookup: Box<dyn PubkeyLookup>,
+}
+
+impl AllowPubkeys {
+    pub fn new(lookup: impl PubkeyLookup + 'static) -> Self {
+        Self {
+            lookup: Box::new(lookup),
+        }
+    }
+
+    /// Allows the listed hex pubkeys.
+    pub fn list(pubkeys: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        Self::new(
+            pubkeys
+                .into_iter()
+                .map(|pubkey| pubkey.as_ref().to_ascii_lowercase())
+                .collect::<HashSet<_>>(),
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl WritePolicy for AllowPubkeys {
+    async fn check(&self, cx: &Context, event: &Event, _origin: &Origin) -> Verdict {
+        match self.lookup.is_allowed(cx, &event.pubkey).await {
+            Ok(true) => Verdict::Accept,
+            Ok(false) => Verdict::Reject("blocked: pubkey is not allowed to publish here".into()),
+            Err(err) => {
+                error!(?err, "error looking up pubkey");
+                Verdict::Reject("error: unable to check pubkey, try again".into())
+            }
+        }
+    }
+}
+
+/// Rejects events from the listed hex pubkeys.
+#[derive(Debug, Clone)]
+pub struct DenyPubkeys(HashSet<String>);
+
+impl DenyPubkeys {
+    pub fn new(pubkeys: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        Self(
+            pubkeys
+                .into_iter()
+                .map(|pubkey| pubkey.as_ref().to_ascii_lowercase())
+                .collect(),
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl WritePolicy for DenyPubkeys {
+    async fn check(&self, _cx: &Context, event: &Event, _origin: &Origin) -> Verdict {
+        if self.0.contains(&event.pubkey.to_ascii_lowercase()) {
+            Verdict::Reject("blocked: pubkey is banned".into())
+        } else {
+            Verdict::Accept
+        }
+    }
+}
+
+/// Refuses events whose content matches any of the patterns. Shadow
+/// accepting them instead keeps spammers from learning what's filtered.
+#[derive(Debug, Clone)]
+pub struct DenyContent {
+    patterns: regex::RegexSet,
+    shadow: bool,
+}
+
+impl DenyContent {
+    pub fn new(
+        patterns: impl IntoIterator<Item = impl AsRef<str>>,
+        shadow: bool,
+    ) -> Result<Self, regex::Error> {
+        Ok(Self {
+            patterns: regex::RegexSet::new(patterns)?,
+            shadow,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl WritePolicy for DenyContent {
+    async fn check(&self, _cx: &Context, event: &Event, _origin: &Origin) -> Verdict {
+        if !self.patterns.is_match(&event.content) {
+            Verdict::Accept
+        } else if self.shadow {
+            Verdict::ShadowAccept
+        } else {
+            Verdict::Reject("blocked: content is not allowed".into())
+        }
+    }
+}
+
+/// Events of the given kinds are only accepted from their authors after
+/// they've authenticated through NIP-42.
+#[derive(Debug, Clone)]
+pub struct RequireAuth(pub Vec<RangeInclusive<u16>>);
+
+#[async_trait::async_trait]
+impl WritePolicy for RequireAuth {
+    async fn check(&self, _cx: &Context, event: &Event, origin: &Origin) -> Verdict {
+        if !self.0.iter().any(|range| range.contains(&event.kind)) {
+            return Verdict::Accept;
+        }
+        if origin
+            .authed_pubkeys
+            .iter()
+            .any(|pubkey| pubkey.eq_ignore_ascii_case(&event.pubkey))
+        {
+            Verdict::Accept
+        } else {
+            Verdict::Reject(format!(
+                "auth-required: kind {} events must be published by their authenticated author",
+                event.kind
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use super::*;
+    use crate::event::testing::*;
+
+    common::table_tests! {
+        check tokio,
+        (policy, event, origin, expected),
+        {
+            let (testing, cx) = crate::utils::testing::cx_fn(common::function_full!()).await;
+            {
+                let policy: Chain = policy;
+                let origin: Origin = origin;
+                assert_eq!(policy.check(&cx, &event, &origin).await, expected);
+            }
+            testing.close().await;
+        },
+        multi_thread: true,
+    }
+
+    check! {
+        empty_chain_accepts: (Chain::default(), EVENT_01.clone(), default(), Verdict::Accept),
+        allowed_kind: (
+            Chain::default().with(AllowKinds(vec![0..=1, 3..=3])),
+            EVENT_01.clone(),
+            default(),
+            Verdict::Accept
+        ),
+        disallowed_kind: (
+            Chain::default().with(AllowKinds(vec![0..=0, 3..=3])),
+            EVENT_01.clone(),
+            default(),
+            Verdict::Reject("blocked: kind 1 is not accepted".into())
+        ),
+        allowed_pubkey: (
+            Chain::default().with(AllowPubkeys::list([EVENT_01.pubkey.to_uppercase()])),
+            EVENT_01.clone(),
+            default(),
+            Verdict::Accept
+        ),
+        unknown_pubkey: (
+            Chain::default().with(AllowPubkeys::list([&EVENT_02.pubkey])),
+            EVENT_01.clone(),
+            default(),
+            Verdict::Reject("blocked: pubkey is not allowed to publish here".into())
+        ),
+        denied_pubkey: (
+            Chain::default().with(DenyPubkeys::new([&EVENT_01.pubkey])),
+            EVENT_01.clone(),
+            default(),
+            Verdict::Reject("blocked: pubkey is banned".into())
+        ),
+        denied_content: (
+            Chain::default().with(DenyContent::new([r"(?i)\btuesdays\b"], false).unwrap()),
+            EVENT_01.clone(),
+            default(),
+            Verdict::Reject("blocked: content is not allowed".into())
+        ),
+        shadowed_content: (
+            Chain::default().with(DenyContent::new([r"(?i)\btuesdays\b"], true).unwrap()),
+            EVENT_01.clone(),
+            default(),
+            Verdict::ShadowAccept
+        ),
+        allowed_content: (
+            Chain::default().with(DenyContent::new([r"(?i)\bkermit\b"], false).unwrap()),
+            EVENT_01.clone(),
+            default(),
+            Verdict::Accept
+        ),
+        requires_auth: (
+            Chain::default().with(RequireAuth(vec![1..=1])),
+            EVENT_01.clone(),
+            Origin {
+                authed_pubkeys: vec![EVENT_02.pubkey.clone()],
+                ..default()
+            },
+            Verdict::Reject(
+                "auth-required: kind 1 events must be published by their authenticated author"
+                    .into()
+            )
+        ),
+        authed_author: (
+            Chain::default().with(RequireAuth(vec![1..=1])),
+            EVENT_01.clone(),
+            Origin {
+                authed_pubkeys: vec![EVENT_01.pubkey.clone()],
+                ..default()
+            },
+            Verdict::Accept
+        ),
+        auth_not_required_for_kind: (
+            Chain::default().with(RequireAuth(vec![4..=4])),
+            EVENT_01.clone(),
+            default(),
+            Verdict::Accept
+        ),
+        first_refusal_wins: (
+            Chain::default()
+                .with(AllowKinds(vec![1..=1]))
+                .with(DenyContent::new(["tuesdays"], true).unwrap())
+                .with(DenyPubkeys::new([&EVENT_01.pubkey])),
+            EVENT_01.clone(),
+            default(),
+            Verdict::ShadowAccept
+        ),
+    }
+
+    #[tokio::test]
+    async fn runs_on_create() {
+        let (testing, cx) =
+            crate::utils::testing::cx_fn_with_config(common::function_full!(), |config| {
+                config.write_policy = Chain::default()
+                    .with(RequireAuth(vec![1..=1]))
+                    .with(DenyContent::new(["burning"], true).unwrap());
+            })
+            .await;
+        {
+            let event = EVENT_01.clone();
+            // not authed
+            let ok = match crate::event::create::CreateEvent
+                .handle(&cx, event.clone())
+                .await
+            {
+                Ok(value) => value.to_nostr_ok(),
+                Err(value) => value.to_nostr_ok(),
+            };
+            check_json(
+                ("expected", &json!(["OK", event.id, false])),
+                ("response", &ok),
+            );
+            assert!(
+                ok[3].as_str().unwrap().starts_with("auth-required:"),
+                "{ok:?}"
+            );
+
+            // shadow accepted
+            let event = sign(
+                EVENT_01_PRIVKEY,
+                event.created_at,
+                1,
+                vec![],
+                "The stars are a burning sun",
+            );
+            let origin = Origin {
+                authed_pubkeys: vec![event.pubkey.clone()],
+                ..default()
+            };
+            let ok = crate::event::create::CreateEvent
+                .handle_from(&cx, event.clone(), &origin)
+                .await
+                .unwrap()
+                .to_nostr_ok();
+            check_json(
+                ("expected", &json!(["OK", event.id, true])),
+                ("response", &ok),
+            );
+            let filter = serde_json::from_value(json!([{ "ids": [event.id] }])).unwrap();
+            let listed = crate::event::list::ListEvents
+                .handle(&cx, filter)
+                .await
+                .unwrap();
+            assert!(listed.is_empty(), "{listed:?}");
+        }
+        testing.close().await;
+    }
+}
@@ -0,0 +1,199 @@
+//! NIP-13 proof of work.
+
+use super::Event;
+
+/// Number of leading zero bits in the id.
+pub fn leading_zero_bits(id: &[u8]) -> u32 {
+    let mut out = 0;
+    for byte in id {
+        out += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    out
+}
+
+/// The target difficulty committed to by the third value of the `nonce` tag.
+pub fn committed_target(event: &Event) -> Option<u32> {
+    event
+        .tags
+        .iter()
+        .find(|tag| matches!(tag.get(0).map(|st| &st[..]), Some("nonce")))
+        .and_then(|tag| tag.get(2))
+        .and_then(|target| target.parse().ok())
+}
+
+/// The difficulty the event can be credited with. A committed target caps it
+/// so that events mined for less that got lucky don't pass.
+pub fn difficulty(event: &Event, id: &[u8]) -> u32 {
+    let bits = leading_zero_bits(id);
+    match committed_target(event) {
+        Some(target) => bits.min(target),
+        None => bits,
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use crate::interlude::*;
+
+    use crate::event::Event;
+
+    /// Mines the event until its id has `difficulty` leading zero bits, adding
+    /// a `nonce` tag that commits to `committed_target`, then signs it.
+    pub fn mine(event: Event, privkey: &str, difficulty: u32, committed_target: u32) -> Event {
+        let key = data_encoding::HEXLOWER.decode(privkey.as_bytes()).unwrap();
+        let key = k256::schnorr::SigningKey::from_bytes(&key[..]).unwrap();
+        let pubkey = data_encoding::HEXLOWER.encode(&key.verifying_key().to_bytes()[..]);
+        for nonce in 0u64.. {
+            let mut tags = event.tags.clone();
+            tags.push(vec![
+                "nonce".into(),
+                nonce.to_string(),
+                committed_target.to_string(),
+            ]);
+            let (id, _) = crate::event::id_for_event(
+                &pubkey,
+                event.created_at,
+                event.kind,
+                &tags,
+                &event.content,
+            );
+            if super::leading_zero_bits(&id[..]) >= difficulty {
+                let (id, sig) = crate::event::hex_id_and_sig_for_event(
+                    &key,
+                    &pubkey,
+                    event.created_at,
+                    event.kind,
+                    &tags,
+                    &event.content,
+                );
+                return Event {
+                    id,
+                    sig,
+                    pubkey,
+                    tags,
+                    ..event
+                };
+            }
+        }
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use super::testing::*;
+    use crate::event::testing::*;
+    use crate::event::Event;
+
+    fn unmined(kind: u16) -> Event {
+        sign(
+            EVENT_01_PRIVKEY,
+            EVENT_01.created_at,
+            kind,
+            vec![],
+            "spam spam spam",
+        )
+    }
+
+    async fn create_ok(cx: &Context, event: Event) -> serde_json::Value {
+        match crate::event::create::CreateEvent.handle(cx, event).await {
+            Ok(value) => value.to_nostr_ok(),
+            Err(value) => value.to_nostr_ok(),
+        }
+    }
+
+    common::table_tests! {
+        leading_zero_bits,
+        (id, expected),
+        {
+            assert_eq!(crate::event::pow::leading_zero_bits(&id[..]), expected);
+        }
+    }
+
+    leading_zero_bits! {
+        none: ([0xffu8, 0x00], 0),
+        partial_byte: ([0x0fu8, 0xff], 4),
+        whole_bytes: ([0x00u8, 0x00, 0x01, 0xff], 23),
+        all_zeroes: ([0x00u8; 4], 32),
+    }
+
+    #[test]
+    fn committed_target_caps_difficulty() {
+        let event = mine(unmined(1), EVENT_01_PRIVKEY, 8, 4);
+        let id = data_encoding::HEXLOWER.decode(event.id.as_bytes()).unwrap();
+        assert!(super::leading_zero_bits(&id[..]) >= 8);
+        assert_eq!(super::difficulty(&event, &id[..]), 4);
+    }
+
+    #[tokio::test]
+    async fn enforces_min_difficulty() {
+        let (testing, cx) =
+            crate::utils::testing::cx_fn_with_config(common::function_full!(), |config| {
+                config.limits.min_pow_difficulty = 8
+            })
+            .await;
+        {
+            let event = unmined(1);
+            let ok = create_ok(&cx, event.clone()).await;
+            check_json(
+                ("expected", &json!(["OK", event.id, false])),
+                ("response", &ok),
+            );
+            assert!(ok[3].as_str().unwrap().starts_with("pow:"), "{ok:?}");
+
+            // lucky events don't count if they commit to less
+            let event = mine(unmined(1), EVENT_01_PRIVKEY, 8, 4);
+            let ok = create_ok(&cx, event.clone()).await;
+            check_json(
+                ("expected", &json!(["OK", event.id, false])),
+                ("response", &ok),
+            );
+
+            let event = mine(unmined(1), EVENT_01_PRIVKEY, 8, 8);
+            let ok = create_ok(&cx, event.clone()).await;
+            check_json(
+                ("expected", &json!(["OK", event.id, true])),
+                ("response", &ok),
+            );
+        }
+        testing.close().await;
+    }
+
+    #[tokio::test]
+    async fn supports_per_kind_difficulty() {
+        let (testing, cx) =
+            crate::utils::testing::cx_fn_with_config(common::function_full!(), |config| {
+                config.limits.min_pow_difficulty = 8;
+                config.limits.kind_pow_difficulty = vec![(0..=1, 0), (7..=7, 12)];
+            })
+            .await;
+        {
+            let event = unmined(1);
+            let ok = create_ok(&cx, event.clone()).await;
+            check_json(
+                ("expected", &json!(["OK", event.id, true])),
+                ("response", &ok),
+            );
+
+            let event = mine(unmined(7), EVENT_01_PRIVKEY, 8, 8);
+            let ok = create_ok(&cx, event.clone()).await;
+            check_json(
+                ("expected", &json!(["OK", event.id, false])),
+                ("response", &ok),
+            );
+
+            let event = mine(unmined(30023), EVENT_01_PRIVKEY, 8, 8);
+            let ok = create_ok(&cx, event.clone()).await;
+            check_json(
+                ("expected", &json!(["OK", event.id, true])),
+                ("response", &ok),
+            );
+        }
+        testing.close().await;
+    }
+}
@@ -0,0 +1,28 @@
+//! Fan out of the accepted events, and of the changes to the bans and
+//! settings overrides, to the switchboards. A Redis stream
+//! carries them across instances while single instance setups can make do
+//! with an in-process channel.
+
+use crate::interlude::*;
+
+use crate::event::Event;
+
+pub mod broadcast;
+pub mod stream;
+
+pub use broadcast::BroadcastHose;
+pub use stream::RedisHose;
+
+#[async_trait::async_trait]
+pub trait EventHose: std::fmt::Debug + Send + Sync {
+    /// Hands the event to the switchboards of all instances.
+    async fn publish(&self, event: &Event) -> eyre::Result<()>;
+
+    /// Hands the overrides change to the switchboards of the other
+    /// instances, the publishing one having already applied it.
+    async fn publish_change(&self, change: &crate::manage::Change) -> eyre::Result<()>;
+
+    /// Switches the events published from here on. Only returns on errors
+    /// it can't recover from.
+    async fn follow(&self, cx: &Context) -> eyre::Result<()>;
+}
@@ -0,0 +1,56 @@
+//! In-process [`EventHose`] for single instance setups.
+
+use crate::interlude::*;
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::EventHose;
+use crate::event::Event;
+
+#[derive(Debug)]
+pub struct BroadcastHose {
+    tx: broadcast::Sender<Event>,
+}
+
+impl BroadcastHose {
+    /// `capacity` events get buffered for a lagging switchboard before it
+    /// starts missing them.
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self { tx }
+    }
+}
+
+impl Default for BroadcastHose {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+#[async_trait::async_trait]
+impl EventHose for BroadcastHose {
+    async fn publish(&self, event: &Event) -> eyre::Result<()> {
+        // no receivers just means nobody's switching yet
+        let _ = self.tx.send(event.clone());
+        Ok(())
+    }
+
+    async fn publish_change(&self, _change: &crate::manage::Change) -> eyre::Result<()> {
+        // there are no other instances
+        Ok(())
+    }
+
+    async fn follow(&self, cx: &Context) -> eyre::Result<()> {
+        let mut rx = self.tx.subscribe();
+        loop {
+            match rx.recv().await {
+                Ok(event) => crate::connect::switch(cx, &event).await,
+                Err(RecvError::Lagged(count)) => {
+                    warn!(count, "switchboard fell behind, events were missed")
+                }
+                // we hold the sender
+                Err(RecvError::Closed) => unreachable!(),
+            }
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! [`EventHose`] over a Redis stream, shared by all instances. Overrides
+//! changes go through the same stream, ordered along with the events. A
+//! lost connection is retried with backoff and the entries published in
+//! the meantime get replayed, as long as they're still in the stream.
+
+use crate::interlude::*;
+
+use redis::AsyncCommands;
+
+use super::EventHose;
+use crate::event::Event;
+
+/// Field of the stream entries holding the event.
+const EVENT_FIELD: &str = "event";
+/// Field of the stream entries holding an overrides change, as JSON.
+const CHANGE_FIELD: &str = "change";
+/// Max number of entries read at once.
+const READ_COUNT: usize = 256;
+/// How long a read waits for new entries before it's retried.
+const READ_BLOCK: std::time::Duration = std::time::Duration::from_secs(5);
+const MIN_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+const MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct RedisHose {
+    redis: RedisPool,
+    stream: String,
+    max_len: usize,
+}
+
+impl RedisHose {
+    /// The stream gets trimmed to roughly `max_len` entries, which bounds
+    /// how far back an instance can catch up after a reconnect.
+    pub fn new(redis: RedisPool, stream: impl Into<String>, max_len: usize) -> Self {
+        Self {
+            redis,
+            stream: stream.into(),
+            max_len,
+        }
+    }
+
+    /// Id of the newest entry in the stream, `0-0` if it's empty.
+    async fn latest_id(&self) -> eyre::Result<String> {
+        let mut conn = self.redis.get().await?;
+        let reply: redis::streams::StreamRangeReply = conn
+            .xrevrange_count(self.stream.as_str(), "+", "-", 1)
+            .await?;
+        Ok(reply
+            .ids
+            .into_iter()
+            .next()
+            .map(|entry| entry.id)
+            .unwrap_or_else(|| "0-0".into()))
+    }
+
+    /// Switches the entries after `last_id`, keeping it up to date. Only
+    /// returns once the connection fails.
+    async fn follow_from(
+        &self,
+        cx: &Context,
+        last_id: &mut String,
+        backoff: &mut std::time::Duration,
+    ) -> eyre::Report {
+        let mut conn = match self.redis.dedicated_connection().await {
+            Ok(conn) => conn,
+            Err(err) => return err.into(),
+        };
+        let opts = redis::streams::StreamReadOptions::default()
+            .count(READ_COUNT)
+            .block(READ_BLOCK.as_millis() as usize);
+        loop {
+            let reply: redis::streams::StreamReadReply = match conn
+                .xread_options(&[self.stream.as_str()], &[last_id.as_str()], &opts)
+                .await
+            {
+                Ok(reply) => reply,
+                Err(err) => return err.into(),
+            };
+            *backoff = MIN_BACKOFF;
+            for entry in reply.keys.into_iter().flat_map(|key| key.ids) {
+                *last_id = entry.id.clone();
+                if let Some(event) = entry.get::<Event>(EVENT_FIELD) {
+                    crate::connect::switch(cx, &event).await;
+                } else if let Some(change) = entry
+                    .get::<String>(CHANGE_FIELD)
+                    .and_then(|json| serde_json::from_str(&json).ok())
+                {
+                    cx.manage.apply(&change);
+                } else {
+                    warn!(id = %entry.id, "malformed event hose entry");
+                }
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EventHose for RedisHose {
+    #[tracing::instrument(skip(self), err)]
+    async fn publish(&self, event: &Event) -> eyre::Result<()> {
+        let mut conn = self.redis.get().await?;
+        conn.xadd_maxlen(
+            self.stream.as_str(),
+            redis::streams::StreamMaxlen::Approx(self.max_len),
+            "*",
+            &[(EVENT_FIELD, event)],
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn publish_change(&self, change: &crate::manage::Change) -> eyre::Result<()> {
+        let mut conn = self.redis.get().await?;
+        conn.xadd_maxlen(
+            self.stream.as_str(),
+            redis::streams::StreamMaxlen::Approx(self.max_len),
+            "*",
+            &[(CHANGE_FIELD, serde_json::to_string(change)?)],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn follow(&self, cx: &Context) -> eyre::Result<()> {
+        // events from before the switchboard started aren't of interest
+        let mut last_id = None;
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let err = match &mut last_id {
+                Some(last_id) => self.follow_from(cx, last_id, &mut backoff).await,
+                None => match self.latest_id().await {
+                    Ok(id) => {
+                        last_id = Some(id);
+                        continue;
+                    }
+                    Err(err) => err,
+                },
+            };
+            warn!(?err, ?last_id, ?backoff, "event hose lost, reconnecting");
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use super::*;
+    use crate::event::testing::*;
+
+    fn hose(cx: &Context, max_len: usize) -> RedisHose {
+        RedisHose::new(
+            cx.redis.clone().unwrap(),
+            format!("event_hose_{}", Uuid::new_v4()),
+            max_len,
+        )
+    }
+
+    #[tokio::test]
+    async fn replays_missed_events() {
+        let (testing, cx) = crate::utils::testing::cx_fn_with_redis(common::function_full!()).await;
+        {
+            let hose = std::sync::Arc::new(hose(&cx, 10_000));
+            let outbox = cx
+                .sw
+                .test_subscriber(serde_json::from_value(json!([{}])).unwrap())
+                .await;
+            let mut last_id = hose.latest_id().await.unwrap();
+            // published while the switchboard was reconnecting
+            for event in [&*EVENT_01, &*EVENT_03] {
+                hose.publish(event).await.unwrap();
+            }
+            let follower = tokio::spawn({
+                let (cx, hose) = (cx.clone(), hose.clone());
+                async move {
+                    let mut backoff = MAX_BACKOFF;
+                    hose.follow_from(&cx, &mut last_id, &mut backoff).await
+                }
+            });
+            for event in [&*EVENT_01, &*EVENT_03] {
+                let msg = tokio::time::timeout(std::time::Duration::from_secs(5), outbox.pop())
+                    .await
+                    .expect("missed event wasn't replayed")
+                    .unwrap();
+                check_json(
+                    ("expected", &json!(["EVENT", "sub", { "id": event.id }])),
+                    ("response", &msg),
+                );
+            }
+            follower.abort();
+        }
+        testing.close().await;
+    }
+
+    #[tokio::test]
+    async fn keeps_retrying_when_unreachable() {
+        let (testing, cx) = crate::utils::testing::cx_fn(common::function_full!()).await;
+        {
+            let pool = bb8_redis::bb8::Pool::builder()
+                .connection_timeout(std::time::Duration::from_millis(50))
+                .build_unchecked(
+                    bb8_redis::RedisConnectionManager::new("redis://127.0.0.1:1").unwrap(),
+                );
+            let hose = RedisHose::new(RedisPool(pool), "event_hose", 10);
+            let follower = tokio::spawn({
+                let cx = cx.clone();
+                async move { hose.follow(&cx).await }
+            });
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            assert!(
+                !follower.is_finished(),
+                "follower gave up: {:?}",
+                follower.await
+            );
+            follower.abort();
+        }
+        testing.close().await;
+    }
+
+    #[tokio::test]
+    async fn retention_is_bounded() {
+        const EVENT_COUNT: usize = 300;
+        let (testing, cx) = crate::utils::testing::cx_fn_with_redis(common::function_full!()).await;
+        {
+            let hose = hose(&cx, 10);
+            for _ in 0..EVENT_COUNT {
+                hose.publish(&EVENT_01).await.unwrap();
+            }
+            let mut conn = hose.redis.get().await.unwrap();
+            let len: usize = conn.xlen(hose.stream.as_str()).await.unwrap();
+            // trimming is approximate
+            assert!(len < EVENT_COUNT, "stream wasn't trimmed: {len} entries");
+        }
+        testing.close().await;
+    }
+}
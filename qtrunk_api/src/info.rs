@@ -0,0 +1,179 @@
+//! NIP-11 relay information document.
+
+use crate::interlude::*;
+
+/// NIPs implemented by the relay.
+pub const SUPPORTED_NIPS: &[u16] = &[1, 9, 11, 13, 40, 42, 45, 50, 77, 86];
+
+pub const MEDIA_TYPE: &str = "application/nostr+json";
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(crate = "serde")]
+pub struct RelayInformationDocument {
+    pub name: String,
+    pub description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contact: Option<String>,
+    pub supported_nips: Vec<u16>,
+    pub software: String,
+    pub version: String,
+    pub limitation: Limitation,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(crate = "serde")]
+pub struct Limitation {
+    pub max_message_length: usize,
+    pub max_limit: usize,
+    pub max_subscriptions: usize,
+    pub max_filters: usize,
+    pub max_subid_length: usize,
+    pub max_event_tags: usize,
+    pub max_content_length: usize,
+    pub min_pow_difficulty: u8,
+    pub auth_required: bool,
+    pub payment_required: bool,
+}
+
+impl RelayInformationDocument {
+    pub fn new(cx: &Context) -> Self {
+        let config = &cx.config;
+        Self {
+            // NIP-86 admins can rename the relay
+            name: cx
+                .manage
+                .relay_name()
+                .unwrap_or_else(|| config.relay_name.clone()),
+            description: config.relay_description.clone(),
+            pubkey: config.relay_pubkey.clone(),
+            contact: config.relay_contact.clone(),
+            supported_nips: SUPPORTED_NIPS.to_vec(),
+            software: crate::build::PROJECT_NAME.into(),
+            version: crate::build::PKG_VERSION.into(),
+            limitation: Limitation {
+                max_message_length: config.limits.max_message_length,
+                max_limit: config.limits.max_limit,
+                max_subscriptions: config.limits.max_subscriptions,
+                max_filters: config.limits.max_filters,
+                max_subid_length: config.limits.max_subid_length,
+                max_event_tags: config.limits.max_event_tags,
+                max_content_length: config.limits.max_content_length,
+                min_pow_difficulty: config.limits.min_pow_difficulty,
+                auth_required: false,
+                payment_required: false,
+            },
+        }
+    }
+}
+
+/// Whether the request is asking for the relay information document.
+pub fn is_info_request(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(http::header::ACCEPT)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','))
+        .filter_map(|val| val.split(';').next())
+        .any(|val| val.trim().eq_ignore_ascii_case(MEDIA_TYPE))
+}
+
+pub fn response(cx: &Context) -> axum::response::Response {
+    use http::header::*;
+    (
+        [
+            (CONTENT_TYPE, MEDIA_TYPE),
+            // NIP-11 requires the document be readable by browser clients
+            (ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+            (ACCESS_CONTROL_ALLOW_HEADERS, "*"),
+            (ACCESS_CONTROL_ALLOW_METHODS, "GET"),
+        ],
+        serde_json::to_string(&RelayInformationDocument::new(cx)).unwrap_or_log(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn serves_info_document() {
+        let (testing, cx) =
+            crate::utils::testing::cx_fn_with_config(common::function_full!(), |config| {
+                config.limits.min_pow_difficulty = 16
+            })
+            .await;
+        {
+            let app =
+                crate::router(cx.clone()).layer(axum::extract::connect_info::MockConnectInfo(
+                    std::net::SocketAddr::from(([127, 0, 0, 1], 19001)),
+                ));
+            let resp = app
+                .oneshot(
+                    http::Request::builder()
+                        .method("GET")
+                        .uri("/")
+                        .header(http::header::ACCEPT, super::MEDIA_TYPE)
+                        .body(Default::default())
+                        .unwrap_or_log(),
+                )
+                .await
+                .unwrap_or_log();
+            assert_eq!(resp.status(), http::StatusCode::OK);
+            assert_eq!(
+                resp.headers()[http::header::ACCESS_CONTROL_ALLOW_ORIGIN],
+                "*"
+            );
+            let body = hyper::body::to_bytes(resp.into_body())
+                .await
+                .unwrap_or_log();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap_or_log();
+            check_json(
+                (
+                    "expected",
+                    &json!({
+                        "name": cx.config.relay_name,
+                        "supported_nips": super::SUPPORTED_NIPS,
+                        "version": crate::build::PKG_VERSION,
+                        "limitation": {
+                            "max_message_length": cx.config.limits.max_message_length,
+                            "max_limit": cx.config.limits.max_limit,
+                            "max_subscriptions": cx.config.limits.max_subscriptions,
+                            "max_filters": cx.config.limits.max_filters,
+                            "min_pow_difficulty": 16,
+                        }
+                    }),
+                ),
+                ("response", &body),
+            );
+        }
+        testing.close().await;
+    }
+
+    #[tokio::test]
+    async fn plain_get_is_not_info_request() {
+        let (testing, cx) = crate::utils::testing::cx_fn(common::function_full!()).await;
+        {
+            let app = crate::router(cx).layer(axum::extract::connect_info::MockConnectInfo(
+                std::net::SocketAddr::from(([127, 0, 0, 1], 19001)),
+            ));
+            let resp = app
+                .oneshot(
+                    http::Request::builder()
+                        .method("GET")
+                        .uri("/")
+                        .header(http::header::ACCEPT, "application/json")
+                        .body(Default::default())
+                        .unwrap_or_log(),
+                )
+                .await
+                .unwrap_or_log();
+            assert_ne!(resp.status(), http::StatusCode::OK);
+        }
+        testing.close().await;
+    }
+}
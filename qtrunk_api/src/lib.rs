@@ -38,10 +38,23 @@ mod interlude {
 }
 use interlude::*;
 
+pub mod asyncapi;
+pub mod auth;
+pub mod bulk;
+pub mod client;
 pub mod connect;
 pub mod event;
+pub mod hose;
+pub mod info;
+pub mod manage;
+pub mod mirror;
+pub mod negentropy;
+pub mod rate_limit;
+pub mod store;
 pub mod utils;
 
+pub use client::{Client, HttpClient, InProcClient};
+
 // use crate::utils::*;
 
 #[derive(Debug)]
@@ -51,20 +64,118 @@ pub struct Config {
     pub auth_token_lifespan: time::Duration,
     pub web_session_lifespan: time::Duration,
     pub service_secret: String,
-    pub event_hose_redis_channel: String,
+    /// Namespace for the keys stored in redis.
+    pub redis_key_prefix: String,
+    pub relay_name: String,
+    pub relay_description: String,
+    /// Hex encoded pubkey of the relay admin.
+    pub relay_pubkey: Option<String>,
+    pub relay_contact: Option<String>,
+    /// The url clients connect to, checked against NIP-42 `relay` tags.
+    pub relay_url: String,
+    /// Hex encoded pubkeys allowed to use the NIP-86 management API.
+    pub admin_pubkeys: Vec<String>,
+    /// How often expired events are purged from the store and the archive
+    /// is pruned.
+    pub expired_reap_interval: time::Duration,
+    /// How long superseded versions of replaceable events are kept in the
+    /// archive. Forever if `None`.
+    pub archive_retention: Option<time::Duration>,
+    /// Most superseded versions kept in the archive for each address, the
+    /// oldest going first. Unbounded if `None`.
+    pub archive_max_versions: Option<usize>,
+    /// How often connected clients get pinged.
+    pub ping_interval: time::Duration,
+    /// Clients that haven't sent anything, pongs included, for this long
+    /// are taken for dead and disconnected.
+    pub idle_timeout: time::Duration,
+    /// How long connected clients are given to be seen off on shutdown.
+    pub shutdown_grace: time::Duration,
+    pub limits: Limits,
+    /// Decides which events get accepted. Accepts everything if empty.
+    pub write_policy: event::policy::Chain,
+    /// Relays to pull events from.
+    pub upstreams: Vec<mirror::Upstream>,
+}
+
+/// Limits enforced by the relay, advertised through NIP-11.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Max size in bytes of a message recieved over the websocket.
+    pub max_message_length: usize,
+    /// Cap on the `limit` of each filter in the initial query of a
+    /// subscription, also used for filters without one.
+    pub max_limit: usize,
+    /// NIP-13 proof-of-work difficulty, in leading zero bits of the id,
+    /// required of events.
+    pub min_pow_difficulty: u8,
+    /// Overrides of `min_pow_difficulty` for ranges of kinds. The first
+    /// range containing the kind wins.
+    pub kind_pow_difficulty: Vec<(std::ops::RangeInclusive<u16>, u8)>,
+    /// Max number of messages queued for a client before the
+    /// `slow_consumer_policy` kicks in.
+    pub outbox_capacity: usize,
+    pub slow_consumer_policy: connect::SlowConsumerPolicy,
+    /// Max number of open subscriptions per connection.
+    pub max_subscriptions: usize,
+    /// Max number of filters in a REQ or COUNT.
+    pub max_filters: usize,
+    pub max_subid_length: usize,
+    pub max_event_tags: usize,
+    /// Max size in bytes of the content of an event.
+    pub max_content_length: usize,
+    /// Rate at which events are accepted from a single pubkey.
+    pub pubkey_event_rate: Option<rate_limit::RateLimit>,
+    /// Rate at which events are accepted from a single IP address, across
+    /// all instances.
+    pub ip_event_rate: Option<rate_limit::RateLimit>,
+    /// Max number of events a NIP-77 reconciliation can be done over.
+    pub max_negentropy_items: usize,
+}
+
+impl Limits {
+    /// The proof-of-work difficulty required of events of the kind.
+    pub fn pow_difficulty_for(&self, kind: u16) -> u8 {
+        self.kind_pow_difficulty
+            .iter()
+            .find(|(range, _)| range.contains(&kind))
+            .map(|(_, difficulty)| *difficulty)
+            .unwrap_or(self.min_pow_difficulty)
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_message_length: 128 * 1024,
+            max_limit: 100,
+            min_pow_difficulty: 0,
+            kind_pow_difficulty: vec![],
+            outbox_capacity: 1024,
+            slow_consumer_policy: default(),
+            max_subscriptions: 20,
+            max_filters: 10,
+            max_subid_length: 64,
+            max_event_tags: 2000,
+            max_content_length: 64 * 1024,
+            pubkey_event_rate: None,
+            ip_event_rate: None,
+            max_negentropy_items: 100_000,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Context {
     pub config: Config,
-    pub db: Db,
-    pub redis: RedisPool,
+    pub store: Box<dyn store::EventStore>,
+    /// Only needed to share the hose and the rate limits across instances.
+    pub redis: Option<RedisPool>,
+    pub hose: Box<dyn hose::EventHose>,
+    /// Stand in for redis in rate limiting.
+    pub rate_counters: rate_limit::LocalCounters,
     pub sw: connect::Switchboard,
-}
-
-#[derive(Debug)]
-pub enum Db {
-    Pg { db_pool: sqlx::postgres::PgPool },
+    pub manage: manage::Overrides,
 }
 
 pub type SharedContext = std::sync::Arc<Context>;
@@ -82,84 +193,140 @@ impl axum::extract::FromRef<SharedContext> for SharedServiceContext {
         Self(ServiceContext(input.clone()))
     }
 }
-// shadow_rs::shadow!(build);
+shadow_rs::shadow!(build);
 
 pub fn router(state: SharedContext) -> axum::Router {
     axum::Router::new()
-        .route("/", axum::routing::get(connect::handler))
+        .route(
+            "/",
+            axum::routing::get(connect::handler).post(manage::handler),
+        )
+        .route(
+            "/openapi.json",
+            axum::routing::get(
+                |axum::extract::OriginalUri(uri): axum::extract::OriginalUri| async move {
+                    // documents the paths under wherever the router's nested
+                    let prefix = uri.path().strip_suffix("/openapi.json").unwrap_or_default();
+                    Json(ApiDoc::at(prefix))
+                },
+            ),
+        )
+        .route(
+            "/asyncapi.json",
+            axum::routing::get(|| async { Json(asyncapi::document()) }),
+        )
         .with_state(state)
 }
 
-/*
 use utoipa::openapi;
 pub struct ApiDoc;
 impl utoipa::OpenApi for ApiDoc {
     fn openapi() -> openapi::OpenApi {
-        //
-        let mut openapi = openapi::OpenApiBuilder::new()
+        Self::at("")
+    }
+}
+
+impl ApiDoc {
+    /// The document for the [`router`] nested under `prefix`.
+    pub fn at(prefix: &str) -> openapi::OpenApi {
+        let doc = |description: &str| {
+            openapi::PathItem::new(
+                openapi::PathItemType::Get,
+                openapi::path::OperationBuilder::new()
+                    .tag(common::DEFAULT_TAG.name)
+                    .response(
+                        "200",
+                        openapi::ResponseBuilder::new()
+                            .description(description)
+                            .content(
+                                "application/json",
+                                openapi::Content::new(openapi::Object::new()),
+                            ),
+                    ),
+            )
+        };
+        openapi::OpenApiBuilder::new()
             .info(
                 openapi::InfoBuilder::new()
                     .title("qtrunk_api")
-                    // .version(build::PKG_VERSION)
-                    .description(Some(format!(
-                        r#"{}
-                        "#,
-                        "qtrunk is an experiment"
-                    )))
+                    .version(build::PKG_VERSION)
+                    .description(Some(
+                        "qtrunk is a nostr relay. The messages exchanged over its \
+                        websocket are described by the AsyncAPI document.",
+                    ))
                     .build(),
             )
             .paths({
                 let builder = openapi::path::PathsBuilder::new();
-                // let builder = gram::paths(builder, "/qtrunk"); //FIXME: make this dyamic
+                let builder = builder
+                    .path(
+                        format!("{prefix}/"),
+                        openapi::path::PathItemBuilder::new()
+                            .operation(openapi::PathItemType::Get, connect::operation())
+                            .operation(openapi::PathItemType::Post, manage::operation())
+                            .build(),
+                    )
+                    .path(format!("{prefix}/openapi.json"), doc("This document"))
+                    .path(
+                        format!("{prefix}/asyncapi.json"),
+                        doc("AsyncAPI document of the websocket messages"),
+                    );
                 builder.build()
             })
             .components(Some({
                 let builder = openapi::ComponentsBuilder::new();
-                let builder = builder
-                    .schema(
-                        "std.net.IpAddr",
-                        openapi::ObjectBuilder::new()
-                            .schema_type(openapi::SchemaType::String)
-                            .format(Some(openapi::SchemaFormat::Custom("ipaddr".into()))),
-                    )
-                    .schemas_from_iter([
-                        <common::utils::SortingOrder as utoipa::ToSchema>::schema(),
-                        <common::utils::ValidationErrors as utoipa::ToSchema>::schema(),
-                        <common::utils::ValidationErrorsKind as utoipa::ToSchema>::schema(),
-                        <common::utils::ValidationError as utoipa::ToSchema>::schema(),
-                    ]);
-                // let builder = gram::components(builder);
+                let builder = builder.schemas_from_iter([
+                    <event::Event as utoipa::ToSchema>::schema(),
+                    <event::Filter as utoipa::ToSchema>::schema(),
+                    <info::RelayInformationDocument as utoipa::ToSchema>::schema(),
+                    <info::Limitation as utoipa::ToSchema>::schema(),
+                    <manage::Request as utoipa::ToSchema>::schema(),
+                ]);
                 builder.build()
             }))
-            // .tags(Some([gram::TAG.into(), common::DEFAULT_TAG.into()]))
-            .build();
-        if let Some(components) = openapi.components.as_mut() {
-            use utoipa::openapi::security::*;
-            components.add_security_scheme(
-                "bearer",
-                SecurityScheme::Http(openapi::security::Http::new(
-                    openapi::security::HttpAuthScheme::Bearer,
-                )),
-            )
-        }
-        openapi
+            .tags(Some([
+                connect::TAG.into(),
+                manage::TAG.into(),
+                common::DEFAULT_TAG.into(),
+            ]))
+            .build()
     }
-}*/
-
-#[async_trait::async_trait]
-pub trait Client {}
-
-pub struct InProcClient {
-    pub cx: SharedContext,
 }
 
-#[async_trait::async_trait]
-impl Client for InProcClient {}
-
-pub struct HttpClient {}
-
-#[async_trait::async_trait]
-impl Client for HttpClient {}
+#[tokio::test]
+async fn openapi_paths_follow_the_mount() {
+    use tower::ServiceExt;
+    let (testing, cx) = utils::testing::cx_fn(common::function_full!()).await;
+    {
+        for (prefix, expected) in [("", "/"), ("/qtrunk", "/qtrunk/")] {
+            let app = if prefix.is_empty() {
+                router(cx.clone())
+            } else {
+                axum::Router::new().nest(prefix, router(cx.clone()))
+            };
+            let resp = app
+                .oneshot(
+                    http::Request::builder()
+                        .uri(format!("{prefix}/openapi.json"))
+                        .body(Default::default())
+                        .unwrap_or_log(),
+                )
+                .await
+                .unwrap_or_log();
+            assert_eq!(resp.status(), http::StatusCode::OK);
+            let body = hyper::body::to_bytes(resp.into_body())
+                .await
+                .unwrap_or_log();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap_or_log();
+            assert!(body["paths"][expected].is_object(), "{prefix:?}: {body}");
+            assert!(
+                body["paths"][format!("{prefix}/asyncapi.json")].is_object(),
+                "{prefix:?}: {body}"
+            );
+        }
+    }
+    testing.close().await;
+}
 
 #[test]
 #[ignore]
@@ -0,0 +1,960 @@
+//! NIP-86 relay management API, authorized through NIP-98 HTTP auth.
+
+use crate::interlude::*;
+
+use std::collections::HashSet;
+
+use axum::extract::State;
+use serde_json::Value;
+
+use crate::auth::tag_value;
+use crate::event::Event;
+
+pub const MEDIA_TYPE: &str = "application/nostr+json+rpc";
+
+/// NIP-98 HTTP auth event kind.
+pub const HTTP_AUTH_KIND: u16 = 27235;
+
+/// How far off from now an HTTP auth event's `created_at` is allowed to be.
+pub const HTTP_AUTH_WINDOW: time::Duration = time::Duration::seconds(60);
+
+pub const TAG: common::Tag = common::Tag {
+    name: "manage",
+    desc: "NIP-86 relay management.",
+};
+
+pub const SUPPORTED_METHODS: &[&str] = &[
+    "supportedmethods",
+    "banpubkey",
+    "allowpubkey",
+    "banevent",
+    "listbannedpubkeys",
+    "listbannedevents",
+    "changerelayname",
+    "listarchivedevents",
+];
+
+/// The bans and settings overrides, cached so that the switchboard can
+/// check events without going to the db.
+#[derive(Debug, Default)]
+pub struct Overrides {
+    banned_pubkeys: std::sync::RwLock<HashSet<String>>,
+    banned_events: std::sync::RwLock<HashSet<String>>,
+    relay_name: std::sync::RwLock<Option<String>>,
+}
+
+impl Overrides {
+    pub fn is_banned(&self, event: &Event) -> bool {
+        self.banned_pubkeys
+            .read()
+            .unwrap_or_log()
+            .contains(&event.pubkey.to_ascii_lowercase())
+            || self
+                .banned_events
+                .read()
+                .unwrap_or_log()
+                .contains(&event.id.to_ascii_lowercase())
+    }
+
+    /// The name set through `changerelayname`, if any.
+    pub fn relay_name(&self) -> Option<String> {
+        self.relay_name.read().unwrap_or_log().clone()
+    }
+
+    pub fn apply(&self, change: &Change) {
+        match change {
+            Change::BanPubkey { pubkey } => {
+                self.banned_pubkeys
+                    .write()
+                    .unwrap_or_log()
+                    .insert(pubkey.clone());
+            }
+            Change::AllowPubkey { pubkey } => {
+                self.banned_pubkeys.write().unwrap_or_log().remove(pubkey);
+            }
+            Change::BanEvent { id } => {
+                self.banned_events
+                    .write()
+                    .unwrap_or_log()
+                    .insert(id.clone());
+            }
+            Change::ChangeRelayName { name } => {
+                *self.relay_name.write().unwrap_or_log() = Some(name.clone());
+            }
+        }
+    }
+}
+
+/// A change to the [`Overrides`], carried over the event hose so that it
+/// takes effect on all instances.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "serde", rename_all = "camelCase", tag = "change")]
+pub enum Change {
+    BanPubkey { pubkey: String },
+    AllowPubkey { pubkey: String },
+    BanEvent { id: String },
+    ChangeRelayName { name: String },
+}
+
+/// Applies the change here and hands it to the other instances.
+async fn change(cx: &Context, change: Change) {
+    cx.manage.apply(&change);
+    if let Err(err) = cx.hose.publish_change(&change).await {
+        // the store's up to date so other instances catch up on restart
+        warn!(?err, ?change, "error publishing overrides change");
+    }
+}
+
+/// Fills the cache from the store. Runs alongside the switchboard.
+pub async fn load_overrides(cx: &Context) -> eyre::Result<()> {
+    let pubkeys = cx.store.banned_pubkeys().await;
+    let events = cx.store.banned_events().await;
+    let relay_name = cx
+        .store
+        .setting(RELAY_NAME_KEY)
+        .await
+        .and_then(|value| value.as_str().map(String::from));
+    let overrides = &cx.manage;
+    // extended and not replaced to not lose bans placed while loading
+    overrides
+        .banned_pubkeys
+        .write()
+        .unwrap_or_log()
+        .extend(pubkeys.into_iter().map(|ban| ban.key));
+    overrides
+        .banned_events
+        .write()
+        .unwrap_or_log()
+        .extend(events.into_iter().map(|ban| ban.key));
+    if let Some(name) = relay_name {
+        overrides
+            .relay_name
+            .write()
+            .unwrap_or_log()
+            .get_or_insert(name);
+    }
+    Ok(())
+}
+
+/// Key of the `changerelayname` override in the store's settings.
+const RELAY_NAME_KEY: &str = "relay_name";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("missing Nostr authorization header")]
+    Missing,
+    #[error("malformed authorization event")]
+    Malformed,
+    #[error("auth events must be of kind {HTTP_AUTH_KIND}")]
+    WrongKind,
+    #[error("created_at is too far off from the current time")]
+    Stale,
+    #[error("u tag doesn't match this relay")]
+    WrongUrl,
+    #[error("method tag doesn't match the request")]
+    WrongMethod,
+    #[error("payload tag doesn't match the request body")]
+    WrongPayload,
+    #[error("invalid auth event: {issues}")]
+    InvalidInput { issues: ValidationErrors },
+    #[error("pubkey is not a relay admin")]
+    NotAdmin,
+}
+
+/// Checks the NIP-98 `Authorization` header of a management request,
+/// returning the admin's pubkey.
+pub fn authorize(
+    relay_url: &str,
+    admin_pubkeys: &[String],
+    headers: &http::HeaderMap,
+    body: &[u8],
+) -> Result<String, AuthError> {
+    let header = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.strip_prefix("Nostr "))
+        .ok_or(AuthError::Missing)?;
+    let event: Event = data_encoding::BASE64
+        .decode(header.trim().as_bytes())
+        .ok()
+        .and_then(|buf| serde_json::from_slice(&buf[..]).ok())
+        .ok_or(AuthError::Malformed)?;
+    if event.kind != HTTP_AUTH_KIND {
+        return Err(AuthError::WrongKind);
+    }
+    if (OffsetDateTime::now_utc() - event.created_at).abs() > HTTP_AUTH_WINDOW {
+        return Err(AuthError::Stale);
+    }
+    // the websocket's url over http
+    let relay_url = crate::auth::normalize_url(relay_url);
+    let http_url = relay_url
+        .strip_prefix("ws")
+        .map(|rest| format!("http{rest}"))
+        .unwrap_or_else(|| relay_url.clone());
+    match tag_value(&event, "u").map(crate::auth::normalize_url) {
+        Some(url) if url == http_url || url == relay_url => {}
+        _ => return Err(AuthError::WrongUrl),
+    }
+    match tag_value(&event, "method") {
+        Some(method) if method.eq_ignore_ascii_case("POST") => {}
+        _ => return Err(AuthError::WrongMethod),
+    }
+    let payload = {
+        use k256::sha2::*;
+        data_encoding::HEXLOWER.encode(&Sha256::digest(body)[..])
+    };
+    match tag_value(&event, "payload") {
+        Some(hash) if hash.eq_ignore_ascii_case(&payload) => {}
+        _ => return Err(AuthError::WrongPayload),
+    }
+    crate::event::create::validate_request(&event)
+        .map_err(|err| AuthError::InvalidInput { issues: err.into() })?;
+    if !admin_pubkeys
+        .iter()
+        .any(|pubkey| pubkey.eq_ignore_ascii_case(&event.pubkey))
+    {
+        return Err(AuthError::NotAdmin);
+    }
+    Ok(event.pubkey)
+}
+
+#[derive(Debug, Clone)]
+pub struct Manage;
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(crate = "serde")]
+#[schema(as = ManageRequest)]
+pub struct Request {
+    pub method: String,
+    #[serde(default)]
+    #[schema(value_type = Vec<Object>)]
+    pub params: Vec<Value>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "serde")]
+pub struct Response {
+    pub result: Value,
+}
+
+#[derive(Debug, Serialize, thiserror::Error)]
+#[serde(crate = "serde", rename_all = "camelCase", tag = "error")]
+pub enum Error {
+    #[error("unsupported method: {method}")]
+    UnsupportedMethod { method: String },
+    #[error("invalid params: {message}")]
+    InvalidParams { message: String },
+}
+
+impl Error {
+    pub fn to_rpc_response(&self) -> Value {
+        json!({ "result": null, "error": format!("{self}") })
+    }
+}
+
+/// The hex encoded 32 byte value at the index of the params, lowercased.
+fn hex_param(params: &[Value], idx: usize, name: &str) -> Result<String, Error> {
+    params
+        .get(idx)
+        .and_then(|val| val.as_str())
+        .and_then(|val| {
+            data_encoding::HEXLOWER_PERMISSIVE
+                .decode(val.as_bytes())
+                .ok()
+        })
+        .filter(|buf| buf.len() == 32)
+        .map(|buf| data_encoding::HEXLOWER.encode(&buf))
+        .ok_or_else(|| Error::InvalidParams {
+            message: format!("expecting a hex encoded {name} at position {idx}"),
+        })
+}
+
+/// The optional reason at the index of the params.
+fn reason_param(params: &[Value], idx: usize) -> Option<String> {
+    params
+        .get(idx)
+        .and_then(|val| val.as_str())
+        .filter(|val| !val.is_empty())
+        .map(String::from)
+}
+
+#[async_trait::async_trait]
+impl Endpoint for Manage {
+    type Request = Request;
+    type Response = Response;
+    type Error = Error;
+    type Cx = Context;
+
+    #[tracing::instrument(skip(cx), err)]
+    async fn handle(
+        &self,
+        cx: &Self::Cx,
+        request: Self::Request,
+    ) -> Result<Self::Response, Self::Error> {
+        let Request { method, params } = request;
+        let result = match &method[..] {
+            "supportedmethods" => json!(SUPPORTED_METHODS),
+            "banpubkey" => {
+                let pubkey = hex_param(&params, 0, "pubkey")?;
+                cx.store
+                    .ban_pubkey(&pubkey, reason_param(&params, 1).as_deref())
+                    .await;
+                change(cx, Change::BanPubkey { pubkey }).await;
+                json!(true)
+            }
+            // lifts bans, the relay doesn't otherwise keep an allow list
+            "allowpubkey" => {
+                let pubkey = hex_param(&params, 0, "pubkey")?;
+                cx.store.unban_pubkey(&pubkey).await;
+                change(cx, Change::AllowPubkey { pubkey }).await;
+                json!(true)
+            }
+            "banevent" => {
+                let id = hex_param(&params, 0, "event id")?;
+                cx.store
+                    .ban_event(&id, reason_param(&params, 1).as_deref())
+                    .await;
+                change(cx, Change::BanEvent { id }).await;
+                json!(true)
+            }
+            "listbannedpubkeys" => cx
+                .store
+                .banned_pubkeys()
+                .await
+                .into_iter()
+                .map(|ban| json!({ "pubkey": ban.key, "reason": ban.reason }))
+                .collect(),
+            "listbannedevents" => cx
+                .store
+                .banned_events()
+                .await
+                .into_iter()
+                .map(|ban| json!({ "id": ban.key, "reason": ban.reason }))
+                .collect(),
+            "changerelayname" => {
+                let name = params
+                    .get(0)
+                    .and_then(|val| val.as_str())
+                    .filter(|val| !val.trim().is_empty())
+                    .ok_or_else(|| Error::InvalidParams {
+                        message: "expecting a name at position 0".into(),
+                    })?;
+                cx.store.set_setting(RELAY_NAME_KEY, json!(name)).await;
+                change(
+                    cx,
+                    Change::ChangeRelayName {
+                        name: name.to_string(),
+                    },
+                )
+                .await;
+                json!(true)
+            }
+            // superseded versions of the replaceable events under an
+            // address, newest first
+            "listarchivedevents" => {
+                let pubkey = hex_param(&params, 0, "pubkey")?;
+                let kind = params
+                    .get(1)
+                    .and_then(|val| val.as_u64())
+                    .and_then(|val| u16::try_from(val).ok())
+                    .filter(|kind| {
+                        crate::event::is_replaceable(*kind)
+                            || crate::event::is_parameterized_replaceable(*kind)
+                    })
+                    .ok_or_else(|| Error::InvalidParams {
+                        message: "expecting a replaceable kind at position 1".into(),
+                    })?;
+                let d_tag = params
+                    .get(2)
+                    .and_then(|val| val.as_str())
+                    .unwrap_or_default();
+                json!(
+                    cx.store
+                        .archived(&pubkey, kind, d_tag, cx.config.limits.max_limit)
+                        .await
+                )
+            }
+            _ => return Err(Error::UnsupportedMethod { method }),
+        };
+        Ok(Response { result })
+    }
+}
+
+/// Serves NIP-86 calls POSTed to the relay's url.
+pub async fn handler(
+    State(cx): State<SharedContext>,
+    headers: http::HeaderMap,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    use http::header::*;
+    let admin = match authorize(
+        &cx.config.relay_url,
+        &cx.config.admin_pubkeys,
+        &headers,
+        &body[..],
+    ) {
+        Ok(admin) => admin,
+        Err(err) => {
+            debug!(?err, "unauthorized management request");
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(CONTENT_TYPE, MEDIA_TYPE)],
+                json!({ "result": null, "error": format!("unauthorized: {err}") }).to_string(),
+            )
+                .into_response();
+        }
+    };
+    let resp = match serde_json::from_slice::<Request>(&body[..]) {
+        Ok(request) => {
+            info!(%admin, method = %request.method, "management call");
+            match Manage.handle(&cx, request).await {
+                Ok(Response { result }) => json!({ "result": result }),
+                Err(err) => err.to_rpc_response(),
+            }
+        }
+        Err(err) => Error::InvalidParams {
+            message: format!("malformed request: {err}"),
+        }
+        .to_rpc_response(),
+    };
+    ([(CONTENT_TYPE, MEDIA_TYPE)], resp.to_string()).into_response()
+}
+
+/// OpenAPI operation for [`handler`].
+pub fn operation() -> utoipa::openapi::path::Operation {
+    use utoipa::openapi::*;
+    let reply = Schema::Object(
+        ObjectBuilder::new()
+            .property("result", Object::new())
+            .required("result")
+            .property(
+                "error",
+                ObjectBuilder::new()
+                    .schema_type(SchemaType::String)
+                    .description(Some("Set, with a null `result`, if the call failed.")),
+            )
+            .build(),
+    );
+    path::OperationBuilder::new()
+        .operation_id(Some("manage"))
+        .tag(TAG.name)
+        .summary(Some("Call a NIP-86 management method"))
+        .description(Some(format!(
+            "Only for the relay's admins, authorized by a NIP-98 `Authorization` header \
+            that covers the body. Supported methods: {}.",
+            SUPPORTED_METHODS.join(", ")
+        )))
+        .request_body(Some(
+            request_body::RequestBodyBuilder::new()
+                .content(
+                    MEDIA_TYPE,
+                    ContentBuilder::new()
+                        .schema(Ref::from_schema_name("ManageRequest"))
+                        .example(Some(json!({ "method": "banpubkey", "params": [
+                            "7ecee90e906e56d7b20b2e76cdb83b786352d2bea53495e34ad556a989f7d39b",
+                            "spam"
+                        ] })))
+                        .build(),
+                )
+                .required(Some(Required::True))
+                .build(),
+        ))
+        .response(
+            "200",
+            ResponseBuilder::new()
+                .description("Result of the call")
+                .content(
+                    MEDIA_TYPE,
+                    ContentBuilder::new().schema(reply.clone()).build(),
+                ),
+        )
+        .response(
+            "401",
+            ResponseBuilder::new()
+                .description("Missing or invalid auth")
+                .content(MEDIA_TYPE, ContentBuilder::new().schema(reply).build()),
+        )
+        .build()
+}
+
+#[cfg(test)]
+pub mod testing {
+    use crate::interlude::*;
+
+    use crate::event::Event;
+
+    /// A signed NIP-98 auth event for the request encoded for the
+    /// `Authorization` header.
+    pub fn http_auth_header(
+        privkey: &str,
+        url: &str,
+        method: &str,
+        body: &[u8],
+        created_at: OffsetDateTime,
+    ) -> String {
+        use k256::sha2::*;
+        let privkey = data_encoding::HEXLOWER.decode(privkey.as_bytes()).unwrap();
+        let privkey = k256::schnorr::SigningKey::from_bytes(&privkey[..]).unwrap();
+        let pubkey = data_encoding::HEXLOWER.encode(&privkey.verifying_key().to_bytes()[..]);
+        let tags = vec![
+            vec!["u".to_string(), url.to_string()],
+            vec!["method".to_string(), method.to_string()],
+            vec![
+                "payload".to_string(),
+                data_encoding::HEXLOWER.encode(&Sha256::digest(body)[..]),
+            ],
+        ];
+        let (id, sig) = crate::event::hex_id_and_sig_for_event(
+            &privkey,
+            &pubkey[..],
+            created_at,
+            super::HTTP_AUTH_KIND,
+            &tags,
+            "",
+        );
+        let event = Event {
+            id,
+            pubkey,
+            created_at,
+            kind: super::HTTP_AUTH_KIND,
+            tags,
+            content: "".into(),
+            sig,
+        };
+        format!(
+            "Nostr {}",
+            data_encoding::BASE64.encode(&serde_json::to_vec(&event).unwrap()[..])
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use super::testing::*;
+    use crate::event::testing::*;
+    use crate::event::Event;
+
+    use tower::ServiceExt;
+
+    const URL: &str = "https://qtrunk.test";
+    const BODY: &[u8] = br#"{"method":"supportedmethods","params":[]}"#;
+
+    common::table_tests! {
+        authorize,
+        (header, body, expected_err),
+        {
+            common::utils::testing::setup_tracing_once();
+            let mut headers = http::HeaderMap::new();
+            if let Some(header) = header {
+                let header: String = header;
+                headers.insert(http::header::AUTHORIZATION, header.parse().unwrap());
+            }
+            let res = crate::manage::authorize(
+                "wss://qtrunk.test",
+                &[pubkey_of(EVENT_05_PRIVKEY)],
+                &headers,
+                body,
+            );
+            let expected_err: Option<&str> = expected_err;
+            match (res, expected_err) {
+                (Ok(pubkey), None) => assert_eq!(pubkey, pubkey_of(EVENT_05_PRIVKEY)),
+                (Err(err), Some(expected)) => {
+                    assert!(format!("{err:?}").starts_with(expected), "unexpected err: {err:?}");
+                }
+                (res, _) => panic!("unexpected result: {res:?}"),
+            }
+        }
+    }
+
+    authorize! {
+        works: (
+            Some(http_auth_header(EVENT_05_PRIVKEY, URL, "POST", BODY, OffsetDateTime::now_utc())),
+            BODY,
+            None,
+        ),
+        accepts_ws_url: (
+            Some(http_auth_header(EVENT_05_PRIVKEY, "wss://qtrunk.test/", "POST", BODY, OffsetDateTime::now_utc())),
+            BODY,
+            None,
+        ),
+        rejects_missing_header: (None, BODY, Some("Missing")),
+        rejects_malformed_event: (Some("Nostr bm90IGFuIGV2ZW50".into()), BODY, Some("Malformed")),
+        rejects_wrong_url: (
+            Some(http_auth_header(EVENT_05_PRIVKEY, "https://other.relay", "POST", BODY, OffsetDateTime::now_utc())),
+            BODY,
+            Some("WrongUrl"),
+        ),
+        rejects_wrong_method: (
+            Some(http_auth_header(EVENT_05_PRIVKEY, URL, "GET", BODY, OffsetDateTime::now_utc())),
+            BODY,
+            Some("WrongMethod"),
+        ),
+        rejects_wrong_payload: (
+            Some(http_auth_header(EVENT_05_PRIVKEY, URL, "POST", b"{}", OffsetDateTime::now_utc())),
+            BODY,
+            Some("WrongPayload"),
+        ),
+        rejects_stale: (
+            Some(http_auth_header(
+                EVENT_05_PRIVKEY,
+                URL,
+                "POST",
+                BODY,
+                OffsetDateTime::now_utc() - time::Duration::minutes(2)
+            )),
+            BODY,
+            Some("Stale"),
+        ),
+        rejects_non_admins: (
+            Some(http_auth_header(EVENT_01_PRIVKEY, URL, "POST", BODY, OffsetDateTime::now_utc())),
+            BODY,
+            Some("NotAdmin"),
+        ),
+    }
+
+    async fn call(cx: &SharedContext, privkey: &str, body: Value) -> (StatusCode, Value) {
+        let body = serde_json::to_vec(&body).unwrap();
+        let resp = crate::router(cx.clone())
+            .layer(axum::extract::connect_info::MockConnectInfo(
+                std::net::SocketAddr::from(([127, 0, 0, 1], 19003)),
+            ))
+            .oneshot(
+                http::Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .header(http::header::CONTENT_TYPE, super::MEDIA_TYPE)
+                    .header(
+                        http::header::AUTHORIZATION,
+                        http_auth_header(
+                            privkey,
+                            URL,
+                            "POST",
+                            &body[..],
+                            OffsetDateTime::now_utc(),
+                        ),
+                    )
+                    .body(body.into())
+                    .unwrap_or_log(),
+            )
+            .await
+            .unwrap_or_log();
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body())
+            .await
+            .unwrap_or_log();
+        (status, serde_json::from_slice(&body).unwrap_or_log())
+    }
+
+    use serde_json::Value;
+
+    async fn create_ok(cx: &Context, event: Event) -> Value {
+        match crate::event::create::CreateEvent.handle(cx, event).await {
+            Ok(value) => value.to_nostr_ok(),
+            Err(value) => value.to_nostr_ok(),
+        }
+    }
+
+    #[tokio::test]
+    async fn bans() {
+        let (testing, cx) =
+            crate::utils::testing::cx_fn_with_config(common::function_full!(), |config| {
+                config.admin_pubkeys = vec![pubkey_of(EVENT_05_PRIVKEY)];
+            })
+            .await;
+        {
+            let (status, _) = call(
+                &cx,
+                EVENT_01_PRIVKEY,
+                json!({ "method": "banpubkey", "params": [EVENT_01.pubkey] }),
+            )
+            .await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+            let (status, resp) = call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "banpubkey", "params": [EVENT_04.pubkey, "spam"] }),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            check_json(
+                ("expected", &json!({ "result": true })),
+                ("response", &resp),
+            );
+            assert!(cx.manage.is_banned(&EVENT_04));
+            let (_, resp) = call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "listbannedpubkeys", "params": [] }),
+            )
+            .await;
+            check_json(
+                (
+                    "expected",
+                    &json!({ "result": [{ "pubkey": EVENT_04.pubkey, "reason": "spam" }] }),
+                ),
+                ("response", &resp),
+            );
+            // EVENT_04 is already stored so it gets rejected for being a
+            // duplicate if the ban's not checked first
+            check_json(
+                (
+                    "expected",
+                    &json!(["OK", EVENT_04.id, false, "blocked: pubkey is banned"]),
+                ),
+                ("response", &create_ok(&cx, EVENT_04.clone()).await),
+            );
+
+            let (_, resp) = call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "allowpubkey", "params": [EVENT_04.pubkey] }),
+            )
+            .await;
+            check_json(
+                ("expected", &json!({ "result": true })),
+                ("response", &resp),
+            );
+            assert!(!cx.manage.is_banned(&EVENT_04));
+            check_json(
+                (
+                    "expected",
+                    &json!([
+                        "OK",
+                        EVENT_04.id,
+                        false,
+                        "duplicate: event already recieved"
+                    ]),
+                ),
+                ("response", &create_ok(&cx, EVENT_04.clone()).await),
+            );
+
+            let (_, resp) = call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "banevent", "params": [EVENT_01.id, "rude"] }),
+            )
+            .await;
+            check_json(
+                ("expected", &json!({ "result": true })),
+                ("response", &resp),
+            );
+            assert!(cx.manage.is_banned(&EVENT_01));
+            let filter = serde_json::from_value(json!([{ "ids": [EVENT_01.id] }])).unwrap();
+            let listed = crate::event::list::ListEvents
+                .handle(&cx, filter)
+                .await
+                .unwrap();
+            assert!(listed.is_empty(), "{listed:?}");
+            check_json(
+                (
+                    "expected",
+                    &json!(["OK", EVENT_01.id, false, "blocked: event is banned"]),
+                ),
+                ("response", &create_ok(&cx, EVENT_01.clone()).await),
+            );
+            let (_, resp) = call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "listbannedevents", "params": [] }),
+            )
+            .await;
+            check_json(
+                (
+                    "expected",
+                    &json!({ "result": [{ "id": EVENT_01.id, "reason": "rude" }] }),
+                ),
+                ("response", &resp),
+            );
+
+            // the cache is rebuilt from the db
+            let fresh = crate::utils::testing::state_fn(&testing);
+            super::load_overrides(&fresh).await.unwrap();
+            assert!(fresh.manage.is_banned(&EVENT_01));
+            assert!(!fresh.manage.is_banned(&EVENT_04));
+        }
+        testing.close().await;
+    }
+
+    /// Waits for the condition to hold.
+    async fn eventually(cond: impl Fn() -> bool) {
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !cond() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition never held");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bans_reach_other_instances() {
+        let (testing, [cx, other]) =
+            crate::utils::testing::instances_fn(common::function_full!(), |config| {
+                config.admin_pubkeys = vec![pubkey_of(EVENT_05_PRIVKEY)];
+            })
+            .await;
+        {
+            // changes are only seen once the other's switchboard is following
+            let ready = other
+                .sw
+                .test_subscriber(serde_json::from_value(json!([{ "ids": [EVENT_03.id] }])).unwrap())
+                .await;
+            tokio::time::timeout(std::time::Duration::from_secs(5), async {
+                loop {
+                    cx.hose.publish(&EVENT_03).await.unwrap();
+                    let wait = std::time::Duration::from_millis(100);
+                    if tokio::time::timeout(wait, ready.pop()).await.is_ok() {
+                        break;
+                    }
+                }
+            })
+            .await
+            .expect("other instance isn't following the hose");
+            let outbox = other
+                .sw
+                .test_subscriber(
+                    serde_json::from_value(json!([{ "authors": [EVENT_01.pubkey] }])).unwrap(),
+                )
+                .await;
+
+            let (_, resp) = call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "banpubkey", "params": [EVENT_01.pubkey] }),
+            )
+            .await;
+            check_json(
+                ("expected", &json!({ "result": true })),
+                ("response", &resp),
+            );
+            eventually(|| other.manage.is_banned(&EVENT_01)).await;
+            // not delivered by the other's switchboard
+            cx.hose.publish(&EVENT_01).await.unwrap();
+
+            call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "allowpubkey", "params": [EVENT_01.pubkey] }),
+            )
+            .await;
+            eventually(|| !other.manage.is_banned(&EVENT_01)).await;
+            cx.hose.publish(&EVENT_01).await.unwrap();
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), outbox.pop())
+                .await
+                .expect("event wasn't delivered after the ban was lifted")
+                .unwrap();
+            check_json(
+                ("expected", &json!(["EVENT", "sub", { "id": EVENT_01.id }])),
+                ("response", &msg),
+            );
+            assert!(
+                tokio::time::timeout(std::time::Duration::from_millis(200), outbox.pop())
+                    .await
+                    .is_err(),
+                "event was delivered while banned"
+            );
+
+            call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "banevent", "params": [EVENT_04.id] }),
+            )
+            .await;
+            eventually(|| other.manage.is_banned(&EVENT_04)).await;
+        }
+        testing.close().await;
+    }
+
+    #[tokio::test]
+    async fn methods() {
+        let (testing, cx) =
+            crate::utils::testing::cx_fn_with_config(common::function_full!(), |config| {
+                config.admin_pubkeys = vec![pubkey_of(EVENT_05_PRIVKEY)];
+            })
+            .await;
+        {
+            let (_, resp) = call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "supportedmethods", "params": [] }),
+            )
+            .await;
+            check_json(
+                ("expected", &json!({ "result": super::SUPPORTED_METHODS })),
+                ("response", &resp),
+            );
+            let (_, resp) = call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "changerelayname", "params": ["trunk of q"] }),
+            )
+            .await;
+            check_json(
+                ("expected", &json!({ "result": true })),
+                ("response", &resp),
+            );
+            assert_eq!(cx.manage.relay_name().as_deref(), Some("trunk of q"));
+            let doc = crate::info::RelayInformationDocument::new(&cx);
+            assert_eq!(doc.name, "trunk of q");
+
+            let (_, resp) = call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "listarchivedevents", "params": [EVENT_01.pubkey, 0] }),
+            )
+            .await;
+            check_json(("expected", &json!({ "result": [] })), ("response", &resp));
+            let (_, resp) = call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "listarchivedevents", "params": [EVENT_01.pubkey, 1] }),
+            )
+            .await;
+            check_json(
+                (
+                    "expected",
+                    &json!({
+                        "result": null,
+                        "error": "invalid params: expecting a replaceable kind at position 1"
+                    }),
+                ),
+                ("response", &resp),
+            );
+
+            let (status, resp) = call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "banpubkey", "params": ["not hex"] }),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            check_json(
+                (
+                    "expected",
+                    &json!({
+                        "result": null,
+                        "error": "invalid params: expecting a hex encoded pubkey at position 0"
+                    }),
+                ),
+                ("response", &resp),
+            );
+            let (_, resp) = call(
+                &cx,
+                EVENT_05_PRIVKEY,
+                json!({ "method": "listallowedpubkeys", "params": [] }),
+            )
+            .await;
+            check_json(
+                (
+                    "expected",
+                    &json!({ "result": null, "error": "unsupported method: listallowedpubkeys" }),
+                ),
+                ("response", &resp),
+            );
+        }
+        testing.close().await;
+    }
+}
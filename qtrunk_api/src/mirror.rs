@@ -0,0 +1,326 @@
+//! Pulls events from upstream relays. Each upstream gets a websocket with
+//! a live subscription for new events and a backfill of those created since
+//! the last time it was mirrored, paged through with `until`. Pulled events
+//! get stored as if they'd been published to the relay.
+//!
+//! How far each upstream's been mirrored is kept in the store so that
+//! restarts resume where they left off.
+
+use crate::interlude::*;
+
+use futures::{SinkExt, StreamExt};
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::Message as WsMsg;
+
+use crate::event::{Event, Filter};
+
+const LIVE_SUB: &str = "mirror-live";
+const BACKFILL_SUB: &str = "mirror-backfill";
+const MIN_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+const MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// A relay to pull the events matching `filters` from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "serde")]
+pub struct Upstream {
+    pub url: String,
+    pub filters: Vec<Filter>,
+}
+
+/// Mirrors all the configured upstreams. Never returns if there are any.
+pub async fn start_mirroring(cx: SharedContext) -> eyre::Result<()> {
+    futures::future::join_all(
+        cx.config
+            .upstreams
+            .iter()
+            .map(|upstream| mirror(&cx, upstream)),
+    )
+    .await;
+    Ok(())
+}
+
+#[tracing::instrument(skip(cx, upstream), fields(url = %upstream.url))]
+async fn mirror(cx: &Context, upstream: &Upstream) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let err = match follow(cx, upstream, &mut backoff).await {
+            Ok(never) => match never {},
+            Err(err) => err,
+        };
+        warn!(?err, ?backoff, "upstream lost, reconnecting");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// The filters of the upstream narrowed down to the given range.
+pub(crate) fn narrowed(
+    filters: &[Filter],
+    since: Option<OffsetDateTime>,
+    until: Option<OffsetDateTime>,
+) -> Vec<Filter> {
+    filters
+        .iter()
+        .map(|filter| Filter {
+            since: filter.since.max(since),
+            until: match (filter.until, until) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            ..filter.clone()
+        })
+        .collect()
+}
+
+/// Only returns once the connection fails.
+async fn follow(
+    cx: &Context,
+    upstream: &Upstream,
+    backoff: &mut std::time::Duration,
+) -> eyre::Result<std::convert::Infallible> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(&upstream.url).await?;
+    *backoff = MIN_BACKOFF;
+    let send = |msg: Value| WsMsg::Text(msg.to_string());
+    // whole seconds as both `since` and `created_at` are
+    let started_at =
+        OffsetDateTime::from_unix_timestamp(OffsetDateTime::now_utc().unix_timestamp())?;
+    let mut req = vec![json!("REQ"), json!(LIVE_SUB)];
+    req.extend(
+        narrowed(&upstream.filters, Some(started_at), None)
+            .into_iter()
+            .map(|filter| {
+                json!(Filter {
+                    limit: Some(0),
+                    ..filter
+                })
+            }),
+    );
+    // opened first so that nothing published during the backfill is missed
+    ws.send(send(Value::Array(req))).await?;
+    let since = cx.store.mirror_since(&upstream.url).await;
+    let backfill_req = |until: OffsetDateTime| {
+        let mut req = vec![json!("REQ"), json!(BACKFILL_SUB)];
+        req.extend(
+            narrowed(&upstream.filters, since, Some(until))
+                .iter()
+                .map(|filter| json!(filter)),
+        );
+        send(Value::Array(req))
+    };
+    ws.send(backfill_req(started_at)).await?;
+    debug!(?since, "mirroring upstream");
+
+    let mut backfilled = false;
+    // ids of the last backfill page and the one being recieved
+    let mut last_page = std::collections::HashSet::new();
+    let mut page = std::collections::HashSet::new();
+    let mut page_oldest = started_at;
+    let mut cursor = since.unwrap_or(OffsetDateTime::UNIX_EPOCH);
+    while let Some(msg) = ws.next().await {
+        let msg: Vec<Value> = match msg? {
+            WsMsg::Text(text) => serde_json::from_str(&text)?,
+            WsMsg::Binary(buf) => serde_json::from_slice(&buf)?,
+            WsMsg::Close(frame) => eyre::bail!("upstream hung up: {frame:?}"),
+            _ => continue,
+        };
+        match (
+            msg.first().and_then(Value::as_str),
+            msg.get(1).and_then(Value::as_str),
+        ) {
+            (Some("EVENT"), Some(sub_id)) if msg.len() == 3 => {
+                let event: Event = match serde_json::from_value(msg[2].clone()) {
+                    Ok(event) => event,
+                    Err(err) => {
+                        warn!(?err, "malformed event from upstream");
+                        continue;
+                    }
+                };
+                let created_at = event.created_at;
+                if sub_id == BACKFILL_SUB {
+                    page_oldest = page_oldest.min(created_at);
+                    page.insert(event.id.clone());
+                }
+                store(cx, event).await;
+                // the live subscription only takes over from the backfill
+                // once it's done
+                if sub_id == LIVE_SUB && backfilled {
+                    let created_at = created_at.min(OffsetDateTime::now_utc());
+                    if created_at > cursor {
+                        cursor = created_at;
+                        cx.store.set_mirror_since(&upstream.url, cursor).await;
+                    }
+                }
+            }
+            (Some("EOSE"), Some(BACKFILL_SUB)) => {
+                // pages overlap on their boundary `created_at`, done once
+                // one has nothing new. Events sharing a timestamp past the
+                // upstream's page size can't be paged through.
+                if page.iter().any(|id| !last_page.contains(id)) {
+                    ws.send(backfill_req(page_oldest)).await?;
+                    last_page = std::mem::take(&mut page);
+                    continue;
+                }
+                ws.send(send(json!(["CLOSE", BACKFILL_SUB]))).await?;
+                backfilled = true;
+                cursor = cursor.max(started_at);
+                cx.store.set_mirror_since(&upstream.url, cursor).await;
+                debug!("backfill done");
+            }
+            (Some("CLOSED"), Some(sub_id)) => {
+                eyre::bail!("upstream closed {sub_id}: {:?}", msg.get(2))
+            }
+            (Some("NOTICE"), _) => debug!(?msg, "notice from upstream"),
+            _ => {}
+        }
+    }
+    eyre::bail!("upstream connection ended")
+}
+
+/// Stores the event the same way events published to the relay are.
+async fn store(cx: &Context, event: Event) {
+    let id = event.id.clone();
+    let origin = crate::event::policy::Origin {
+        authed_pubkeys: vec![],
+        ip: None,
+    };
+    match crate::event::create::CreateEvent
+        .handle_from(cx, event, &origin)
+        .await
+    {
+        Ok(_) => trace!(%id, "mirrored event"),
+        Err(err) => trace!(%id, ?err, "mirrored event rejected"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interlude::*;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message as WsMsg;
+
+    use super::Upstream;
+    use crate::event::testing::TEST_PRIVKEY;
+    use crate::event::Event;
+
+    const UPSTREAM_URL: &str = "ws://127.0.0.1:19008";
+
+    fn signed_event(created_at: i64, kind: u16, content: &str) -> Event {
+        let created_at = OffsetDateTime::from_unix_timestamp(created_at).unwrap();
+        crate::event::testing::sign(TEST_PRIVKEY, created_at, kind, vec![], content)
+    }
+
+    async fn stored(cx: &Context, events: &[&Event]) -> usize {
+        let filters: Vec<crate::event::Filter> = serde_json::from_value(json!([{
+            "ids": events.iter().map(|event| &event.id).collect::<Vec<_>>()
+        }]))
+        .unwrap();
+        cx.store.query(&filters, 100).await.unwrap().len()
+    }
+
+    async fn eventually(
+        what: &str,
+        mut check: impl FnMut() -> futures::future::BoxFuture<'static, bool>,
+    ) {
+        tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            while !check().await {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for {what}"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mirrors_upstream() {
+        let (upstream_testing, upstream) = crate::utils::testing::cx_fn_with_backend(
+            common::function_full!(),
+            crate::utils::testing::Backend::Mem,
+            |config| {
+                // for the backfill to take a few pages
+                config.limits.max_limit = 2;
+            },
+        )
+        .await;
+        let (testing, cx) =
+            crate::utils::testing::cx_fn_with_config(common::function_full!(), |config| {
+                config.upstreams = vec![Upstream {
+                    url: UPSTREAM_URL.into(),
+                    filters: serde_json::from_value(json!([{ "kinds": [1] }])).unwrap(),
+                }];
+            })
+            .await;
+        {
+            let server_handle = tokio::spawn(
+                axum::Server::bind(&"127.0.0.1:19008".parse().unwrap()).serve(
+                    crate::router(upstream.clone())
+                        .into_make_service_with_connect_info::<std::net::SocketAddr>(),
+                ),
+            );
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            let old = [
+                signed_event(now - 30, 1, "old 1"),
+                signed_event(now - 20, 1, "old 2"),
+                signed_event(now - 10, 1, "old 3"),
+            ];
+            let unwanted = signed_event(now - 5, 7, "+");
+            for event in old.iter().chain([&unwanted]) {
+                upstream.store.insert(event).await.unwrap();
+            }
+
+            let mirroring = tokio::spawn(super::start_mirroring(cx.clone()));
+            eventually("the backfill", || {
+                let (cx, old) = (cx.clone(), old.clone());
+                Box::pin(async move {
+                    stored(&cx, &old.iter().collect::<Vec<_>>()).await == old.len()
+                        && cx.store.mirror_since(UPSTREAM_URL).await.is_some()
+                })
+            })
+            .await;
+            assert_eq!(stored(&cx, &[&unwanted]).await, 0);
+
+            // events published upstream come through the live subscription
+            let live = signed_event(OffsetDateTime::now_utc().unix_timestamp(), 1, "live");
+            let (mut ws, _) = tokio_tungstenite::connect_async(UPSTREAM_URL)
+                .await
+                .unwrap();
+            ws.send(WsMsg::Text(json!(["EVENT", live]).to_string()))
+                .await
+                .unwrap();
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.to_text().unwrap().starts_with("[\"OK\"") {
+                    break;
+                }
+            }
+            eventually("the live event", || {
+                let (cx, live) = (cx.clone(), live.clone());
+                Box::pin(async move {
+                    stored(&cx, &[&live]).await == 1
+                        && cx.store.mirror_since(UPSTREAM_URL).await == Some(live.created_at)
+                })
+            })
+            .await;
+            mirroring.abort();
+
+            // restarts pick up from the mark, so events created while down
+            // get pulled but not those backdated past it
+            let missed = signed_event(OffsetDateTime::now_utc().unix_timestamp(), 1, "missed");
+            let backdated = signed_event(live.created_at.unix_timestamp() - 60, 1, "backdated");
+            for event in [&missed, &backdated] {
+                upstream.store.insert(event).await.unwrap();
+            }
+            let mirroring = tokio::spawn(super::start_mirroring(cx.clone()));
+            eventually("the missed event", || {
+                let (cx, missed) = (cx.clone(), missed.clone());
+                Box::pin(async move { stored(&cx, &[&missed]).await == 1 })
+            })
+            .await;
+            assert_eq!(stored(&cx, &[&backdated]).await, 0);
+            mirroring.abort();
+
+            server_handle.abort();
+        }
+        testing.close().await;
+        upstream_testing.close().await;
+    }
+}
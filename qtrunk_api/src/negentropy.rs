@@ -0,0 +1,544 @@
+//! NIP-77 negentropy set reconciliation. Both sides sort their events by
+//! `(created_at, id)` and exchange fingerprints of ranges of them, splitting
+//! the ranges that differ until they're small enough to list the ids
+//! outright.
+//!
+//! Messages are in the protocol's binary form, hex encoded over the wire.
+
+use crate::interlude::*;
+
+pub const PROTOCOL_VERSION: u8 = 0x61;
+
+const ID_SIZE: usize = 32;
+const FINGERPRINT_SIZE: usize = 16;
+/// Number of ranges a mismatched range gets split into.
+const BUCKETS: usize = 16;
+
+pub type Id = [u8; ID_SIZE];
+
+#[derive(Debug, thiserror::Error, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("unsupported protocol version {version:#x}")]
+    UnsupportedVersion { version: u8 },
+    #[error("malformed message: {reason}")]
+    Malformed { reason: &'static str },
+}
+
+/// An event as far as reconciliation is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Item {
+    pub timestamp: u64,
+    pub id: Id,
+}
+
+/// Exclusive upper end of a range, compared against items with the id
+/// prefix padded out with zeros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Bound {
+    item: Item,
+    prefix_len: usize,
+}
+
+impl Bound {
+    const INFINITY: Self = Self {
+        item: Item {
+            timestamp: u64::MAX,
+            id: [0; ID_SIZE],
+        },
+        prefix_len: 0,
+    };
+
+    fn from_item(item: &Item) -> Self {
+        Self {
+            item: *item,
+            prefix_len: ID_SIZE,
+        }
+    }
+
+    /// The shortest bound that falls between the two adjacent items.
+    fn between(prev: &Item, curr: &Item) -> Self {
+        if prev.timestamp != curr.timestamp {
+            return Self {
+                item: Item {
+                    timestamp: curr.timestamp,
+                    id: [0; ID_SIZE],
+                },
+                prefix_len: 0,
+            };
+        }
+        let shared = prev
+            .id
+            .iter()
+            .zip(&curr.id)
+            .take_while(|(prev, curr)| prev == curr)
+            .count();
+        let prefix_len = (shared + 1).min(ID_SIZE);
+        let mut id = [0; ID_SIZE];
+        id[..prefix_len].copy_from_slice(&curr.id[..prefix_len]);
+        Self {
+            item: Item {
+                timestamp: curr.timestamp,
+                id,
+            },
+            prefix_len,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Skip = 0,
+    Fingerprint = 1,
+    IdList = 2,
+}
+
+/// Sum of the ids as 256-bit little endian integers, hashed along with
+/// their count.
+fn fingerprint(items: &[Item]) -> [u8; FINGERPRINT_SIZE] {
+    use k256::sha2::*;
+    let mut sum = [0u8; ID_SIZE];
+    for item in items {
+        let mut carry = 0u16;
+        for (acc, byte) in sum.iter_mut().zip(&item.id) {
+            let next = *acc as u16 + *byte as u16 + carry;
+            *acc = next as u8;
+            carry = next >> 8;
+        }
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(sum);
+    hasher.update(encode_varint(items.len() as u64));
+    let hash = hasher.finalize();
+    let mut out = [0; FINGERPRINT_SIZE];
+    out.copy_from_slice(&hash[..FINGERPRINT_SIZE]);
+    out
+}
+
+/// Base-128, most significant group first, with the high bit set on all
+/// but the last byte.
+fn encode_varint(mut val: u64) -> Vec<u8> {
+    let mut out = vec![(val & 0x7f) as u8];
+    val >>= 7;
+    while val > 0 {
+        out.push((val & 0x7f) as u8 | 0x80);
+        val >>= 7;
+    }
+    out.reverse();
+    out
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    /// Timestamps are encoded as deltas from the previous one.
+    last_timestamp: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() < len {
+            return Err(Error::Malformed {
+                reason: "unexpected end of message",
+            });
+        }
+        let (out, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(out)
+    }
+
+    fn varint(&mut self) -> Result<u64, Error> {
+        let mut val = 0u64;
+        loop {
+            let byte = self.bytes(1)?[0];
+            val = val.checked_mul(128).ok_or(Error::Malformed {
+                reason: "varint overflow",
+            })? | (byte & 0x7f) as u64;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+    }
+
+    fn bound(&mut self) -> Result<Bound, Error> {
+        let timestamp = match self.varint()? {
+            0 => u64::MAX,
+            delta => self.last_timestamp.saturating_add(delta - 1),
+        };
+        self.last_timestamp = timestamp;
+        let prefix_len = self.varint()? as usize;
+        if prefix_len > ID_SIZE {
+            return Err(Error::Malformed {
+                reason: "bound id prefix too long",
+            });
+        }
+        let mut id = [0; ID_SIZE];
+        id[..prefix_len].copy_from_slice(self.bytes(prefix_len)?);
+        Ok(Bound {
+            item: Item { timestamp, id },
+            prefix_len,
+        })
+    }
+
+    fn id(&mut self) -> Result<[u8; ID_SIZE], Error> {
+        Ok(self.bytes(ID_SIZE)?.try_into().unwrap())
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+    last_timestamp: u64,
+}
+
+impl Writer {
+    fn varint(&mut self, val: u64) {
+        self.buf.extend(encode_varint(val));
+    }
+
+    fn bound(&mut self, bound: &Bound) {
+        let timestamp = bound.item.timestamp;
+        if timestamp == u64::MAX {
+            self.varint(0);
+        } else {
+            self.varint(timestamp - self.last_timestamp + 1);
+        }
+        self.last_timestamp = timestamp;
+        self.varint(bound.prefix_len as u64);
+        self.buf.extend(&bound.item.id[..bound.prefix_len]);
+    }
+
+    fn range(&mut self, bound: &Bound, mode: Mode) {
+        self.bound(bound);
+        self.varint(mode as u64);
+    }
+}
+
+/// One side of a reconciliation over a fixed set of items.
+#[derive(Debug)]
+pub struct Negentropy {
+    items: Vec<Item>,
+    /// Max size in bytes of the messages produced, unlimited if zero.
+    frame_size_limit: usize,
+}
+
+impl Negentropy {
+    pub fn new(mut items: Vec<Item>, frame_size_limit: usize) -> Self {
+        items.sort_unstable();
+        items.dedup();
+        Self {
+            items,
+            frame_size_limit,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// The opening message of the initiator.
+    pub fn initiate(&self) -> Vec<u8> {
+        let mut out = Writer {
+            buf: vec![PROTOCOL_VERSION],
+            ..default()
+        };
+        self.split_range(0, self.items.len(), &Bound::INFINITY, &mut out);
+        out.buf
+    }
+
+    /// Answers a message as the responder. Messages of other protocol
+    /// versions are answered with the version supported.
+    pub fn reconcile(&self, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        self.reconcile_inner(msg, None)
+    }
+
+    /// Answers the responder's message as the initiator, noting down the
+    /// ids only it has and those it needs. The reconciliation is complete
+    /// once there's no answer.
+    pub fn reconcile_with_ids(
+        &self,
+        msg: &[u8],
+        have_ids: &mut Vec<Id>,
+        need_ids: &mut Vec<Id>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let out = self.reconcile_inner(msg, Some((have_ids, need_ids)))?;
+        Ok(if out.len() == 1 { None } else { Some(out) })
+    }
+
+    fn reconcile_inner(
+        &self,
+        msg: &[u8],
+        mut initiator: Option<(&mut Vec<Id>, &mut Vec<Id>)>,
+    ) -> Result<Vec<u8>, Error> {
+        let (&version, msg) = msg.split_first().ok_or(Error::Malformed {
+            reason: "empty message",
+        })?;
+        if !(0x60..=0x6f).contains(&version) {
+            return Err(Error::Malformed {
+                reason: "invalid protocol version byte",
+            });
+        }
+        if version != PROTOCOL_VERSION {
+            if initiator.is_some() {
+                return Err(Error::UnsupportedVersion { version });
+            }
+            return Ok(vec![PROTOCOL_VERSION]);
+        }
+        let mut msg = Reader {
+            buf: msg,
+            last_timestamp: 0,
+        };
+        let mut full = Writer {
+            buf: vec![PROTOCOL_VERSION],
+            ..default()
+        };
+        let mut prev_bound = Bound {
+            item: Item {
+                timestamp: 0,
+                id: [0; ID_SIZE],
+            },
+            prefix_len: 0,
+        };
+        let mut prev_idx = 0;
+        // index of the items the last range in the answer ends at
+        let mut answered_idx = 0;
+        // consecutive skips are coalesced into one and trailing ones dropped
+        let mut skip = false;
+        while !msg.is_empty() {
+            let mut out = Writer {
+                buf: vec![],
+                last_timestamp: full.last_timestamp,
+            };
+            let curr_bound = msg.bound()?;
+            let lower = prev_idx;
+            let mut upper = self.lower_bound(prev_idx, &curr_bound);
+            match msg.varint()? {
+                0 => skip = true,
+                1 => {
+                    let theirs = msg.bytes(FINGERPRINT_SIZE)?;
+                    if theirs == fingerprint(&self.items[lower..upper]) {
+                        skip = true;
+                    } else {
+                        if std::mem::take(&mut skip) {
+                            out.range(&prev_bound, Mode::Skip);
+                        }
+                        self.split_range(lower, upper, &curr_bound, &mut out);
+                    }
+                }
+                2 => {
+                    let count = msg.varint()?;
+                    let mut theirs = std::collections::HashSet::new();
+                    for _ in 0..count {
+                        theirs.insert(msg.id()?);
+                    }
+                    if let Some((have_ids, need_ids)) = initiator.as_mut() {
+                        for item in &self.items[lower..upper] {
+                            if !theirs.remove(&item.id) {
+                                have_ids.push(item.id);
+                            }
+                        }
+                        need_ids.extend(theirs);
+                        skip = true;
+                    } else {
+                        if std::mem::take(&mut skip) {
+                            out.range(&prev_bound, Mode::Skip);
+                        }
+                        let mut end_bound = curr_bound;
+                        let mut ids: Vec<u8> = vec![];
+                        for (idx, item) in self.items[lower..upper].iter().enumerate() {
+                            if self.exceeds_limit(full.buf.len() + out.buf.len() + ids.len()) {
+                                end_bound = Bound::from_item(item);
+                                upper = lower + idx;
+                                break;
+                            }
+                            ids.extend(&item.id);
+                        }
+                        out.range(&end_bound, Mode::IdList);
+                        out.varint((ids.len() / ID_SIZE) as u64);
+                        out.buf.extend(ids);
+                        // always sent, whatever was left out gets fingerprinted below
+                        full.buf.append(&mut out.buf);
+                        full.last_timestamp = out.last_timestamp;
+                        answered_idx = upper;
+                    }
+                }
+                _ => {
+                    return Err(Error::Malformed {
+                        reason: "unknown range mode",
+                    })
+                }
+            }
+            if self.exceeds_limit(full.buf.len() + out.buf.len()) {
+                // leave the rest for the next round
+                let mut rest = Writer {
+                    buf: vec![],
+                    last_timestamp: full.last_timestamp,
+                };
+                rest.range(&Bound::INFINITY, Mode::Fingerprint);
+                rest.buf.extend(fingerprint(&self.items[answered_idx..]));
+                full.buf.extend(rest.buf);
+                break;
+            }
+            if !out.buf.is_empty() {
+                full.buf.extend(out.buf);
+                full.last_timestamp = out.last_timestamp;
+                answered_idx = upper;
+            }
+            prev_idx = upper;
+            prev_bound = curr_bound;
+        }
+        Ok(full.buf)
+    }
+
+    /// Index of the first item from `start` that's not below the bound.
+    fn lower_bound(&self, start: usize, bound: &Bound) -> usize {
+        start + self.items[start..].partition_point(|item| *item < bound.item)
+    }
+
+    fn exceeds_limit(&self, len: usize) -> bool {
+        // room for the closing fingerprint
+        self.frame_size_limit != 0 && len > self.frame_size_limit.saturating_sub(200)
+    }
+
+    /// Describes the range either with its ids if it's small or the
+    /// fingerprints of its buckets.
+    fn split_range(&self, lower: usize, upper: usize, upper_bound: &Bound, out: &mut Writer) {
+        let items = &self.items[lower..upper];
+        if items.len() < BUCKETS * 2 {
+            out.range(upper_bound, Mode::IdList);
+            out.varint(items.len() as u64);
+            for item in items {
+                out.buf.extend(&item.id);
+            }
+            return;
+        }
+        let per_bucket = items.len() / BUCKETS;
+        let with_extra = items.len() % BUCKETS;
+        let mut curr = 0;
+        for bucket in 0..BUCKETS {
+            let size = per_bucket + usize::from(bucket < with_extra);
+            let fingerprint = fingerprint(&items[curr..curr + size]);
+            curr += size;
+            let bound = if curr == items.len() {
+                *upper_bound
+            } else {
+                Bound::between(&items[curr - 1], &items[curr])
+            };
+            out.range(&bound, Mode::Fingerprint);
+            out.buf.extend(fingerprint);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(range: std::ops::Range<u64>) -> Vec<Item> {
+        use k256::sha2::*;
+        range
+            .map(|ii| Item {
+                // some share timestamps
+                timestamp: 1_700_000_000 + ii / 3,
+                id: Sha256::digest(ii.to_be_bytes()).into(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn varints() {
+        for (val, encoded) in [
+            (0, vec![0]),
+            (127, vec![0x7f]),
+            (128, vec![0x81, 0x00]),
+            (300, vec![0x82, 0x2c]),
+            (
+                u64::MAX,
+                vec![0x81, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f],
+            ),
+        ] {
+            assert_eq!(encode_varint(val), encoded);
+            let mut reader = Reader {
+                buf: &encoded,
+                last_timestamp: 0,
+            };
+            assert_eq!(reader.varint().unwrap(), val);
+            assert!(reader.is_empty());
+        }
+    }
+
+    common::table_tests! {
+        reconciles,
+        (ours, theirs, frame_size_limit),
+        {
+            let ours = items(ours.0..ours.1);
+            let theirs = items(theirs.0..theirs.1);
+            let initiator = Negentropy::new(ours.clone(), frame_size_limit);
+            let responder = Negentropy::new(theirs.clone(), frame_size_limit);
+            let (mut have, mut need) = (vec![], vec![]);
+            let mut msg = initiator.initiate();
+            let mut rounds = 0;
+            loop {
+                rounds += 1;
+                assert!(rounds < 100, "reconciliation doesn't converge");
+                if frame_size_limit > 0 {
+                    assert!(msg.len() <= frame_size_limit);
+                }
+                let answer = responder.reconcile(&msg).unwrap();
+                match initiator
+                    .reconcile_with_ids(&answer, &mut have, &mut need)
+                    .unwrap()
+                {
+                    Some(next) => msg = next,
+                    None => break,
+                }
+            }
+            let ids = |items: &[Item], others: &[Item]| {
+                let mut ids = items
+                    .iter()
+                    .filter(|item| !others.contains(item))
+                    .map(|item| item.id)
+                    .collect::<Vec<_>>();
+                ids.sort();
+                ids
+            };
+            have.sort();
+            need.sort();
+            assert_eq!(have, ids(&ours, &theirs));
+            assert_eq!(need, ids(&theirs, &ours));
+        }
+    }
+
+    reconciles! {
+        both_empty: ((0, 0), (0, 0), 0),
+        ours_empty: ((0, 0), (0, 1000), 0),
+        theirs_empty: ((0, 1000), (0, 0), 0),
+        same: ((0, 1000), (0, 1000), 0),
+        overlapping: ((0, 1000), (500, 2000), 0),
+        disjoint: ((0, 50), (50, 100), 0),
+        frame_limited: ((0, 5000), (2500, 10000), 4096),
+    }
+
+    #[test]
+    fn answers_unsupported_versions() {
+        let responder = Negentropy::new(items(0..10), 0);
+        assert_eq!(
+            responder.reconcile(&[0x62]).unwrap(),
+            vec![PROTOCOL_VERSION]
+        );
+        assert_eq!(
+            Negentropy::new(vec![], 0).reconcile_with_ids(&[0x62], &mut vec![], &mut vec![]),
+            Err(Error::UnsupportedVersion { version: 0x62 })
+        );
+        assert!(matches!(
+            responder.reconcile(&[0x61, 0x00]),
+            Err(Error::Malformed { .. })
+        ));
+    }
+}
@@ -0,0 +1,128 @@
+//! Fixed window rate limiting. Counters live in redis so that limits hold
+//! across instances, falling back to process local ones without it.
+
+use crate::interlude::*;
+
+/// At most `count` hits every `window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub count: u32,
+    pub window: std::time::Duration,
+}
+
+impl RateLimit {
+    pub fn per_second(count: u32) -> Self {
+        Self {
+            count,
+            window: std::time::Duration::from_secs(1),
+        }
+    }
+
+    pub fn per_minute(count: u32) -> Self {
+        Self {
+            count,
+            window: std::time::Duration::from_secs(60),
+        }
+    }
+}
+
+impl RateLimit {
+    /// The window in whole seconds, as counted.
+    pub fn window_secs(&self) -> u64 {
+        self.window.as_secs().max(1)
+    }
+}
+
+/// Counters for when there's no redis. Only hold within the instance.
+#[derive(Debug, Default)]
+pub struct LocalCounters {
+    inner: std::sync::Mutex<LocalInner>,
+}
+
+#[derive(Debug, Default)]
+struct LocalInner {
+    /// Hits by bucketed key, along with the unix time they expire at.
+    counts: std::collections::HashMap<String, (u64, u64)>,
+    /// Unix time expired counts were last purged at.
+    purged_at: u64,
+}
+
+impl LocalCounters {
+    fn incr(&self, key: String, now: u64, expires_at: u64) -> u64 {
+        let mut inner = self.inner.lock().unwrap_or_log();
+        if inner.purged_at < now {
+            inner.counts.retain(|_, (expires_at, _)| *expires_at > now);
+            inner.purged_at = now;
+        }
+        let (_, count) = inner.counts.entry(key).or_insert((expires_at, 0));
+        *count += 1;
+        *count
+    }
+}
+
+/// Counts a hit against the key. Returns false if the limit's been exceeded.
+pub async fn hit(cx: &Context, key: &str, limit: &RateLimit) -> eyre::Result<bool> {
+    let window = limit.window_secs();
+    let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+    let bucket = now / window;
+    let key = format!("{}:rate:{key}:{bucket}", cx.config.redis_key_prefix);
+    let Some(pool) = &cx.redis else {
+        let count = cx.rate_counters.incr(key, now, (bucket + 1) * window);
+        return Ok(count <= limit.count as u64);
+    };
+    let mut conn = pool.get().await?;
+    let (count,): (u64,) = redis::pipe()
+        .incr(&key, 1)
+        .expire(&key, window as usize)
+        .ignore()
+        .query_async(&mut *conn)
+        .await?;
+    Ok(count <= limit.count as u64)
+}
+
+/// Counts a hit against the key if there's a limit, returning the limit if
+/// it's been exceeded. Errors reaching redis let the hit through.
+pub async fn exceeded(cx: &Context, key: &str, limit: Option<RateLimit>) -> Option<RateLimit> {
+    let limit = limit?;
+    match hit(cx, key, &limit).await {
+        Ok(true) => None,
+        Ok(false) => Some(limit),
+        Err(err) => {
+            warn!(?err, %key, "error checking rate limit");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimit;
+
+    common::table_tests! {
+        counts_hits tokio,
+        with_redis,
+        {
+            let (testing, cx) = if with_redis {
+                crate::utils::testing::cx_fn_with_redis(common::function_full!()).await
+            } else {
+                crate::utils::testing::cx_fn(common::function_full!()).await
+            };
+            // long enough to not cross into the next window mid test
+            let limit = RateLimit {
+                count: 2,
+                window: std::time::Duration::from_secs(60 * 60),
+            };
+            assert!(crate::rate_limit::hit(&cx, "ip:127.0.0.1", &limit).await.unwrap());
+            assert!(crate::rate_limit::hit(&cx, "ip:127.0.0.1", &limit).await.unwrap());
+            assert!(!crate::rate_limit::hit(&cx, "ip:127.0.0.1", &limit).await.unwrap());
+            // keys are counted separately
+            assert!(crate::rate_limit::hit(&cx, "ip:127.0.0.2", &limit).await.unwrap());
+            testing.close().await;
+        },
+    }
+
+    counts_hits! {
+        redis: true,
+        local: false,
+    }
+}
@@ -0,0 +1,219 @@
+//! Storage of events. The endpoints only go through [`EventStore`] so that
+//! the backends can be swapped.
+
+use crate::interlude::*;
+
+use crate::event::{Event, Filter};
+
+pub mod mem;
+pub mod pg;
+
+pub use mem::MemStore;
+pub use pg::PgStore;
+
+/// Why an event wasn't stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Duplicate,
+    /// A newer version of the replaceable event is already stored.
+    Stale,
+    /// The replaceable event was being concurrently replaced.
+    Conflict,
+    /// The event was deleted by its author through NIP-09.
+    Deleted,
+}
+
+/// What a NIP-86 ban applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Banned {
+    Pubkey,
+    Event,
+}
+
+/// A NIP-86 ban on a hex pubkey or event id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub key: String,
+    pub reason: Option<String>,
+}
+
+#[async_trait::async_trait]
+pub trait EventStore: std::fmt::Debug + Send + Sync {
+    /// Stores a regular event unless it's been deleted through NIP-09.
+    async fn insert(&self, event: &Event) -> Result<(), Rejection>;
+
+    /// Like [`Self::insert`] for a batch of regular events, with a result
+    /// for each in order.
+    async fn insert_batch(&self, events: &[Event]) -> Vec<Result<(), Rejection>> {
+        let mut results = Vec::with_capacity(events.len());
+        for event in events {
+            results.push(self.insert(event).await);
+        }
+        results
+    }
+
+    /// Stores a replaceable event if it's newer than the version under its
+    /// address. NIP-01: the latest `created_at` wins with the lowest id
+    /// breaking ties. The version it takes the place of gets archived.
+    async fn replace(&self, event: &Event) -> Result<(), Rejection>;
+
+    /// Stores the NIP-09 deletion request, removes the events it refers to
+    /// and tombstones them to prevent re-submission.
+    async fn delete_by_reference(&self, request: &Event) -> Result<(), Rejection>;
+
+    /// Unexpired events matching any of the filters. Each filter gets its
+    /// own `limit`, capped at `max_limit`. Ordered by NIP-50 relevance
+    /// then newest first.
+    async fn query(
+        &self,
+        filters: &[Filter],
+        max_limit: usize,
+    ) -> Result<Vec<Event>, ValidationErrors>;
+
+    /// Number of unexpired events matching any of the filters. `limit` is
+    /// ignored.
+    async fn count(&self, filters: &[Filter]) -> Result<i64, ValidationErrors>;
+
+    /// A page of the unexpired events matching any of the filters, oldest
+    /// first, resuming after the `after` event. Matches everything if there
+    /// are no filters. `limit` is ignored.
+    async fn scan(
+        &self,
+        filters: &[Filter],
+        after: Option<&Event>,
+        page_size: usize,
+    ) -> Result<Vec<Event>, ValidationErrors>;
+
+    /// What NIP-77 reconciliation goes on of the unexpired events matching
+    /// any of the filters, at most `max_items` of them, oldest first.
+    /// Matches everything if there are no filters. `limit` is ignored.
+    async fn negentropy_items(
+        &self,
+        filters: &[Filter],
+        max_items: usize,
+    ) -> Result<Vec<crate::negentropy::Item>, ValidationErrors>;
+
+    /// Deletes expired events, returning how many.
+    async fn reap_expired(&self) -> u64;
+
+    /// Archived versions of the replaceable events under the address,
+    /// newest first. `d_tag` is ignored for kinds that aren't
+    /// parameterized.
+    async fn archived(&self, pubkey: &str, kind: u16, d_tag: &str, limit: usize) -> Vec<Event>;
+
+    /// Deletes archived versions archived before `archived_before` and
+    /// those past the newest `max_versions` of each address, returning how
+    /// many.
+    async fn prune_archive(
+        &self,
+        archived_before: Option<OffsetDateTime>,
+        max_versions: Option<usize>,
+    ) -> u64;
+
+    async fn banned(&self, event: &Event) -> Option<Banned>;
+
+    async fn ban_pubkey(&self, pubkey: &str, reason: Option<&str>);
+
+    async fn unban_pubkey(&self, pubkey: &str);
+
+    /// Also removes the event if it's stored.
+    async fn ban_event(&self, id: &str, reason: Option<&str>);
+
+    /// In the order they were placed.
+    async fn banned_pubkeys(&self) -> Vec<Ban>;
+
+    /// In the order they were placed.
+    async fn banned_events(&self) -> Vec<Ban>;
+
+    async fn setting(&self, key: &str) -> Option<serde_json::Value>;
+
+    async fn set_setting(&self, key: &str, value: serde_json::Value);
+
+    /// Where mirroring the upstream relay resumes from, see [`crate::mirror`].
+    async fn mirror_since(&self, upstream: &str) -> Option<OffsetDateTime>;
+
+    /// Never moves the mark back.
+    async fn set_mirror_since(&self, upstream: &str, since: OffsetDateTime);
+}
+
+/// Decodes the hex values of the `ids` or `authors` of the filter at `idx`.
+pub(crate) fn decode_hex_list(
+    idx: usize,
+    field: &'static str,
+    items: &[String],
+) -> Result<Vec<Vec<u8>>, ValidationErrors> {
+    items
+        .iter()
+        .map(|hex| {
+            data_encoding::HEXLOWER_PERMISSIVE
+                .decode(hex.as_bytes())
+                .map_err(|_| hex)
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|hex| {
+            let mut issues = validator::ValidationErrors::new();
+            issues.add(
+                field,
+                validator::ValidationError {
+                    code: "invalid_hex".into(),
+                    message: Some(format!("error decoding hex values in `{field}`").into()),
+                    params: [
+                        (std::borrow::Cow::from("value"), serde_json::json!(hex)),
+                        (std::borrow::Cow::from("filter_idx"), serde_json::json!(idx)),
+                    ]
+                    .into_iter()
+                    .collect(),
+                },
+            );
+            issues.into()
+        })
+}
+
+/// The events a NIP-09 deletion request refers to.
+#[derive(Debug, Default)]
+pub(crate) struct DeletionTargets {
+    /// Ids from the `e` tags.
+    pub ids: Vec<Vec<u8>>,
+    /// `(kind, d)` pairs from the `a` tags.
+    pub addresses: Vec<(u16, String)>,
+}
+
+pub(crate) fn deletion_targets(req: &Event) -> DeletionTargets {
+    let mut out = DeletionTargets::default();
+    for tag in &req.tags {
+        match (tag.get(0).map(|st| &st[..]), tag.get(1)) {
+            (Some("e"), Some(id)) => {
+                // silently ignore malformed references
+                if let Ok(id) = data_encoding::HEXLOWER_PERMISSIVE.decode(id.as_bytes()) {
+                    out.ids.push(id);
+                }
+            }
+            (Some("a"), Some(address)) => {
+                let mut parts = address.splitn(3, ':');
+                let (Some(kind), Some(pubkey)) = (parts.next(), parts.next()) else {
+                    continue;
+                };
+                let Ok(kind) = kind.parse::<u16>() else {
+                    continue;
+                };
+                // only the author can delete their events
+                if pubkey != req.pubkey {
+                    continue;
+                }
+                if !crate::event::is_replaceable(kind)
+                    && !crate::event::is_parameterized_replaceable(kind)
+                {
+                    continue;
+                }
+                let d_tag = if crate::event::is_parameterized_replaceable(kind) {
+                    parts.next().unwrap_or("")
+                } else {
+                    ""
+                };
+                out.addresses.push((kind, d_tag.to_string()));
+            }
+            _ => {}
+        }
+    }
+    out
+}
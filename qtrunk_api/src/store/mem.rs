@@ -0,0 +1,424 @@
+//! In-memory [`EventStore`] for tests and throwaway relays. Nothing
+//! survives a restart.
+
+use crate::interlude::*;
+
+use std::collections::{HashMap, HashSet};
+
+use super::{Ban, Banned, EventStore, Rejection};
+use crate::event::{Event, Filter};
+
+#[derive(Debug, Default)]
+pub struct MemStore {
+    inner: std::sync::Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// By lowercase hex id.
+    events: HashMap<String, Event>,
+    /// `(id, pubkey)` of events deleted by their authors.
+    event_deletions: HashSet<(String, String)>,
+    /// `(kind, pubkey, d)` addresses mapped to the `created_at` of the latest
+    /// deletion request targeting them.
+    address_deletions: HashMap<(u16, String, String), OffsetDateTime>,
+    banned_pubkeys: Vec<Ban>,
+    banned_events: Vec<Ban>,
+    settings: HashMap<String, serde_json::Value>,
+    mirror_cursors: HashMap<String, OffsetDateTime>,
+    /// Superseded versions of replaceable events along with when they
+    /// were archived.
+    archive: Vec<(OffsetDateTime, Event)>,
+}
+
+impl MemStore {
+    pub fn new() -> Self {
+        default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_log()
+    }
+}
+
+impl Inner {
+    fn is_deleted(&self, event: &Event) -> bool {
+        if self
+            .event_deletions
+            .contains(&(event.id.clone(), event.pubkey.clone()))
+        {
+            return true;
+        }
+        // only replaceable events can be deleted by address
+        if !crate::event::is_replaceable(event.kind)
+            && !crate::event::is_parameterized_replaceable(event.kind)
+        {
+            return false;
+        }
+        matches!(
+            self.address_deletions.get(&(
+                event.kind,
+                event.pubkey.clone(),
+                event.d_tag().to_string()
+            )),
+            Some(until) if *until >= event.created_at
+        )
+    }
+
+    fn insert(&mut self, event: &Event) -> Result<(), Rejection> {
+        if self.is_deleted(event) {
+            return Err(Rejection::Deleted);
+        }
+        if self.events.contains_key(&event.id) {
+            return Err(Rejection::Duplicate);
+        }
+        self.events.insert(event.id.clone(), event.clone());
+        Ok(())
+    }
+}
+
+/// Whether `event` takes the place of `current` under their address.
+fn is_newer(event: &Event, current: &Event) -> bool {
+    event.created_at > current.created_at
+        || (event.created_at == current.created_at && event.id < current.id)
+}
+
+fn rank(filter: &Filter, event: &Event) -> f32 {
+    filter
+        .search_query()
+        .map(|query| crate::event::search_rank(&query, &event.content))
+        .unwrap_or_default()
+}
+
+/// NIP-01 ordering, with NIP-50 relevance first.
+fn by_rank(a: &(f32, &Event), b: &(f32, &Event)) -> std::cmp::Ordering {
+    b.0.total_cmp(&a.0)
+        .then_with(|| b.1.created_at.cmp(&a.1.created_at))
+        .then_with(|| a.1.id.cmp(&b.1.id))
+}
+
+fn negentropy_item(event: &Event) -> crate::negentropy::Item {
+    let mut id = [0; 32];
+    data_encoding::HEXLOWER_PERMISSIVE
+        .decode_mut(event.id.as_bytes(), &mut id)
+        .expect_or_log("stored events must be validated");
+    crate::negentropy::Item {
+        timestamp: event.created_at.unix_timestamp() as u64,
+        id,
+    }
+}
+
+fn validate(filters: &[Filter]) -> Result<(), ValidationErrors> {
+    for (idx, filter) in filters.iter().enumerate() {
+        if let Some(items) = &filter.ids {
+            super::decode_hex_list(idx, "ids", items)?;
+        }
+        if let Some(items) = &filter.authors {
+            super::decode_hex_list(idx, "authors", items)?;
+        }
+    }
+    Ok(())
+}
+
+#[async_trait::async_trait]
+impl EventStore for MemStore {
+    async fn insert(&self, event: &Event) -> Result<(), Rejection> {
+        self.lock().insert(event)
+    }
+
+    async fn replace(&self, event: &Event) -> Result<(), Rejection> {
+        let mut inner = self.lock();
+        let parameterized = crate::event::is_parameterized_replaceable(event.kind);
+        let current = inner
+            .events
+            .values()
+            .find(|current| {
+                current.kind == event.kind
+                    && current.pubkey == event.pubkey
+                    && (!parameterized || current.d_tag() == event.d_tag())
+            })
+            .cloned();
+        if let Some(current) = &current {
+            if !is_newer(event, current) {
+                return Err(if current.id == event.id {
+                    Rejection::Duplicate
+                } else {
+                    Rejection::Stale
+                });
+            }
+        }
+        if inner.is_deleted(event) {
+            return Err(Rejection::Deleted);
+        }
+        inner.insert(event)?;
+        if let Some(current) = current {
+            inner.events.remove(&current.id);
+            if !inner
+                .archive
+                .iter()
+                .any(|(_, archived)| archived.id == current.id)
+            {
+                inner.archive.push((OffsetDateTime::now_utc(), current));
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete_by_reference(&self, request: &Event) -> Result<(), Rejection> {
+        let mut inner = self.lock();
+        inner.insert(request)?;
+        let super::DeletionTargets { ids, addresses } = super::deletion_targets(request);
+        for id in ids {
+            let id = data_encoding::HEXLOWER.encode(&id);
+            // deletion requests against deletion requests have no effect
+            if matches!(
+                inner.events.get(&id),
+                Some(event) if event.pubkey == request.pubkey && event.kind != 5
+            ) {
+                inner.events.remove(&id);
+            }
+            inner.event_deletions.insert((id, request.pubkey.clone()));
+        }
+        for (kind, d_tag) in addresses {
+            inner.events.retain(|_, event| {
+                !(event.kind == kind
+                    && event.pubkey == request.pubkey
+                    && event.created_at <= request.created_at
+                    && event.d_tag() == d_tag)
+            });
+            let until = inner
+                .address_deletions
+                .entry((kind, request.pubkey.clone(), d_tag))
+                .or_insert(request.created_at);
+            *until = (*until).max(request.created_at);
+        }
+        Ok(())
+    }
+
+    async fn query(
+        &self,
+        filters: &[Filter],
+        max_limit: usize,
+    ) -> Result<Vec<Event>, ValidationErrors> {
+        validate(filters)?;
+        let inner = self.lock();
+        // an event matched by several filters takes its best rank
+        let mut hits: HashMap<&str, (f32, &Event)> = default();
+        for filter in filters {
+            let limit = filter.limit.unwrap_or(max_limit).min(max_limit);
+            if limit == 0 {
+                continue;
+            }
+            let mut matches = inner
+                .events
+                .values()
+                .filter(|event| filter.matches(event))
+                .map(|event| (rank(filter, event), event))
+                .collect::<Vec<_>>();
+            matches.sort_by(by_rank);
+            for (rank, event) in matches.into_iter().take(limit) {
+                let hit = hits.entry(&event.id[..]).or_insert((rank, event));
+                hit.0 = hit.0.max(rank);
+            }
+        }
+        let mut hits = hits.into_values().collect::<Vec<_>>();
+        hits.sort_by(by_rank);
+        Ok(hits.into_iter().map(|(_, event)| event.clone()).collect())
+    }
+
+    async fn count(&self, filters: &[Filter]) -> Result<i64, ValidationErrors> {
+        validate(filters)?;
+        let inner = self.lock();
+        let count = inner
+            .events
+            .values()
+            .filter(|event| {
+                if filters.is_empty() {
+                    !event.is_expired()
+                } else {
+                    filters.iter().any(|filter| filter.matches(event))
+                }
+            })
+            .count();
+        Ok(count as i64)
+    }
+
+    async fn scan(
+        &self,
+        filters: &[Filter],
+        after: Option<&Event>,
+        page_size: usize,
+    ) -> Result<Vec<Event>, ValidationErrors> {
+        validate(filters)?;
+        let inner = self.lock();
+        let mut events = inner
+            .events
+            .values()
+            .filter(|event| {
+                after.map_or(true, |after| {
+                    (event.created_at, &event.id) > (after.created_at, &after.id)
+                })
+            })
+            .filter(|event| {
+                if filters.is_empty() {
+                    !event.is_expired()
+                } else {
+                    filters.iter().any(|filter| filter.matches(event))
+                }
+            })
+            .collect::<Vec<_>>();
+        events.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        Ok(events.into_iter().take(page_size).cloned().collect())
+    }
+
+    async fn negentropy_items(
+        &self,
+        filters: &[Filter],
+        max_items: usize,
+    ) -> Result<Vec<crate::negentropy::Item>, ValidationErrors> {
+        Ok(self
+            .scan(filters, None, max_items)
+            .await?
+            .iter()
+            .map(negentropy_item)
+            .collect())
+    }
+
+    async fn reap_expired(&self) -> u64 {
+        let mut inner = self.lock();
+        let before = inner.events.len();
+        inner.events.retain(|_, event| !event.is_expired());
+        (before - inner.events.len()) as u64
+    }
+
+    async fn archived(&self, pubkey: &str, kind: u16, d_tag: &str, limit: usize) -> Vec<Event> {
+        let inner = self.lock();
+        let parameterized = crate::event::is_parameterized_replaceable(kind);
+        let mut events: Vec<_> = inner
+            .archive
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| {
+                event.kind == kind
+                    && event.pubkey.eq_ignore_ascii_case(pubkey)
+                    && (!parameterized || event.d_tag() == d_tag)
+            })
+            .collect();
+        events.sort_by(|a, b| {
+            b.created_at
+                .cmp(&a.created_at)
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        events.into_iter().take(limit).cloned().collect()
+    }
+
+    async fn prune_archive(
+        &self,
+        archived_before: Option<OffsetDateTime>,
+        max_versions: Option<usize>,
+    ) -> u64 {
+        let mut inner = self.lock();
+        let before = inner.archive.len();
+        if let Some(archived_before) = archived_before {
+            inner
+                .archive
+                .retain(|(archived_at, _)| *archived_at >= archived_before);
+        }
+        if let Some(max_versions) = max_versions {
+            let address = |event: &Event| {
+                let d_tag = if crate::event::is_parameterized_replaceable(event.kind) {
+                    event.d_tag().to_string()
+                } else {
+                    String::new()
+                };
+                (event.pubkey.clone(), event.kind, d_tag)
+            };
+            inner.archive.sort_by(|(_, a), (_, b)| {
+                b.created_at
+                    .cmp(&a.created_at)
+                    .then_with(|| a.id.cmp(&b.id))
+            });
+            let mut versions: HashMap<_, usize> = default();
+            inner.archive.retain(|(_, event)| {
+                let count = versions.entry(address(event)).or_default();
+                *count += 1;
+                *count <= max_versions
+            });
+        }
+        (before - inner.archive.len()) as u64
+    }
+
+    async fn banned(&self, event: &Event) -> Option<Banned> {
+        let inner = self.lock();
+        if inner
+            .banned_pubkeys
+            .iter()
+            .any(|ban| ban.key.eq_ignore_ascii_case(&event.pubkey))
+        {
+            Some(Banned::Pubkey)
+        } else if inner
+            .banned_events
+            .iter()
+            .any(|ban| ban.key.eq_ignore_ascii_case(&event.id))
+        {
+            Some(Banned::Event)
+        } else {
+            None
+        }
+    }
+
+    async fn ban_pubkey(&self, pubkey: &str, reason: Option<&str>) {
+        upsert_ban(&mut self.lock().banned_pubkeys, pubkey, reason);
+    }
+
+    async fn unban_pubkey(&self, pubkey: &str) {
+        self.lock()
+            .banned_pubkeys
+            .retain(|ban| !ban.key.eq_ignore_ascii_case(pubkey));
+    }
+
+    async fn ban_event(&self, id: &str, reason: Option<&str>) {
+        let mut inner = self.lock();
+        upsert_ban(&mut inner.banned_events, id, reason);
+        inner.events.remove(&id.to_ascii_lowercase());
+    }
+
+    async fn banned_pubkeys(&self) -> Vec<Ban> {
+        self.lock().banned_pubkeys.clone()
+    }
+
+    async fn banned_events(&self) -> Vec<Ban> {
+        self.lock().banned_events.clone()
+    }
+
+    async fn setting(&self, key: &str) -> Option<serde_json::Value> {
+        self.lock().settings.get(key).cloned()
+    }
+
+    async fn set_setting(&self, key: &str, value: serde_json::Value) {
+        self.lock().settings.insert(key.to_string(), value);
+    }
+
+    async fn mirror_since(&self, upstream: &str) -> Option<OffsetDateTime> {
+        self.lock().mirror_cursors.get(upstream).copied()
+    }
+
+    async fn set_mirror_since(&self, upstream: &str, since: OffsetDateTime) {
+        let mut inner = self.lock();
+        let cursor = inner
+            .mirror_cursors
+            .entry(upstream.to_string())
+            .or_insert(since);
+        *cursor = (*cursor).max(since);
+    }
+}
+
+/// Re-banning only updates the reason.
+fn upsert_ban(bans: &mut Vec<Ban>, key: &str, reason: Option<&str>) {
+    let key = key.to_ascii_lowercase();
+    let reason = reason.map(String::from);
+    match bans.iter_mut().find(|ban| ban.key == key) {
+        Some(ban) => ban.reason = reason,
+        None => bans.push(Ban { key, reason }),
+    }
+}
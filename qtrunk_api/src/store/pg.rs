@@ -0,0 +1,988 @@
+//! Postgres backed [`EventStore`].
+
+use crate::interlude::*;
+
+use super::{Ban, Banned, EventStore, Rejection};
+use crate::event::{Event, Filter};
+
+#[derive(Debug, Clone)]
+pub struct PgStore {
+    pub db_pool: sqlx::PgPool,
+}
+
+impl PgStore {
+    pub fn new(db_pool: sqlx::PgPool) -> Self {
+        Self { db_pool }
+    }
+}
+
+/// The raw bytes of the hex fields of a validated event.
+fn event_bytes(event: &Event) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let decode = |hex: &str| {
+        data_encoding::HEXLOWER_PERMISSIVE
+            .decode(hex.as_bytes())
+            .expect_or_log("stored events must be validated")
+    };
+    (decode(&event.id), decode(&event.pubkey), decode(&event.sig))
+}
+
+fn decode_key(hex: &str) -> Vec<u8> {
+    data_encoding::HEXLOWER_PERMISSIVE
+        .decode(hex.as_bytes())
+        .expect_or_log("ban keys must be validated")
+}
+
+/// Inserts the event unless it's been tombstoned by a NIP-09 deletion request.
+async fn insert_event(event: &Event, executor: impl sqlx::PgExecutor<'_>) -> Result<(), Rejection> {
+    let (id_bytes, pubkey_bytes, sig_bytes) = event_bytes(event);
+    // only replaceable events can be deleted by address
+    let address = if crate::event::is_replaceable(event.kind)
+        || crate::event::is_parameterized_replaceable(event.kind)
+    {
+        Some(event.d_tag())
+    } else {
+        None
+    };
+    sqlx::query!(
+        r#"
+INSERT INTO public.events (
+    id
+    ,pubkey
+    ,created_at
+    ,kind
+    ,tags
+    ,content
+    ,sig
+    ,expires_at
+) SELECT
+    $1::BYTEA
+    ,$2::BYTEA
+    ,$3::TIMESTAMPTZ
+    ,$4::INT
+    ,$5::JSONB
+    ,$6::TEXT
+    ,$7::BYTEA
+    ,$9::TIMESTAMPTZ
+WHERE NOT EXISTS (
+    SELECT 1 FROM public.event_deletions
+    WHERE event_id = $1 AND pubkey = $2
+) AND NOT EXISTS (
+    SELECT 1 FROM public.address_deletions
+    WHERE kind = $4 AND pubkey = $2 AND d_tag = $8 AND deleted_until >= $3
+)
+                        "#,
+        &id_bytes[..],
+        &pubkey_bytes[..],
+        event.created_at,
+        event.kind as i32,
+        &json!(event.tags),
+        event.content.as_str(),
+        &sig_bytes[..],
+        address,
+        event.expires_at(),
+    )
+    .execute(executor)
+    .await
+    .map_err(|err| {
+        if let sqlx::Error::Database(boxed) = &err {
+            match boxed.constraint() {
+                Some("events_pkey") => return Rejection::Duplicate,
+                // another version was inserted under the same address concurrently
+                Some("events_replaceable_key" | "events_parameterized_replaceable_key") => {
+                    return Rejection::Conflict
+                }
+                _ => {}
+            }
+        }
+        panic!("db error: {err}");
+    })
+    .and_then(|res| {
+        if res.rows_affected() == 0 {
+            Err(Rejection::Deleted)
+        } else {
+            Ok(())
+        }
+    })
+}
+
+/// Attempts at replacing an event when losing races to concurrent inserts.
+const REPLACE_ATTEMPTS: usize = 3;
+
+/// Removes the events targeted by the NIP-09 deletion request and tombstones
+/// them to prevent re-submission.
+async fn apply_deletion(request: &Event, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>) {
+    let (id_bytes, pubkey_bytes, _) = event_bytes(request);
+    let super::DeletionTargets { ids, addresses } = super::deletion_targets(request);
+    if !ids.is_empty() {
+        // deletion requests against deletion requests have no effect
+        sqlx::query!(
+            r#"
+DELETE FROM public.events
+WHERE id = ANY($1) AND pubkey = $2 AND kind != 5
+            "#,
+            &ids[..],
+            &pubkey_bytes[..],
+        )
+        .execute(&mut **tx)
+        .await
+        .unwrap_or_log();
+        sqlx::query!(
+            r#"
+INSERT INTO public.event_deletions (
+    event_id
+    ,pubkey
+    ,deletion_id
+) SELECT
+    UNNEST($1::BYTEA[])
+    ,$2
+    ,$3
+ON CONFLICT DO NOTHING
+            "#,
+            &ids[..],
+            &pubkey_bytes[..],
+            &id_bytes[..],
+        )
+        .execute(&mut **tx)
+        .await
+        .unwrap_or_log();
+    }
+    for (kind, d_tag) in addresses {
+        sqlx::query!(
+            r#"
+DELETE
+    FROM
+        public.events
+    WHERE
+        kind = $1
+        AND pubkey = $2
+        AND created_at <= $3
+        AND d_tag = $4
+            "#,
+            kind as i32,
+            &pubkey_bytes[..],
+            request.created_at,
+            d_tag,
+        )
+        .execute(&mut **tx)
+        .await
+        .unwrap_or_log();
+        sqlx::query!(
+            r#"
+INSERT INTO public.address_deletions (
+    kind
+    ,pubkey
+    ,d_tag
+    ,deleted_until
+    ,deletion_id
+) VALUES (
+    $1
+    ,$2
+    ,$3
+    ,$4
+    ,$5
+) ON CONFLICT (kind, pubkey, d_tag) DO UPDATE SET
+    deleted_until = GREATEST(address_deletions.deleted_until, EXCLUDED.deleted_until)
+    ,deletion_id = CASE
+        WHEN EXCLUDED.deleted_until > address_deletions.deleted_until
+            THEN EXCLUDED.deletion_id
+        ELSE address_deletions.deletion_id
+    END
+            "#,
+            kind as i32,
+            &pubkey_bytes[..],
+            d_tag,
+            request.created_at,
+            &id_bytes[..],
+        )
+        .execute(&mut **tx)
+        .await
+        .unwrap_or_log();
+    }
+}
+
+struct Args {
+    ctr: usize,
+    pub inner: sqlx::postgres::PgArguments,
+}
+impl Args {
+    pub fn new() -> Self {
+        Self {
+            ctr: default(),
+            inner: default(),
+        }
+    }
+
+    pub fn add<'q, T>(&mut self, val: T) -> String
+    where
+        T: sqlx::Encode<'q, sqlx::Postgres> + sqlx::Type<sqlx::Postgres> + 'static + Send + Sync,
+    {
+        use sqlx::Arguments;
+        self.inner.add(val);
+        self.ctr += 1;
+        format!("${ctr}", ctr = self.ctr)
+    }
+}
+
+/// Compiles the filters into a `WHERE` clause that also excludes expired events.
+/// `limit` is not considered.
+fn where_clause(filters: &[Filter], args: &mut Args) -> Result<String, ValidationErrors> {
+    let where_clause = filters
+        .iter()
+        .enumerate()
+        .map(|(idx, filter)| filter_clause(idx, filter, args))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .fold("false".to_string(), |mut acc, val| {
+            acc.push_str(" OR (");
+            acc.push_str(&val[..]);
+            acc.push(')');
+            acc
+        });
+    Ok(if where_clause == "false" {
+        format!("WHERE {UNEXPIRED}")
+    } else {
+        format!("WHERE {UNEXPIRED} AND ({where_clause})")
+    })
+}
+
+// NIP-40: expired events are never served
+const UNEXPIRED: &str = "(expires_at IS NULL OR expires_at > NOW())";
+
+/// Compiles a single filter into a boolean expression. `idx` is the position
+/// of the filter in the request, for error reporting.
+fn filter_clause(idx: usize, filter: &Filter, args: &mut Args) -> Result<String, ValidationErrors> {
+    // map each filter option to a clause
+    [
+        filter.ids.as_deref().map(|items| {
+            let items = super::decode_hex_list(idx, "ids", items)?;
+            let ph = args.add(items);
+            Ok(format!("id = ANY ({ph})"))
+        }),
+        filter.authors.as_deref().map(|items| {
+            let items = super::decode_hex_list(idx, "authors", items)?;
+            let ph = args.add(items);
+            Ok(format!("pubkey = ANY ({ph})"))
+        }),
+        filter.kinds.as_deref().map(|items| {
+            let items = items.iter().map(|kind| *kind as i64).collect::<Vec<_>>();
+            let ph = args.add(items);
+            Ok(format!("kind = ANY ({ph})"))
+        }),
+        filter.since.map(|ts| {
+            let ph = args.add(ts);
+            Ok(format!("created_at >= ({ph})"))
+        }),
+        filter.until.map(|ts| {
+            let ph = args.add(ts);
+            Ok(format!("created_at <= ({ph})"))
+        }),
+        filter.tags.as_ref().map(|tags| {
+            Ok(tags
+                .iter()
+                .map(|(char, needles)| {
+                    let name = args.add(char.to_string());
+                    let values = args.add(needles.clone());
+                    format!(
+                        "id IN (SELECT event_id FROM event_tags WHERE name = {name} AND value = ANY({values}))"
+                    )
+                })
+                .fold("true".to_string(), |mut acc, val| {
+                    acc.push_str(" AND (");
+                    acc.push_str(&val[..]);
+                    acc.push(')');
+                    acc
+                }))
+        }),
+        filter.search_query().map(|query| {
+            let ph = args.add(query);
+            Ok(format!(
+                "content_tsv @@ websearch_to_tsquery('simple', {ph})"
+            ))
+        }),
+    ]
+    .into_iter()
+    // remove all the None values
+    .flatten()
+    // TODO: is it possible to avoid allocation to a Vec here?
+    .collect::<Result<Vec<_>, ValidationErrors>>()
+    .map(|clauses| {
+        clauses
+            .into_iter()
+            // combine the clauses using AND
+            .fold("true".to_string(), |mut acc, val| {
+                acc.push_str(" AND (");
+                acc.push_str(&val[..]);
+                acc.push(')');
+                acc
+            })
+    })
+}
+
+/// Each filter gets its own subquery limited to the filter's `limit`,
+/// capped at `max_limit`. Filters with `limit: 0` are skipped. `None` if
+/// no filter is left.
+fn list_query(
+    filters: &[Filter],
+    max_limit: usize,
+) -> Result<Option<(String, sqlx::postgres::PgArguments)>, ValidationErrors> {
+    let mut args = Args::new();
+    let mut subqueries = vec![];
+    for (idx, filter) in filters.iter().enumerate() {
+        let limit = filter.limit.unwrap_or(max_limit).min(max_limit);
+        if limit == 0 {
+            continue;
+        }
+        let clause = filter_clause(idx, filter, &mut args)?;
+        // NIP-50: searches are ranked by relevance
+        let rank = match filter.search_query() {
+            Some(query) => {
+                let ph = args.add(query);
+                format!("ts_rank(content_tsv, websearch_to_tsquery('simple', {ph}))")
+            }
+            None => "0::REAL".to_string(),
+        };
+        let limit = args.add(limit as i64);
+        subqueries.push(format!(
+            r#"(
+    SELECT id, {rank} AS "rank"
+    FROM events
+    WHERE {UNEXPIRED} AND ({clause})
+    ORDER BY "rank" DESC, created_at DESC, id ASC
+    LIMIT {limit}
+)"#
+        ));
+    }
+    if subqueries.is_empty() {
+        return Ok(None);
+    }
+    let subqueries = subqueries.join("\nUNION\n");
+    // an event matched by several filters takes its best rank
+    Ok(Some((
+        format!(
+            r#"
+SELECT
+    encode(id, 'hex') as "id"
+    ,encode(pubkey, 'hex') as "pubkey"
+    ,created_at
+    ,kind
+    ,tags
+    ,content
+    ,encode(sig, 'hex') as "sig"
+FROM events
+JOIN (
+    SELECT id, MAX("rank") AS "rank"
+    FROM (
+{subqueries}
+    ) AS "matches"
+    GROUP BY id
+) AS "hits" USING (id)
+ORDER BY "hits"."rank" DESC, created_at DESC, id ASC
+        "#,
+        ),
+        args.inner,
+    )))
+}
+
+fn scan_query(
+    filters: &[Filter],
+    after: Option<&Event>,
+    page_size: usize,
+) -> Result<(String, sqlx::postgres::PgArguments), ValidationErrors> {
+    let mut args = Args::new();
+    let where_clause = where_clause(filters, &mut args)?;
+    let cursor = match after {
+        Some(event) => {
+            let created_at = args.add(event.created_at);
+            let id = args.add(event_bytes(event).0);
+            format!("AND (created_at, id) > ({created_at}::TIMESTAMPTZ, {id}::BYTEA)")
+        }
+        None => String::new(),
+    };
+    let limit = args.add(page_size as i64);
+    Ok((
+        format!(
+            r#"
+SELECT
+    encode(id, 'hex') as "id"
+    ,encode(pubkey, 'hex') as "pubkey"
+    ,created_at
+    ,kind
+    ,tags
+    ,content
+    ,encode(sig, 'hex') as "sig"
+FROM events
+{where_clause} {cursor}
+ORDER BY created_at ASC, id ASC
+LIMIT {limit}
+        "#,
+        ),
+        args.inner,
+    ))
+}
+
+fn negentropy_query(
+    filters: &[Filter],
+    max_items: usize,
+) -> Result<(String, sqlx::postgres::PgArguments), ValidationErrors> {
+    let mut args = Args::new();
+    let where_clause = where_clause(filters, &mut args)?;
+    let limit = args.add(max_items as i64);
+    Ok((
+        format!(
+            r#"
+SELECT
+    EXTRACT(EPOCH FROM created_at)::BIGINT
+    ,id
+FROM events
+{where_clause}
+ORDER BY created_at ASC, id ASC
+LIMIT {limit}
+        "#,
+        ),
+        args.inner,
+    ))
+}
+
+fn count_query(
+    filters: &[Filter],
+) -> Result<(String, sqlx::postgres::PgArguments), ValidationErrors> {
+    let mut args = Args::new();
+    let where_clause = where_clause(filters, &mut args)?;
+    Ok((
+        format!(
+            r#"
+SELECT count(*)
+FROM events
+{where_clause}
+        "#,
+        ),
+        args.inner,
+    ))
+}
+
+#[async_trait::async_trait]
+impl EventStore for PgStore {
+    async fn insert(&self, event: &Event) -> Result<(), Rejection> {
+        insert_event(event, &self.db_pool).await
+    }
+
+    async fn insert_batch(&self, events: &[Event]) -> Vec<Result<(), Rejection>> {
+        let mut ids = Vec::with_capacity(events.len());
+        let mut pubkeys = Vec::with_capacity(events.len());
+        let mut sigs = Vec::with_capacity(events.len());
+        for event in events {
+            let (id, pubkey, sig) = event_bytes(event);
+            ids.push(id);
+            pubkeys.push(pubkey);
+            sigs.push(sig);
+        }
+        let inserted = sqlx::query_scalar!(
+            r#"
+INSERT INTO public.events (
+    id
+    ,pubkey
+    ,created_at
+    ,kind
+    ,tags
+    ,content
+    ,sig
+    ,expires_at
+) SELECT * FROM UNNEST(
+    $1::BYTEA[]
+    ,$2::BYTEA[]
+    ,$3::TIMESTAMPTZ[]
+    ,$4::INT[]
+    ,$5::JSONB[]
+    ,$6::TEXT[]
+    ,$7::BYTEA[]
+    ,$8::TIMESTAMPTZ[]
+) AS batch (id, pubkey, created_at, kind, tags, content, sig, expires_at)
+WHERE NOT EXISTS (
+    SELECT 1 FROM public.event_deletions
+    WHERE event_id = batch.id AND pubkey = batch.pubkey
+)
+ON CONFLICT (id) DO NOTHING
+RETURNING id
+            "#,
+            &ids[..],
+            &pubkeys[..],
+            &events
+                .iter()
+                .map(|event| event.created_at)
+                .collect::<Vec<_>>()[..],
+            &events
+                .iter()
+                .map(|event| event.kind as i32)
+                .collect::<Vec<_>>()[..],
+            &events
+                .iter()
+                .map(|event| json!(event.tags))
+                .collect::<Vec<_>>()[..],
+            &events
+                .iter()
+                .map(|event| event.content.clone())
+                .collect::<Vec<_>>()[..],
+            &sigs[..],
+            &events
+                .iter()
+                .map(|event| event.expires_at())
+                .collect::<Vec<_>>()[..] as &[Option<OffsetDateTime>],
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .unwrap_or_log();
+        let mut inserted = inserted
+            .into_iter()
+            .collect::<std::collections::HashSet<_>>();
+        // whatever wasn't inserted was either already there or tombstoned
+        let deleted = sqlx::query_scalar!(
+            r#"
+SELECT event_id FROM public.event_deletions
+WHERE event_id = ANY($1)
+            "#,
+            &ids[..],
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .unwrap_or_log()
+        .into_iter()
+        .collect::<std::collections::HashSet<_>>();
+        ids.into_iter()
+            .map(|id| {
+                // repeats within the batch are duplicates of the first
+                if inserted.remove(&id) {
+                    Ok(())
+                } else if deleted.contains(&id) {
+                    Err(Rejection::Deleted)
+                } else {
+                    Err(Rejection::Duplicate)
+                }
+            })
+            .collect()
+    }
+
+    async fn replace(&self, event: &Event) -> Result<(), Rejection> {
+        let (id_bytes, pubkey_bytes, _) = event_bytes(event);
+        let d_tag = if crate::event::is_parameterized_replaceable(event.kind) {
+            Some(event.d_tag())
+        } else {
+            None
+        };
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let mut tx = self.db_pool.begin().await.unwrap_or_log();
+            let current = sqlx::query!(
+                r#"
+SELECT id, created_at
+FROM public.events
+WHERE pubkey = $1 AND kind = $2 AND ($3::TEXT IS NULL OR d_tag = $3)
+FOR UPDATE
+                "#,
+                &pubkey_bytes[..],
+                event.kind as i32,
+                d_tag,
+            )
+            .fetch_optional(&mut *tx)
+            .await
+            .unwrap_or_log();
+            if let Some(current) = current {
+                let is_newer = event.created_at > current.created_at
+                    || (event.created_at == current.created_at && id_bytes < current.id);
+                if !is_newer {
+                    return Err(if current.id == id_bytes {
+                        Rejection::Duplicate
+                    } else {
+                        Rejection::Stale
+                    });
+                }
+                sqlx::query!(
+                    r#"
+WITH superseded AS (
+    DELETE FROM public.events WHERE id = $1
+    RETURNING recieved_at, id, pubkey, created_at, kind, content, sig, tags, d_tag
+)
+INSERT INTO public.events_archive (
+    recieved_at
+    ,id
+    ,pubkey
+    ,created_at
+    ,kind
+    ,content
+    ,sig
+    ,tags
+    ,d_tag
+    ,superseded_by
+) SELECT
+    recieved_at
+    ,id
+    ,pubkey
+    ,created_at
+    ,kind
+    ,content
+    ,sig
+    ,tags
+    ,CASE WHEN kind >= 30000 AND kind < 40000 THEN d_tag ELSE '' END
+    ,$2
+FROM superseded
+-- versions can come back after their successor's deleted
+ON CONFLICT (id) DO NOTHING
+                    "#,
+                    &current.id[..],
+                    &id_bytes[..],
+                )
+                .execute(&mut *tx)
+                .await
+                .unwrap_or_log();
+            }
+            match insert_event(event, &mut *tx).await {
+                Ok(()) => {
+                    tx.commit().await.unwrap_or_log();
+                    return Ok(());
+                }
+                // the transaction's rolled back on drop
+                Err(Rejection::Conflict) if attempt < REPLACE_ATTEMPTS => {
+                    debug!(attempt, "lost race replacing event, retrying");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn delete_by_reference(&self, request: &Event) -> Result<(), Rejection> {
+        let mut tx = self.db_pool.begin().await.unwrap_or_log();
+        insert_event(request, &mut *tx).await?;
+        apply_deletion(request, &mut tx).await;
+        tx.commit().await.unwrap_or_log();
+        Ok(())
+    }
+
+    async fn query(
+        &self,
+        filters: &[Filter],
+        max_limit: usize,
+    ) -> Result<Vec<Event>, ValidationErrors> {
+        let Some((query, args)) = list_query(filters, max_limit)? else {
+            return Ok(vec![]);
+        };
+        Ok(sqlx::query_as_with(&query[..], args)
+            .fetch_all(&self.db_pool)
+            .await
+            .unwrap_or_log())
+    }
+
+    async fn count(&self, filters: &[Filter]) -> Result<i64, ValidationErrors> {
+        let (query, args) = count_query(filters)?;
+        Ok(sqlx::query_scalar_with(&query[..], args)
+            .fetch_one(&self.db_pool)
+            .await
+            .unwrap_or_log())
+    }
+
+    async fn scan(
+        &self,
+        filters: &[Filter],
+        after: Option<&Event>,
+        page_size: usize,
+    ) -> Result<Vec<Event>, ValidationErrors> {
+        let (query, args) = scan_query(filters, after, page_size)?;
+        Ok(sqlx::query_as_with(&query[..], args)
+            .fetch_all(&self.db_pool)
+            .await
+            .unwrap_or_log())
+    }
+
+    async fn negentropy_items(
+        &self,
+        filters: &[Filter],
+        max_items: usize,
+    ) -> Result<Vec<crate::negentropy::Item>, ValidationErrors> {
+        let (query, args) = negentropy_query(filters, max_items)?;
+        let rows: Vec<(i64, Vec<u8>)> = sqlx::query_as_with(&query[..], args)
+            .fetch_all(&self.db_pool)
+            .await
+            .unwrap_or_log();
+        Ok(rows
+            .into_iter()
+            .map(|(timestamp, id)| crate::negentropy::Item {
+                timestamp: timestamp as u64,
+                id: id[..].try_into().expect_or_log("ids are 32 bytes"),
+            })
+            .collect())
+    }
+
+    async fn reap_expired(&self) -> u64 {
+        sqlx::query!(
+            r#"
+DELETE FROM public.events
+WHERE expires_at <= NOW()
+            "#,
+        )
+        .execute(&self.db_pool)
+        .await
+        .unwrap_or_log()
+        .rows_affected()
+    }
+
+    async fn archived(&self, pubkey: &str, kind: u16, d_tag: &str, limit: usize) -> Vec<Event> {
+        let d_tag = if crate::event::is_parameterized_replaceable(kind) {
+            d_tag
+        } else {
+            ""
+        };
+        sqlx::query_as(
+            r#"
+SELECT
+    encode(id, 'hex') as "id"
+    ,encode(pubkey, 'hex') as "pubkey"
+    ,created_at
+    ,kind
+    ,tags
+    ,content
+    ,encode(sig, 'hex') as "sig"
+FROM public.events_archive
+WHERE pubkey = $1 AND kind = $2 AND d_tag = $3
+ORDER BY created_at DESC, id ASC
+LIMIT $4
+            "#,
+        )
+        .bind(decode_key(pubkey))
+        .bind(kind as i32)
+        .bind(d_tag)
+        .bind(limit as i64)
+        .fetch_all(&self.db_pool)
+        .await
+        .unwrap_or_log()
+    }
+
+    async fn prune_archive(
+        &self,
+        archived_before: Option<OffsetDateTime>,
+        max_versions: Option<usize>,
+    ) -> u64 {
+        let mut count = 0;
+        if let Some(archived_before) = archived_before {
+            count += sqlx::query!(
+                r#"
+DELETE FROM public.events_archive
+WHERE archived_at < $1
+                "#,
+                archived_before,
+            )
+            .execute(&self.db_pool)
+            .await
+            .unwrap_or_log()
+            .rows_affected();
+        }
+        if let Some(max_versions) = max_versions {
+            count += sqlx::query!(
+                r#"
+DELETE FROM public.events_archive
+USING (
+    SELECT
+        id
+        ,ROW_NUMBER() OVER (
+            PARTITION BY pubkey, kind, d_tag
+            ORDER BY created_at DESC, id ASC
+        ) AS "version"
+    FROM public.events_archive
+) AS "ranked"
+WHERE events_archive.id = "ranked".id AND "ranked"."version" > $1
+                "#,
+                max_versions as i64,
+            )
+            .execute(&self.db_pool)
+            .await
+            .unwrap_or_log()
+            .rows_affected();
+        }
+        count
+    }
+
+    async fn banned(&self, event: &Event) -> Option<Banned> {
+        let (id_bytes, pubkey_bytes, _) = event_bytes(event);
+        let bans = sqlx::query!(
+            r#"
+SELECT
+    EXISTS (SELECT 1 FROM public.banned_pubkeys WHERE pubkey = $2) as "pubkey!"
+    ,EXISTS (SELECT 1 FROM public.banned_events WHERE event_id = $1) as "event!"
+            "#,
+            &id_bytes[..],
+            &pubkey_bytes[..],
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .unwrap_or_log();
+        if bans.pubkey {
+            Some(Banned::Pubkey)
+        } else if bans.event {
+            Some(Banned::Event)
+        } else {
+            None
+        }
+    }
+
+    async fn ban_pubkey(&self, pubkey: &str, reason: Option<&str>) {
+        sqlx::query!(
+            r#"
+INSERT INTO public.banned_pubkeys (
+    pubkey
+    ,reason
+) VALUES (
+    $1
+    ,$2
+) ON CONFLICT (pubkey) DO UPDATE SET
+    reason = EXCLUDED.reason
+            "#,
+            &decode_key(pubkey)[..],
+            reason,
+        )
+        .execute(&self.db_pool)
+        .await
+        .unwrap_or_log();
+    }
+
+    async fn unban_pubkey(&self, pubkey: &str) {
+        sqlx::query!(
+            r#"
+DELETE FROM public.banned_pubkeys WHERE pubkey = $1
+            "#,
+            &decode_key(pubkey)[..],
+        )
+        .execute(&self.db_pool)
+        .await
+        .unwrap_or_log();
+    }
+
+    async fn ban_event(&self, id: &str, reason: Option<&str>) {
+        let id = decode_key(id);
+        let mut tx = self.db_pool.begin().await.unwrap_or_log();
+        sqlx::query!(
+            r#"
+INSERT INTO public.banned_events (
+    event_id
+    ,reason
+) VALUES (
+    $1
+    ,$2
+) ON CONFLICT (event_id) DO UPDATE SET
+    reason = EXCLUDED.reason
+            "#,
+            &id[..],
+            reason,
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap_or_log();
+        sqlx::query!(
+            r#"
+DELETE FROM public.events WHERE id = $1
+            "#,
+            &id[..],
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap_or_log();
+        tx.commit().await.unwrap_or_log();
+    }
+
+    async fn banned_pubkeys(&self) -> Vec<Ban> {
+        sqlx::query!(
+            r#"
+SELECT pubkey, reason FROM public.banned_pubkeys ORDER BY banned_at, pubkey
+            "#
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .unwrap_or_log()
+        .into_iter()
+        .map(|row| Ban {
+            key: data_encoding::HEXLOWER.encode(&row.pubkey),
+            reason: row.reason,
+        })
+        .collect()
+    }
+
+    async fn banned_events(&self) -> Vec<Ban> {
+        sqlx::query!(
+            r#"
+SELECT event_id, reason FROM public.banned_events ORDER BY banned_at, event_id
+            "#
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .unwrap_or_log()
+        .into_iter()
+        .map(|row| Ban {
+            key: data_encoding::HEXLOWER.encode(&row.event_id),
+            reason: row.reason,
+        })
+        .collect()
+    }
+
+    async fn setting(&self, key: &str) -> Option<serde_json::Value> {
+        sqlx::query_scalar!(
+            r#"
+SELECT value FROM public.relay_settings WHERE key = $1
+            "#,
+            key,
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .unwrap_or_log()
+    }
+
+    async fn set_setting(&self, key: &str, value: serde_json::Value) {
+        sqlx::query!(
+            r#"
+INSERT INTO public.relay_settings (
+    key
+    ,value
+) VALUES (
+    $1
+    ,$2
+) ON CONFLICT (key) DO UPDATE SET
+    value = EXCLUDED.value
+    ,updated_at = CURRENT_TIMESTAMP
+            "#,
+            key,
+            value,
+        )
+        .execute(&self.db_pool)
+        .await
+        .unwrap_or_log();
+    }
+
+    async fn mirror_since(&self, upstream: &str) -> Option<OffsetDateTime> {
+        sqlx::query_scalar!(
+            r#"
+SELECT since FROM public.mirror_cursors WHERE upstream = $1
+            "#,
+            upstream,
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .unwrap_or_log()
+    }
+
+    async fn set_mirror_since(&self, upstream: &str, since: OffsetDateTime) {
+        sqlx::query!(
+            r#"
+INSERT INTO public.mirror_cursors (
+    upstream
+    ,since
+) VALUES (
+    $1
+    ,$2
+) ON CONFLICT (upstream) DO UPDATE SET
+    since = GREATEST(mirror_cursors.since, EXCLUDED.since)
+    ,updated_at = CURRENT_TIMESTAMP
+            "#,
+            upstream,
+            since,
+        )
+        .execute(&self.db_pool)
+        .await
+        .unwrap_or_log();
+    }
+}
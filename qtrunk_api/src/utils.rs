@@ -16,31 +16,189 @@ pub mod testing {
     }
 
     pub fn state_fn(testing: &TestContext) -> crate::SharedContext {
+        state_fn_with_config(testing, |_| {})
+    }
+
+    /// Like [`state_fn`] but lets the test tweak the [`crate::Config`].
+    pub fn state_fn_with_config(
+        testing: &TestContext,
+        config_fn: impl FnOnce(&mut crate::Config),
+    ) -> crate::SharedContext {
+        context(
+            testing,
+            Box::new(crate::store::PgStore::new(
+                testing.pg_pools["qtrunk"].pool.clone(),
+            )),
+            config_fn,
+        )
+    }
+
+    fn context(
+        testing: &TestContext,
+        store: Box<dyn crate::store::EventStore>,
+        config_fn: impl FnOnce(&mut crate::Config),
+    ) -> crate::SharedContext {
+        let hose_stream = format!("event_hose_{}_{}", testing.test_name, Uuid::new_v4());
+        context_on_stream(testing, store, hose_stream, config_fn)
+    }
+
+    /// Like [`context`] but with the hose on the given redis stream.
+    fn context_on_stream(
+        testing: &TestContext,
+        store: Box<dyn crate::store::EventStore>,
+        hose_stream: String,
+        config_fn: impl FnOnce(&mut crate::Config),
+    ) -> crate::SharedContext {
+        let mut config = crate::Config {
+            pass_salt_hash: b"sea brine".to_vec(),
+            argon2_conf: argon2::Config::default(),
+            auth_token_lifespan: time::Duration::seconds_f64(60. * 60. * 24. * 30.),
+            web_session_lifespan: time::Duration::seconds_f64(60. * 60. * 24. * 30.),
+            service_secret: SERVICE_SECRET.to_string(),
+            redis_key_prefix: format!("qtrunk_{}_{}", testing.test_name, Uuid::new_v4()),
+            relay_name: "qtrunk".into(),
+            relay_description: "qtrunk test relay".into(),
+            relay_pubkey: None,
+            relay_contact: None,
+            relay_url: "wss://qtrunk.test".into(),
+            admin_pubkeys: vec![],
+            expired_reap_interval: time::Duration::minutes(5),
+            archive_retention: None,
+            archive_max_versions: None,
+            ping_interval: time::Duration::seconds(30),
+            idle_timeout: time::Duration::seconds(90),
+            shutdown_grace: time::Duration::seconds(5),
+            limits: default(),
+            write_policy: default(),
+            upstreams: vec![],
+        };
+        config_fn(&mut config);
+        let redis = testing
+            .redis_pools
+            .get("default")
+            .map(|redis| redis.pool.clone());
+        let hose: Box<dyn crate::hose::EventHose> = match &redis {
+            Some(redis) => Box::new(crate::hose::RedisHose::new(
+                redis.clone(),
+                hose_stream,
+                10_000,
+            )),
+            None => Box::<crate::hose::BroadcastHose>::default(),
+        };
         std::sync::Arc::new(crate::Context {
-            db: crate::Db::Pg {
-                db_pool: testing.pg_pools["qtrunk"].pool.clone(),
-            },
-            redis: testing.redis_pools["default"].pool.clone(),
-            config: crate::Config {
-                pass_salt_hash: b"sea brine".to_vec(),
-                argon2_conf: argon2::Config::default(),
-                auth_token_lifespan: time::Duration::seconds_f64(60. * 60. * 24. * 30.),
-                web_session_lifespan: time::Duration::seconds_f64(60. * 60. * 24. * 30.),
-                service_secret: SERVICE_SECRET.to_string(),
-                event_hose_redis_channel: format!("event_hose_{}", testing.test_name),
-            },
+            store,
+            redis,
+            hose,
+            rate_counters: default(),
+            config,
             sw: default(),
+            manage: default(),
         })
     }
 
     pub async fn cx_fn(test_name: &'static str) -> (TestContext, crate::SharedContext) {
+        cx_fn_with_config(test_name, |_| {}).await
+    }
+
+    /// Runs without redis, the hose being in-process.
+    pub async fn cx_fn_with_config(
+        test_name: &'static str,
+        config_fn: impl FnOnce(&mut crate::Config),
+    ) -> (TestContext, crate::SharedContext) {
+        let testing = TestContext::new(
+            test_name.into(),
+            [("qtrunk".to_string(), test_db(test_name).await)],
+            std::collections::HashMap::new(),
+        );
+        let cx = state_fn_with_config(&testing, config_fn);
+        spawn_tasks(&cx);
+        (testing, cx)
+    }
+
+    /// Like [`cx_fn`] but with the hose and rate limits on redis.
+    pub async fn cx_fn_with_redis(test_name: &'static str) -> (TestContext, crate::SharedContext) {
         let testing = TestContext::new(
             test_name.into(),
             [("qtrunk".to_string(), test_db(test_name).await)],
             [("default".to_string(), TestRedis::new().await)],
         );
         let cx = state_fn(&testing);
+        spawn_tasks(&cx);
+        (testing, cx)
+    }
+
+    /// Like [`cx_fn_with_redis`] but with `N` instances sharing the db and
+    /// the hose.
+    pub async fn instances_fn<const N: usize>(
+        test_name: &'static str,
+        config_fn: impl Fn(&mut crate::Config),
+    ) -> (TestContext, [crate::SharedContext; N]) {
+        let testing = TestContext::new(
+            test_name.into(),
+            [("qtrunk".to_string(), test_db(test_name).await)],
+            [("default".to_string(), TestRedis::new().await)],
+        );
+        let hose_stream = format!("event_hose_{test_name}_{}", Uuid::new_v4());
+        let instances = std::array::from_fn(|_| {
+            let cx = context_on_stream(
+                &testing,
+                Box::new(crate::store::PgStore::new(
+                    testing.pg_pools["qtrunk"].pool.clone(),
+                )),
+                hose_stream.clone(),
+                &config_fn,
+            );
+            spawn_tasks(&cx);
+            cx
+        });
+        (testing, instances)
+    }
+
+    /// The background tasks of the relay.
+    fn spawn_tasks(cx: &crate::SharedContext) {
         drop(tokio::spawn(crate::connect::start_switchboard(cx.clone())));
+        drop(tokio::spawn(crate::event::expire::start_reaper(cx.clone())));
+    }
+
+    /// The [`crate::store::EventStore`]s tests get run against.
+    #[derive(Debug, Clone, Copy)]
+    pub enum Backend {
+        Pg,
+        Mem,
+    }
+
+    pub const BACKENDS: [Backend; 2] = [Backend::Pg, Backend::Mem];
+
+    /// Like [`cx_fn_with_config`] but against the given backend. The
+    /// in-memory one gets seeded with the same fixtures as the db.
+    pub async fn cx_fn_with_backend(
+        test_name: &'static str,
+        backend: Backend,
+        config_fn: impl FnOnce(&mut crate::Config),
+    ) -> (TestContext, crate::SharedContext) {
+        let store = match backend {
+            Backend::Pg => return cx_fn_with_config(test_name, config_fn).await,
+            Backend::Mem => crate::store::MemStore::new(),
+        };
+        {
+            use crate::event::testing::*;
+            use crate::store::EventStore;
+            for event in [&EVENT_01, &EVENT_02, &EVENT_03, &EVENT_04, &EVENT_05] {
+                let event: &crate::event::Event = event;
+                if crate::event::is_replaceable(event.kind) {
+                    store.replace(event).await.unwrap();
+                } else {
+                    store.insert(event).await.unwrap();
+                }
+            }
+        }
+        let testing = TestContext::new(
+            test_name.into(),
+            std::collections::HashMap::new(),
+            std::collections::HashMap::new(),
+        );
+        let cx = context(&testing, Box::new(store), config_fn);
+        spawn_tasks(&cx);
         (testing, cx)
     }
 }